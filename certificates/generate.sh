@@ -0,0 +1,9 @@
+#!/bin/sh
+# Self-signed certificate for testing WebTransport locally, browsers only accept it for 14 days
+set -e
+cd "$(dirname "$0")"
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 14 \
+    -keyout key.pem -out cert.pem -subj "/CN=localhost" \
+    -addext "subjectAltName=DNS:localhost,IP:127.0.0.1,IP:::1"
+openssl x509 -in cert.pem -outform der | openssl dgst -sha256 -binary | od -An -tx1 | tr -d ' \n' > digest.txt
+echo "certificate digest: $(cat digest.txt)"
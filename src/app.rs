@@ -1,16 +1,21 @@
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use bevy::asset::ron;
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::input::InputPlugin;
 use bevy::log::{Level, LogPlugin};
 use bevy::prelude::*;
 use bevy::render::RenderPlugin;
+use bevy::scene::ScenePlugin;
 use bevy::state::app::StatesPlugin;
 use bevy::winit::{WakeUp, WinitPlugin};
 use bevy::DefaultPlugins;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use lightyear::prelude::client::ClientConfig;
 use lightyear::prelude::*;
 use lightyear::prelude::{client, server};
@@ -19,32 +24,150 @@ use lightyear::shared::log::add_log_layer;
 use lightyear::transport::LOCAL_SOCKET;
 use serde::{Deserialize, Serialize};
 
+#[cfg(not(target_family = "wasm"))]
+use crate::auth::TokenClientPlugin;
+use crate::auth::{random_client_id, ServerAuth, ServerAuthPlugin, ASSIGN_CLIENT_ID};
+use crate::bindings::Device;
+use crate::bot::BotBehaviour;
+use crate::link::{LinkRelay, LinkRelayPlugin};
+use crate::recording::Recording;
+use crate::replay::ReplayPlugin;
 use crate::settings::*;
-use crate::shared::{shared_config, SERVER_REPLICATION_INTERVAL};
+use crate::shared::{shared_config, FIXED_TIMESTEP_HZ, SERVER_REPLICATION_INTERVAL};
 
+/// Networked physics sandbox
 #[derive(Parser, PartialEq, Debug)]
+pub struct Args {
+    /// Path to a RON settings file, defaults to the embedded assets/settings.ron
+    #[arg(long, global = true, env = "NET_PHYS_SETTINGS")]
+    pub settings: Option<PathBuf>,
+    /// Path to a RON bindings file, created by the rebinding screen if missing, defaults to the
+    /// embedded assets/bindings.ron
+    #[arg(long, global = true, env = "NET_PHYS_BINDINGS")]
+    pub bindings: Option<PathBuf>,
+    /// Input device the client reads: any, keyboard:<layout> or gamepad:<id>
+    #[arg(long, global = true)]
+    pub device: Option<Device>,
+    #[command(flatten)]
+    pub overrides: SettingsOverrides,
+    #[command(subcommand)]
+    pub cli: Cli,
+}
+
+/// Overrides applied on top of the settings file, from environment variables or CLI flags
+///
+/// Only the fields that differ between the machines running the same settings file can be
+/// overridden, every other field is set in the file
+#[derive(clap::Args, PartialEq, Clone, Debug, Default)]
+pub struct SettingsOverrides {
+    /// Address the client connects to
+    #[arg(long, global = true, env = "NET_PHYS_SERVER_ADDR")]
+    pub server_addr: Option<Ipv4Addr>,
+    /// Port the server listens on with UDP, and the client connects to
+    #[arg(long, global = true, env = "NET_PHYS_SERVER_PORT")]
+    pub server_port: Option<u16>,
+    /// Local port of the client socket
+    #[arg(long, global = true, env = "NET_PHYS_CLIENT_PORT")]
+    pub client_port: Option<u16>,
+    /// Run the server without a window
+    #[arg(long, global = true, env = "NET_PHYS_HEADLESS")]
+    pub headless: Option<bool>,
+    /// Set by `Cli::Spectate`
+    #[arg(skip)]
+    pub spectator: bool,
+}
+
+impl SettingsOverrides {
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(server_addr) = self.server_addr {
+            settings.client.server_addr = server_addr;
+        }
+        if let Some(server_port) = self.server_port {
+            settings.client.server_port = server_port;
+            for transport in settings.server.transport.iter_mut() {
+                if let ServerTransports::Udp { local_port } = transport {
+                    *local_port = server_port;
+                }
+            }
+        }
+        if let Some(client_port) = self.client_port {
+            settings.client.client_port = client_port;
+        }
+        if let Some(headless) = self.headless {
+            settings.server.headless = headless;
+        }
+        if self.spectator {
+            settings.client.spectator = true;
+        }
+    }
+}
+
+#[derive(Subcommand, PartialEq, Debug)]
 pub enum Cli {
     HostServer {
         #[arg(short, long, default_value = None)]
         client_id: Option<u64>,
+        /// Record the session to this file
+        #[arg(long)]
+        record: Option<PathBuf>,
+    },
+    Server {
+        /// Record the session to this file
+        #[arg(long)]
+        record: Option<PathBuf>,
     },
-    Server,
     Client {
         #[arg(short, long, default_value = None)]
         client_id: Option<u64>,
         #[arg(short, long, default_value = None)]
         server_ip: Option<Ipv4Addr>,
     },
+    /// Connect as a spectator, without spawning a player
+    Spectate {
+        #[arg(short, long, default_value = None)]
+        client_id: Option<u64>,
+        #[arg(short, long, default_value = None)]
+        server_ip: Option<Ipv4Addr>,
+    },
+    /// Play back a session recorded with `--record`
+    Replay { file: PathBuf },
+    /// Run a server and headless clients with mock input in this process, without sockets
+    LocalSwarm {
+        #[arg(short, long, default_value_t = 8)]
+        clients: u64,
+    },
+    /// Run headless bots that play with a scripted behaviour, to load-test a server
+    Bot {
+        #[arg(short = 'n', long, default_value_t = 1)]
+        count: u64,
+        #[arg(short, long, value_enum, default_value_t = BotBehaviour::RandomWalk)]
+        behaviour: BotBehaviour,
+        /// Recording whose inputs are replayed by the `replay` behaviour
+        #[arg(long)]
+        input_file: Option<PathBuf>,
+        #[arg(short, long, default_value = None)]
+        server_ip: Option<Ipv4Addr>,
+    },
+}
+
+impl Cli {
+    /// File the server records the session to
+    pub fn record_path(&self) -> Option<&Path> {
+        match self {
+            Cli::HostServer { record, .. } | Cli::Server { record } => record.as_deref(),
+            _ => None,
+        }
+    }
 }
 
-impl Default for Cli {
+impl Default for Args {
     fn default() -> Self {
-        cli()
+        args()
     }
 }
 
-pub fn cli() -> Cli {
-    Cli::parse()
+pub fn args() -> Args {
+    Args::parse()
 }
 
 pub enum Apps {
@@ -61,42 +184,78 @@ pub enum Apps {
         client_config: ClientConfig,
         server_config: ServerConfig,
     },
+    Replay {
+        app: App,
+    },
+    /// Bots updated by a windowless app
+    Bots {
+        app: App,
+    },
 }
 
 impl Apps {
-    pub fn new(settings: Settings, cli: Cli) -> Self {
-        match cli {
-            Cli::HostServer { client_id } => {
-                let client_net_config = client::NetConfig::Local {
-                    id: client_id.unwrap_or(settings.client.client_id),
-                };
-                let (app, client_config, server_config) =
-                    combined_app(settings, vec![], client_net_config);
-                Apps::HostServer {
-                    app,
-                    client_config,
-                    server_config,
-                }
-            }
-            Cli::Server => {
-                let (app, config) = server_app(settings, vec![]);
-                Apps::Server { app, config }
-            }
-            Cli::Client {
-                client_id,
-                server_ip,
-            } => {
-                let client_id = client_id.unwrap_or(settings.client.client_id);
-                let server_ip = server_ip.unwrap_or(settings.client.server_addr).into();
+    /// Server with a local client in the same app, the `client_id` defaults to the settings
+    pub fn host_server(settings: Settings, client_id: Option<u64>) -> Self {
+        let client_net_config = client::NetConfig::Local {
+            id: client_id
+                .or(settings.client.client_id)
+                .unwrap_or(ASSIGN_CLIENT_ID),
+        };
+        let (app, client_config, server_config) = combined_app(settings, vec![], client_net_config);
+        Apps::HostServer {
+            app,
+            client_config,
+            server_config,
+        }
+    }
 
-                let net_config = get_client_net_config(&settings, client_id, server_ip);
-                let (app, config) = client_app(settings, net_config);
-                Apps::Client { app, config }
-            }
+    pub fn server(settings: Settings) -> Self {
+        let headless = settings.server.headless;
+        let (mut app, config) = server_app(settings, vec![]);
+        if headless {
+            app.add_plugins(LogPlugin::default());
         }
+        Apps::Server { app, config }
     }
 
-    pub fn with_server_replication_send_interval(mut self, replication_interval: Duration) -> Self {
+    /// Client of the server at `server_ip`, the `client_id` and `server_ip` default to the
+    /// settings
+    pub fn client(settings: Settings, client_id: Option<u64>, server_ip: Option<Ipv4Addr>) -> Self {
+        let token_port = settings.client.token_port;
+        let client_id = match client_id.or(settings.client.client_id) {
+            Some(client_id) => client_id,
+            None if token_port.is_some() => ASSIGN_CLIENT_ID,
+            None => random_client_id(),
+        };
+        let server_ip = server_ip.unwrap_or(settings.client.server_addr).into();
+
+        let net_config = get_client_net_config(&settings, client_id, server_ip);
+        let (mut app, config) = client_app(settings, net_config);
+        #[cfg(not(target_family = "wasm"))]
+        if let Some(port) = token_port {
+            app.add_plugins(TokenClientPlugin {
+                service_addr: SocketAddr::new(server_ip, port),
+                client_id,
+            });
+        }
+        Apps::Client { app, config }
+    }
+
+    /// Plays back the recording at `file`, exits if it can't be loaded
+    pub fn replay(file: &Path) -> Self {
+        let recording = Recording::load(file).unwrap_or_else(|err| {
+            eprintln!("Could not load the recording: {err}");
+            std::process::exit(1);
+        });
+        let mut app = App::new();
+        app.add_plugins((DefaultPlugins.build(), ReplayPlugin { recording }));
+        Apps::Replay { app }
+    }
+
+    pub fn with_server_replication_send_interval(
+        &mut self,
+        replication_interval: Duration,
+    ) -> &mut Self {
         self.update_lightyear_client_config(|cc: &mut ClientConfig| {
             cc.shared.server_replication_send_interval = replication_interval
         });
@@ -108,6 +267,18 @@ impl Apps {
         self
     }
 
+    /// Caps the bytes per second the server sends to each client, lightyear then sends the
+    /// replication groups with the highest accumulated priority first
+    pub fn with_server_bandwidth_cap(&mut self, bytes_per_second: Option<u32>) -> &mut Self {
+        if let Some(cap) = bytes_per_second {
+            self.update_lightyear_server_config(|sc: &mut ServerConfig| {
+                sc.packet.send_bandwidth_cap = cap;
+                sc.packet.bandwidth_cap_enabled = true;
+            });
+        }
+        self
+    }
+
     pub fn add_lightyear_plugins(&mut self) -> &mut Self {
         match self {
             Apps::Client { app, config } => {
@@ -132,10 +303,16 @@ impl Apps {
                     config: server_config.clone(),
                 });
             }
+            Apps::Replay { .. } | Apps::Bots { .. } => {}
         }
         self
     }
 
+    pub fn add_plugins<M>(&mut self, plugins: impl bevy::app::Plugins<M>) -> &mut Self {
+        self.app_mut().add_plugins(plugins);
+        self
+    }
+
     pub fn add_user_plugins(
         &mut self,
         client_plugin: impl Plugin,
@@ -152,6 +329,7 @@ impl Apps {
             Apps::HostServer { app, .. } => {
                 app.add_plugins((client_plugin, server_plugin, shared_plugin));
             }
+            Apps::Replay { .. } | Apps::Bots { .. } => {}
         }
         self
     }
@@ -168,6 +346,7 @@ impl Apps {
             Apps::HostServer { client_config, .. } => {
                 f(client_config);
             }
+            Apps::Replay { .. } | Apps::Bots { .. } => {}
         }
         self
     }
@@ -185,10 +364,21 @@ impl Apps {
             Apps::HostServer { server_config, .. } => {
                 f(server_config);
             }
+            Apps::Replay { .. } | Apps::Bots { .. } => {}
         }
         self
     }
 
+    pub fn app_mut(&mut self) -> &mut App {
+        match self {
+            Apps::Client { app, .. }
+            | Apps::Server { app, .. }
+            | Apps::HostServer { app, .. }
+            | Apps::Replay { app }
+            | Apps::Bots { app } => app,
+        }
+    }
+
     pub fn run(self) {
         match self {
             Apps::Client { mut app, .. } => {
@@ -200,14 +390,38 @@ impl Apps {
             Apps::HostServer { mut app, .. } => {
                 app.run();
             }
+            Apps::Replay { mut app } | Apps::Bots { mut app } => {
+                app.run();
+            }
         }
     }
 }
 
-fn client_app(settings: Settings, net_config: client::NetConfig) -> (App, ClientConfig) {
-    let mut app = App::new();
+/// Adds the plugins needed to run the simulation without a window or a GPU
+pub(crate) fn add_headless_plugins(app: &mut App) {
+    app.add_plugins((
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            1.0 / FIXED_TIMESTEP_HZ,
+        ))),
+        StatesPlugin,
+        TransformPlugin,
+        HierarchyPlugin,
+        // avian's collider constructor systems read scenes and meshes
+        AssetPlugin::default(),
+        ScenePlugin,
+    ))
+    .init_resource::<Assets<Mesh>>();
+}
 
-    app.add_plugins(DefaultPlugins.build());
+pub(crate) fn client_app(settings: Settings, net_config: client::NetConfig) -> (App, ClientConfig) {
+    let mut app = App::new();
+    if !settings.client.headless {
+        app.add_plugins(DefaultPlugins.build());
+    } else {
+        add_headless_plugins(&mut app);
+        // leafwing reads the input resources even when nothing is pressed
+        app.add_plugins(InputPlugin);
+    }
     let client_config = ClientConfig {
         shared: shared_config(Mode::Separate),
         net: net_config,
@@ -216,7 +430,7 @@ fn client_app(settings: Settings, net_config: client::NetConfig) -> (App, Client
     (app, client_config)
 }
 
-fn server_app(
+pub(crate) fn server_app(
     settings: Settings,
     extra_transport_configs: Vec<server::ServerTransport>,
 ) -> (App, ServerConfig) {
@@ -224,29 +438,83 @@ fn server_app(
     if !settings.server.headless {
         app.add_plugins(DefaultPlugins.build().disable::<LogPlugin>());
     } else {
-        app.add_plugins((
-            // TODO: cannot use MinimalPlugins because avian requires render/assets plugin
-            // MinimalPlugins,
-            // StatesPlugin,
-            DefaultPlugins.build().disable::<LogPlugin>(),
-        ));
+        add_headless_plugins(&mut app);
     }
 
-    let mut net_configs = get_server_net_configs(&settings);
+    let server_config = add_server(&mut app, settings, extra_transport_configs, Mode::Separate);
+    (app, server_config)
+}
+
+/// Adds the link relay and the token service of the server to `app`, and returns the config of the
+/// server with the transports of the settings and `extra_transport_configs`
+fn add_server(
+    app: &mut App,
+    mut settings: Settings,
+    mut extra_transport_configs: Vec<server::ServerTransport>,
+    mode: Mode,
+) -> ServerConfig {
+    // the clients keep connecting to the port of the UDP transport when it is relayed
+    let game_port = game_port(&settings.server);
+    if settings.server.link_relay {
+        let relay = LinkRelay::new(&mut settings.server, &mut extra_transport_configs);
+        app.insert_resource(relay);
+    }
+    app.add_plugins(LinkRelayPlugin);
+    let auth = ServerAuth::new(&settings);
+    let mut net_configs = get_server_net_configs(&settings, &auth);
     let extra_net_configs = extra_transport_configs.into_iter().map(|c| {
-        build_server_netcode_config(settings.server.conditioner.as_ref(), &settings.shared, c)
+        let mut config = build_server_netcode_config(
+            settings.server.conditioner.as_ref(),
+            &settings.shared,
+            &auth,
+            c,
+        );
+        config.set_connection_request_handler(Arc::new(auth.registry.clone()));
+        config
     });
     net_configs.extend(extra_net_configs);
-    let server_config = ServerConfig {
-        shared: shared_config(Mode::Separate),
+    log_certificate_digests(app, &net_configs);
+    app.add_plugins(ServerAuthPlugin {
+        token_port: settings.server.token_port,
+        game_port,
+        protocol_id: settings.shared.protocol_id,
+        auth,
+    });
+    ServerConfig {
+        shared: shared_config(mode),
         net: net_configs,
         replication: ReplicationConfig {
             send_interval: SERVER_REPLICATION_INTERVAL,
             ..default()
         },
         ..default()
-    };
-    (app, server_config)
+    }
+}
+
+/// Logs the digests of the WebTransport certificates on startup, once the logger is set up
+fn log_certificate_digests(app: &mut App, net_configs: &[server::NetConfig]) {
+    #[cfg(not(target_family = "wasm"))]
+    let digests: Vec<_> = net_configs
+        .iter()
+        .filter_map(|config| match config {
+            server::NetConfig::Netcode {
+                io:
+                    server::IoConfig {
+                        transport: server::ServerTransport::WebTransportServer { certificate, .. },
+                        ..
+                    },
+                ..
+            } => Some(certificate_digest(certificate)),
+            _ => None,
+        })
+        .collect();
+    #[cfg(target_family = "wasm")]
+    let digests: Vec<String> = vec![];
+    app.add_systems(Startup, move || {
+        for digest in &digests {
+            info!("WebTransport certificate digest: {digest}");
+        }
+    });
 }
 
 fn combined_app(
@@ -258,20 +526,7 @@ fn combined_app(
     app.add_plugins(DefaultPlugins.build());
 
     // Server config
-    let mut net_configs = get_server_net_configs(&settings);
-    let extra_net_configs = extra_transport_configs.into_iter().map(|c| {
-        build_server_netcode_config(settings.server.conditioner.as_ref(), &settings.shared, c)
-    });
-    net_configs.extend(extra_net_configs);
-    let server_config = ServerConfig {
-        shared: shared_config(Mode::HostServer),
-        net: net_configs,
-        replication: ReplicationConfig {
-            send_interval: SERVER_REPLICATION_INTERVAL,
-            ..default()
-        },
-        ..default()
-    };
+    let server_config = add_server(&mut app, settings, extra_transport_configs, Mode::HostServer);
 
     // Client config
     let client_config = ClientConfig {
@@ -0,0 +1,463 @@
+//! Issues netcode connect tokens over TCP, so that the private key never leaves the server
+//!
+//! A client sends the client id it wants as a big-endian `u64`, or [`ASSIGN_CLIENT_ID`] to get a
+//! free one, followed by the [`ClientSecret`] of that id, all zeros the first time. It receives the
+//! id it was given, its secret and a serialized [`ConnectToken`]. An id is bound to the secret
+//! returned the first time it is issued, so that no other client can take over its player. The
+//! connection is closed without a token if the id is refused
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use bevy::ecs::world::Command;
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap, HashSet, Instant};
+use crossbeam_channel::{Receiver, TryRecvError, TrySendError};
+use lightyear::connection::netcode::{self, CONNECT_TOKEN_BYTES};
+use lightyear::connection::server::{ConnectionRequestHandler, DeniedReason};
+use lightyear::prelude::client::{
+    Authentication, ClientCommands, ClientConfig, NetConfig, NetworkingState,
+};
+use lightyear::prelude::server::{ConnectEvent, DisconnectEvent};
+use lightyear::prelude::*;
+
+use crate::settings::Settings;
+
+/// How long a client has to connect with the token it was issued
+pub(crate) const TOKEN_EXPIRE: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Threads of the token service, the requests beyond the queue are dropped
+const TOKEN_WORKERS: usize = 4;
+const MAX_QUEUED_REQUESTS: usize = 64;
+/// Requested from the token service to be assigned an unused client id, it is also the id of the
+/// host-server's local client
+pub const ASSIGN_CLIENT_ID: u64 = 0;
+
+/// Proves to the token service that a client was issued its id before
+pub type ClientSecret = [u8; 32];
+/// Sent by the clients that were not issued their id yet
+const NO_SECRET: ClientSecret = [0; 32];
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("could not generate the token: {0}")]
+    Netcode(Box<netcode::Error>),
+    #[error("invalid token: {0:?}")]
+    Invalid(netcode::InvalidTokenError),
+    #[error("client id {0} is already connected")]
+    AlreadyConnected(u64),
+    #[error("client id {0} was issued to another client")]
+    WrongSecret(u64),
+    #[error("the token service refused client id {0}")]
+    Refused(u64),
+}
+
+impl From<netcode::Error> for TokenError {
+    fn from(e: netcode::Error) -> Self {
+        TokenError::Netcode(Box::new(e))
+    }
+}
+
+/// Client ids that were issued a token and the ones that are connected, shared by the token
+/// service and the netcode server
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ClientRegistry(Arc<Mutex<Registry>>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Only accept the client ids issued by the token service
+    tokens_required: bool,
+    issued: HashMap<u64, Instant>,
+    /// Secret each issued client id is bound to, with when the binding expires unless the client
+    /// is connected
+    secrets: HashMap<u64, (ClientSecret, Instant)>,
+    connected: HashSet<u64>,
+    /// How long a disconnected client keeps its id, to reconnect to its player
+    reconnect_grace_period: Duration,
+    /// Last assigned client id, ids are not reused so that a new client can't take over the body
+    /// of a player who may still reconnect
+    last_assigned: u64,
+}
+
+impl ClientRegistry {
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Record that a token is issued to `client_id` at `now`, unless it is already connected or
+    /// bound to another secret, and returns the id the token is for with its secret
+    pub(crate) fn issue(
+        &self,
+        client_id: u64,
+        secret: ClientSecret,
+        now: Instant,
+    ) -> Result<(u64, ClientSecret), TokenError> {
+        let mut registry = self.lock();
+        let Registry {
+            issued,
+            secrets,
+            connected,
+            ..
+        } = &mut *registry;
+        issued.retain(|_, expiry| *expiry > now);
+        secrets.retain(|client_id, (_, expiry)| *expiry > now || connected.contains(client_id));
+        let client_id = match client_id {
+            ASSIGN_CLIENT_ID => registry.assign(),
+            client_id if registry.connected.contains(&client_id) => {
+                return Err(TokenError::AlreadyConnected(client_id));
+            }
+            client_id => client_id,
+        };
+        let expiry = now + TOKEN_EXPIRE;
+        let secret = match registry.secrets.get_mut(&client_id) {
+            Some((bound, _)) if *bound != secret => return Err(TokenError::WrongSecret(client_id)),
+            Some((bound, bound_until)) => {
+                *bound_until = (*bound_until).max(expiry);
+                *bound
+            }
+            None => {
+                let secret = netcode::generate_key();
+                registry.secrets.insert(client_id, (secret, expiry));
+                secret
+            }
+        };
+        registry.issued.insert(client_id, expiry);
+        Ok((client_id, secret))
+    }
+}
+
+impl Registry {
+    fn assign(&mut self) -> u64 {
+        loop {
+            self.last_assigned = self.last_assigned.wrapping_add(1);
+            let client_id = self.last_assigned;
+            if client_id != ASSIGN_CLIENT_ID
+                && !self.connected.contains(&client_id)
+                && !self.secrets.contains_key(&client_id)
+            {
+                return client_id;
+            }
+        }
+    }
+}
+
+impl ConnectionRequestHandler for ClientRegistry {
+    fn handle_request(&self, client_id: ClientId) -> Option<DeniedReason> {
+        // steam ids are authenticated by steam
+        let ClientId::Netcode(client_id) = client_id else {
+            return None;
+        };
+        let registry = self.lock();
+        if registry.connected.contains(&client_id) {
+            return Some(DeniedReason::AlreadyConnected);
+        }
+        let issued = registry
+            .issued
+            .get(&client_id)
+            .is_some_and(|expiry| *expiry > Instant::now());
+        if registry.tokens_required && !issued {
+            return Some(DeniedReason::InvalidToken);
+        }
+        None
+    }
+}
+
+/// Private key and client registry of the netcode server
+#[derive(Clone, Debug)]
+pub struct ServerAuth {
+    pub(crate) private_key: netcode::Key,
+    pub(crate) registry: ClientRegistry,
+}
+
+impl ServerAuth {
+    /// Uses a random key if the server runs a token service, the shared key otherwise
+    pub fn new(settings: &Settings) -> Self {
+        let registry = ClientRegistry::default();
+        registry.lock().reconnect_grace_period =
+            Duration::from_millis(settings.server.reconnect_grace_ms);
+        let private_key = if settings.server.token_port.is_some() {
+            registry.lock().tokens_required = true;
+            netcode::generate_key()
+        } else {
+            if settings.shared.private_key == [0; 32] {
+                warn!("The netcode private key is all zeros, any client can connect with any id");
+            }
+            settings.shared.private_key
+        };
+        Self {
+            private_key,
+            registry,
+        }
+    }
+}
+
+/// Keeps the [`ClientRegistry`] up to date, and runs the token service if `token_port` is set
+pub struct ServerAuthPlugin {
+    pub(crate) auth: ServerAuth,
+    pub(crate) token_port: Option<u16>,
+    /// Port of the netcode server written in the tokens, with the address the client reached the
+    /// token service on
+    pub(crate) game_port: u16,
+    pub(crate) protocol_id: u64,
+}
+
+/// Address the token service listens on
+#[derive(Resource)]
+pub struct TokenService {
+    pub addr: SocketAddr,
+}
+
+impl Plugin for ServerAuthPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.auth.registry.clone()).add_systems(
+            PreUpdate,
+            track_connections.after(lightyear::prelude::MainSet::EmitEvents),
+        );
+        let Some(port) = self.token_port else {
+            return;
+        };
+        let listener = match TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Could not start the token service on port {port}: {e}");
+                return;
+            }
+        };
+        let addr = listener.local_addr().expect("the listener is bound");
+        app.insert_resource(TokenService { addr })
+            .add_systems(Startup, move || info!("Issuing connect tokens on {addr}"));
+        let (game_port, protocol_id) = (self.game_port, self.protocol_id);
+        // a slow client only holds back one worker, until its request times out
+        let (send, recv) = crossbeam_channel::bounded::<TcpStream>(MAX_QUEUED_REQUESTS);
+        for _ in 0..TOKEN_WORKERS {
+            let (auth, recv) = (self.auth.clone(), recv.clone());
+            thread::spawn(move || {
+                for mut stream in recv {
+                    if let Err(e) = issue_token(&mut stream, &auth, game_port, protocol_id) {
+                        warn!("Did not issue a connect token: {e}");
+                    }
+                }
+            });
+        }
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Could not accept a token request: {e}");
+                        continue;
+                    }
+                };
+                if let Err(TrySendError::Full(stream)) = send.try_send(stream) {
+                    warn!(
+                        "Too many pending token requests, dropping the one from {:?}",
+                        stream.peer_addr()
+                    );
+                }
+            }
+        });
+    }
+}
+
+fn issue_token(
+    stream: &mut TcpStream,
+    auth: &ServerAuth,
+    game_port: u16,
+    protocol_id: u64,
+) -> Result<(), TokenError> {
+    // the whole request has to arrive in time, not each of its bytes
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut request = [0; 8 + 32];
+    let mut read = 0;
+    while read < request.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut request[read..])? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            n => read += n,
+        }
+    }
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let (client_id, secret) = request.split_at(8);
+    let client_id = u64::from_be_bytes(client_id.try_into().expect("8 bytes"));
+    let secret = secret.try_into().expect("32 bytes");
+    let (client_id, secret) = auth.registry.issue(client_id, secret, Instant::now())?;
+    let server_addr = SocketAddr::new(stream.local_addr()?.ip(), game_port);
+    let token = netcode::ConnectToken::build(server_addr, protocol_id, client_id, auth.private_key)
+        .expire_seconds(TOKEN_EXPIRE.as_secs() as i32)
+        .generate()?;
+    stream.write_all(&client_id.to_be_bytes())?;
+    stream.write_all(&secret)?;
+    stream.write_all(&token.try_into_bytes()?)?;
+    Ok(())
+}
+
+fn track_connections(
+    registry: Res<ClientRegistry>,
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+) {
+    let mut registry = registry.lock();
+    // the local client of a host-server also takes its id
+    for event in connections.read() {
+        if let ClientId::Netcode(client_id) | ClientId::Local(client_id) = event.client_id {
+            registry.connected.insert(client_id);
+            registry.issued.remove(&client_id);
+        }
+    }
+    // the id stays bound to its secret while the player can reconnect
+    let released = Instant::now() + registry.reconnect_grace_period;
+    for event in disconnections.read() {
+        if let ClientId::Netcode(client_id) | ClientId::Local(client_id) = event.client_id {
+            registry.connected.remove(&client_id);
+            if let Some((_, expiry)) = registry.secrets.get_mut(&client_id) {
+                *expiry = released;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+/// Connect token issued by the token service
+pub struct IssuedToken {
+    pub client_id: u64,
+    /// To send with the next requests for the same id
+    pub secret: ClientSecret,
+    pub token: netcode::ConnectToken,
+}
+
+/// Requests a connect token for `client_id` from the token service at `addr`, with the secret
+/// returned when the id was first issued
+#[cfg(not(target_family = "wasm"))]
+pub fn request_token(
+    addr: SocketAddr,
+    client_id: u64,
+    secret: Option<ClientSecret>,
+) -> Result<IssuedToken, TokenError> {
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(&client_id.to_be_bytes())?;
+    stream.write_all(&secret.unwrap_or(NO_SECRET))?;
+    let refused = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => TokenError::Refused(client_id),
+        _ => e.into(),
+    };
+    let mut assigned = [0; 8];
+    stream.read_exact(&mut assigned).map_err(refused)?;
+    let mut secret = NO_SECRET;
+    stream.read_exact(&mut secret).map_err(refused)?;
+    let mut token = vec![0; CONNECT_TOKEN_BYTES];
+    stream.read_exact(&mut token).map_err(refused)?;
+    let token = netcode::ConnectToken::try_from_bytes(&token).map_err(TokenError::Invalid)?;
+    Ok(IssuedToken {
+        client_id: u64::from_be_bytes(assigned),
+        secret,
+        token,
+    })
+}
+
+/// Random client id for the clients that build their own token, the server refuses it in the
+/// unlikely case that it is already connected
+pub fn random_client_id() -> u64 {
+    let key = netcode::generate_key();
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&key[..8]);
+    match u64::from_le_bytes(bytes) {
+        ASSIGN_CLIENT_ID => random_client_id(),
+        client_id => client_id,
+    }
+}
+
+/// Fetches a new connect token from the token service every time the client connects with
+/// [`Connect`], browsers can't open the TCP connection to the service
+#[cfg(not(target_family = "wasm"))]
+pub struct TokenClientPlugin {
+    pub(crate) service_addr: SocketAddr,
+    /// [`ASSIGN_CLIENT_ID`] to use the id assigned by the server, which is then kept to reconnect
+    pub(crate) client_id: u64,
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[derive(Resource, Clone, Copy)]
+struct TokenSource {
+    service_addr: SocketAddr,
+    client_id: u64,
+    /// Returned with the first token, to get the same id back when reconnecting
+    secret: Option<ClientSecret>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[derive(Resource)]
+struct PendingToken(Receiver<Result<IssuedToken, TokenError>>);
+
+#[cfg(not(target_family = "wasm"))]
+impl Plugin for TokenClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(TokenSource {
+            service_addr: self.service_addr,
+            client_id: self.client_id,
+            secret: None,
+        })
+        .add_systems(Update, receive_token.run_if(resource_exists::<PendingToken>));
+    }
+}
+
+/// Connects the client, after fetching a connect token if it uses a token service
+pub(crate) struct Connect;
+
+impl Command for Connect {
+    fn apply(self, world: &mut World) {
+        #[cfg(not(target_family = "wasm"))]
+        if let Some(source) = world.get_resource::<TokenSource>().copied() {
+            let (send, recv) = crossbeam_channel::bounded(1);
+            thread::spawn(move || {
+                let _ = send.send(request_token(
+                    source.service_addr,
+                    source.client_id,
+                    source.secret,
+                ));
+            });
+            world.insert_resource(PendingToken(recv));
+            return;
+        }
+        world
+            .resource_mut::<NextState<NetworkingState>>()
+            .set(NetworkingState::Connecting);
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn receive_token(
+    mut commands: Commands,
+    pending: Res<PendingToken>,
+    mut source: ResMut<TokenSource>,
+    mut config: ResMut<ClientConfig>,
+) {
+    let token = match pending.0.try_recv() {
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => Err(TokenError::Io(io::ErrorKind::BrokenPipe.into())),
+        Ok(token) => token,
+    };
+    commands.remove_resource::<PendingToken>();
+    match token {
+        Ok(IssuedToken {
+            client_id,
+            secret,
+            token,
+        }) => {
+            source.client_id = client_id;
+            source.secret = Some(secret);
+            if let NetConfig::Netcode { auth, .. } = &mut config.net {
+                *auth = Authentication::Token(token);
+            }
+            commands.connect_client();
+        }
+        Err(e) => error!("Could not get a connect token: {e}"),
+    }
+}
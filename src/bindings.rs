@@ -0,0 +1,482 @@
+//! Input bindings of the player, read from a RON file and rebindable in game
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use avian2d::prelude::Position;
+use bevy::asset::ron;
+use bevy::color::palettes::css;
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::window::PrimaryWindow;
+use leafwing_input_manager::axislike::DualAxisData;
+use leafwing_input_manager::plugin::InputManagerSystem;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::client::*;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::*;
+use crate::settings::read_settings;
+
+/// Keys that control the player
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyLayout {
+    pub(crate) up: KeyCode,
+    pub(crate) down: KeyCode,
+    pub(crate) left: KeyCode,
+    pub(crate) right: KeyCode,
+    /// The actions were added after the movement, layouts without them get the keys of the first
+    /// embedded layout
+    #[serde(default = "default_dash")]
+    pub(crate) dash: KeyCode,
+    #[serde(default = "default_brake")]
+    pub(crate) brake: KeyCode,
+    #[serde(default = "default_kick")]
+    pub(crate) kick: KeyCode,
+}
+
+fn default_dash() -> KeyCode {
+    KeyCode::Space
+}
+
+fn default_brake() -> KeyCode {
+    KeyCode::ShiftLeft
+}
+
+fn default_kick() -> KeyCode {
+    KeyCode::KeyE
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Up,
+    Down,
+    Left,
+    Right,
+    Dash,
+    Brake,
+    Kick,
+}
+
+impl Control {
+    const ALL: [Control; 7] = [
+        Control::Up,
+        Control::Down,
+        Control::Left,
+        Control::Right,
+        Control::Dash,
+        Control::Brake,
+        Control::Kick,
+    ];
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl KeyLayout {
+    fn key(&self, control: Control) -> KeyCode {
+        match control {
+            Control::Up => self.up,
+            Control::Down => self.down,
+            Control::Left => self.left,
+            Control::Right => self.right,
+            Control::Dash => self.dash,
+            Control::Brake => self.brake,
+            Control::Kick => self.kick,
+        }
+    }
+
+    fn key_mut(&mut self, control: Control) -> &mut KeyCode {
+        match control {
+            Control::Up => &mut self.up,
+            Control::Down => &mut self.down,
+            Control::Left => &mut self.left,
+            Control::Right => &mut self.right,
+            Control::Dash => &mut self.dash,
+            Control::Brake => &mut self.brake,
+            Control::Kick => &mut self.kick,
+        }
+    }
+}
+
+/// Input device the client reads, the others are ignored
+///
+/// A client has a single set of bindings for its single player, there are no local players. People
+/// sharing a computer each run a client restricted to their own device, and only the focused
+/// window receives the keyboard, so the keyboard layouts can't be played at the same time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Device {
+    /// Every keyboard layout and every gamepad
+    #[default]
+    Any,
+    /// One keyboard layout, by its index in `keyboard`
+    Keyboard(usize),
+    /// One gamepad, by its id
+    Gamepad(usize),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid device `{0}`, expected any, keyboard:<layout> or gamepad:<id>")]
+pub struct DeviceError(String);
+
+impl FromStr for Device {
+    type Err = DeviceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DeviceError(s.to_string());
+        match s.split_once(':') {
+            None if s == "any" => Ok(Device::Any),
+            Some(("keyboard", index)) => index.parse().map(Device::Keyboard).map_err(|_| invalid()),
+            Some(("gamepad", id)) => id.parse().map(Device::Gamepad).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Resource, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Bindings {
+    /// Any of the layouts moves the player
+    pub(crate) keyboard: Vec<KeyLayout>,
+    /// Move with the left stick of the gamepads and aim with the right one, dash with south, brake
+    /// with east and kick with west
+    pub(crate) gamepad: bool,
+    /// Share of the sticks' travel around their center that is ignored, the rest is rescaled so
+    /// that the speed still goes from 0 to full
+    pub(crate) deadzone: f32,
+    pub(crate) device: Device,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        read_settings(include_str!("../assets/bindings.ron"))
+            .expect("the embedded bindings are valid")
+    }
+}
+
+impl Bindings {
+    pub(crate) fn input_map(&self) -> InputMap<PlayerActions> {
+        let mut input_map = InputMap::default();
+        for (index, layout) in self.keyboard.iter().enumerate() {
+            if matches!(self.device, Device::Any) || self.device == Device::Keyboard(index) {
+                input_map.insert(
+                    PlayerActions::Move,
+                    VirtualDPad {
+                        up: layout.up.into(),
+                        down: layout.down.into(),
+                        left: layout.left.into(),
+                        right: layout.right.into(),
+                    },
+                );
+                input_map.insert_multiple([
+                    (PlayerActions::Dash, layout.dash),
+                    (PlayerActions::Brake, layout.brake),
+                    (PlayerActions::Kick, layout.kick),
+                ]);
+            }
+        }
+        let gamepad = match self.device {
+            Device::Any => self.gamepad,
+            Device::Keyboard(_) => false,
+            Device::Gamepad(id) => {
+                input_map.set_gamepad(Gamepad::new(id));
+                true
+            }
+        };
+        if gamepad {
+            input_map
+                .insert(PlayerActions::Move, DualAxis::left_stick())
+                .insert(PlayerActions::Aim, DualAxis::right_stick())
+                .insert_multiple([
+                    (PlayerActions::Dash, GamepadButtonType::South),
+                    (PlayerActions::Brake, GamepadButtonType::East),
+                    (PlayerActions::Kick, GamepadButtonType::West),
+                ]);
+        }
+        input_map
+    }
+
+    /// Rescales the movement outside the deadzone to the full range, and caps it to 1 so that
+    /// diagonals are not faster
+    pub(crate) fn process(&self, movement: Vec2) -> Vec2 {
+        let length = movement.length();
+        if length <= self.deadzone {
+            return Vec2::ZERO;
+        }
+        let scaled = ((length - self.deadzone) / (1.0 - self.deadzone).max(f32::EPSILON)).min(1.0);
+        movement / length * scaled
+    }
+}
+
+/// Reads the movement and aim of the local player through [`Bindings`]
+pub struct BindingsPlugin;
+
+impl Plugin for BindingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Bindings>()
+            .add_systems(PreUpdate, apply_deadzone.after(InputManagerSystem::Update))
+            .add_systems(
+                Update,
+                update_input_maps.run_if(resource_changed::<Bindings>),
+            );
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(PreUpdate, aim_at_cursor.after(apply_deadzone));
+        }
+    }
+}
+
+/// Processes the sticks of the local player once leafwing has read them, before they are
+/// buffered and sent to the server
+pub(crate) fn apply_deadzone(
+    bindings: Res<Bindings>,
+    mut players: Query<&mut ActionState<PlayerActions>, With<InputMap<PlayerActions>>>,
+) {
+    for mut action in players.iter_mut() {
+        for stick in [PlayerActions::Move, PlayerActions::Aim] {
+            let Some(axis_pair) = action.axis_pair(&stick) else {
+                continue;
+            };
+            let axis_pair = bindings.process(axis_pair.xy());
+            action.action_data_mut_or_default(&stick).axis_pair =
+                Some(DualAxisData::from_xy(axis_pair));
+            if axis_pair == Vec2::ZERO {
+                action.release(&stick);
+            }
+        }
+    }
+}
+
+/// Aims the local player at the cursor, from when it moves until a right stick is used
+#[allow(clippy::too_many_arguments)]
+fn aim_at_cursor(
+    bindings: Res<Bindings>,
+    mut cursor_aim: Local<bool>,
+    mut cursor_moved: EventReader<CursorMoved>,
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    mut players: Query<(&Position, &mut ActionState<PlayerActions>), With<InputMap<PlayerActions>>>,
+) {
+    if matches!(bindings.device, Device::Gamepad(_)) {
+        return;
+    }
+    if cursor_moved.read().count() > 0 {
+        *cursor_aim = true;
+    }
+    let stick_used = bindings.gamepad
+        && gamepads.iter().any(|gamepad| {
+            let axis = |axis_type| axes.get(GamepadAxis::new(gamepad, axis_type));
+            let stick = Vec2::new(
+                axis(GamepadAxisType::RightStickX).unwrap_or_default(),
+                axis(GamepadAxisType::RightStickY).unwrap_or_default(),
+            );
+            bindings.process(stick) != Vec2::ZERO
+        });
+    if stick_used {
+        *cursor_aim = false;
+    }
+    if !*cursor_aim {
+        return;
+    }
+    let Some(cursor) = windows.get_single().ok().and_then(Window::cursor_position) else {
+        return;
+    };
+    let Some(target) = cameras
+        .iter()
+        .find_map(|(camera, transform)| camera.viewport_to_world_2d(transform, cursor))
+    else {
+        return;
+    };
+    for (position, mut action) in players.iter_mut() {
+        let Some(aim) = (target - position.0).try_normalize() else {
+            continue;
+        };
+        action.press(&PlayerActions::Aim);
+        action
+            .action_data_mut_or_default(&PlayerActions::Aim)
+            .axis_pair = Some(DualAxisData::from_xy(aim));
+    }
+}
+
+fn update_input_maps(
+    bindings: Res<Bindings>,
+    mut input_maps: Query<&mut InputMap<PlayerActions>, With<Predicted>>,
+) {
+    for mut input_map in input_maps.iter_mut() {
+        *input_map = bindings.input_map();
+    }
+}
+
+/// Rebinding screen, opened with F1, that saves the bindings to `path`
+pub struct RebindingPlugin {
+    pub(crate) bindings: Bindings,
+    pub(crate) path: Option<PathBuf>,
+}
+
+#[derive(Resource)]
+struct BindingsFile(Option<PathBuf>);
+
+/// Binding waiting for a key press
+#[derive(Resource, Default)]
+struct Rebinding(Option<(usize, Control)>);
+
+#[derive(Component)]
+struct RebindingScreen;
+
+#[derive(Component, Clone, Copy)]
+struct BindingButton {
+    layout: usize,
+    control: Control,
+}
+
+impl Plugin for RebindingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.bindings.clone())
+            .insert_resource(BindingsFile(self.path.clone()))
+            .init_resource::<Rebinding>();
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(
+                Update,
+                (
+                    toggle_screen,
+                    select_binding,
+                    capture_key,
+                    update_labels.run_if(
+                        resource_changed::<Bindings>.or_else(resource_changed::<Rebinding>),
+                    ),
+                )
+                    .chain(),
+            );
+        }
+    }
+}
+
+fn toggle_screen(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<Bindings>,
+    mut rebinding: ResMut<Rebinding>,
+    screens: Query<Entity, With<RebindingScreen>>,
+) {
+    if !keys.just_pressed(KeyCode::F1) {
+        return;
+    }
+    rebinding.0 = None;
+    if let Ok(screen) = screens.get_single() {
+        commands.entity(screen).despawn_recursive();
+        return;
+    }
+    let style = TextStyle {
+        font_size: 20.0,
+        color: Color::WHITE,
+        ..default()
+    };
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(80.0),
+                    left: Val::Px(10.0),
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(4.0),
+                    padding: UiRect::all(Val::Px(8.0)),
+                    ..default()
+                },
+                background_color: css::DARK_SLATE_GRAY.into(),
+                ..default()
+            },
+            RebindingScreen,
+        ))
+        .with_children(|screen| {
+            screen.spawn(TextBundle::from_section(
+                "Click a binding then press its new key, Esc cancels, F1 closes",
+                style.clone(),
+            ));
+            for layout in 0..bindings.keyboard.len() {
+                for control in Control::ALL {
+                    let button = BindingButton { layout, control };
+                    screen
+                        .spawn((
+                            ButtonBundle {
+                                style: Style {
+                                    padding: UiRect::axes(Val::Px(6.0), Val::Px(2.0)),
+                                    ..default()
+                                },
+                                background_color: css::BLACK.into(),
+                                ..default()
+                            },
+                            button,
+                        ))
+                        .with_children(|parent| {
+                            parent.spawn((TextBundle::from_section("", style.clone()), button));
+                        });
+                }
+            }
+        });
+}
+
+#[allow(clippy::type_complexity)]
+fn select_binding(
+    mut rebinding: ResMut<Rebinding>,
+    buttons: Query<(&Interaction, &BindingButton), (Changed<Interaction>, With<Button>)>,
+) {
+    for (interaction, button) in buttons.iter() {
+        if *interaction == Interaction::Pressed {
+            rebinding.0 = Some((button.layout, button.control));
+        }
+    }
+}
+
+fn capture_key(
+    keys: Res<ButtonInput<KeyCode>>,
+    file: Res<BindingsFile>,
+    mut rebinding: ResMut<Rebinding>,
+    mut bindings: ResMut<Bindings>,
+) {
+    let Some((layout, control)) = rebinding.0 else {
+        return;
+    };
+    let Some(key) = keys.get_just_pressed().next().copied() else {
+        return;
+    };
+    rebinding.0 = None;
+    if key == KeyCode::Escape {
+        return;
+    }
+    *bindings.keyboard[layout].key_mut(control) = key;
+    match &file.0 {
+        Some(path) => {
+            let saved = ron::ser::to_string_pretty(bindings.as_ref(), default())
+                .map_err(|e| e.to_string())
+                .and_then(|content| fs::write(path, content).map_err(|e| e.to_string()));
+            match saved {
+                Ok(()) => info!("Saved the bindings to {}", path.display()),
+                Err(e) => error!("Could not save the bindings to {}: {e}", path.display()),
+            }
+        }
+        None => info!("The bindings are not saved, start with --bindings <file> to keep them"),
+    }
+}
+
+fn update_labels(
+    bindings: Res<Bindings>,
+    rebinding: Res<Rebinding>,
+    mut labels: Query<(&mut Text, &BindingButton)>,
+) {
+    for (mut text, button) in labels.iter_mut() {
+        let Some(layout) = bindings.keyboard.get(button.layout) else {
+            continue;
+        };
+        let key = if rebinding.0 == Some((button.layout, button.control)) {
+            "press a key".to_string()
+        } else {
+            format!("{:?}", layout.key(button.control))
+        };
+        text.sections[0].value = format!("Layout {} {}: {key}", button.layout + 1, button.control);
+    }
+}
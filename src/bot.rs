@@ -0,0 +1,322 @@
+//! Headless clients driven by a scripted behaviour, to load-test the server
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use avian2d::prelude::*;
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::prelude::*;
+use bevy::time::common_conditions::on_timer;
+use bevy::utils::Duration;
+use clap::ValueEnum;
+use leafwing_input_manager::axislike::DualAxisData;
+use leafwing_input_manager::prelude::*;
+use lightyear::client::input::leafwing::InputSystemSet;
+use lightyear::prelude::client::*;
+use lightyear::prelude::*;
+
+use crate::app::{client_app, Apps};
+#[cfg(not(target_family = "wasm"))]
+use crate::auth::TokenClientPlugin;
+use crate::auth::{random_client_id, ASSIGN_CLIENT_ID};
+use crate::diagnostics::Metrics;
+use crate::protocol::*;
+use crate::recording::{Recording, RecordingError};
+use crate::settings::get_client_net_config;
+use crate::shared::{XorShift, FIXED_TIMESTEP_HZ};
+use crate::swarm::{update_clients, SwarmClients};
+use crate::MySettings;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a random walk keeps its direction
+const WALK_DURATION: Duration = Duration::from_millis(1500);
+/// Distance kept from the player or the point a bot circles around
+const STRAFE_RADIUS: f32 = 150.0;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BotBehaviour {
+    /// Move in a random direction, or stop, and change every 1.5s
+    RandomWalk,
+    /// Move toward the closest ball
+    ChaseBall,
+    /// Circle around the closest player, or the center of the arena
+    CircleStrafe,
+    /// Loop over the inputs of a player in a recording, given with `--input-file`
+    Replay,
+}
+
+/// Behaviour of a bot, with the recording it replays
+#[derive(Clone)]
+pub enum Behaviour {
+    RandomWalk,
+    ChaseBall,
+    CircleStrafe,
+    /// Plays the inputs of the recorded player `client_id`
+    Replay {
+        recording: Arc<Recording>,
+        client_id: ClientId,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BotError {
+    #[error("the replay behaviour needs an --input-file")]
+    MissingInputFile,
+    #[error(transparent)]
+    Recording(#[from] RecordingError),
+    #[error("the recording has no player inputs")]
+    NoInputs,
+}
+
+impl Behaviour {
+    /// Builds the behaviour chosen on the command line, loading the recording to replay
+    pub fn load(behaviour: BotBehaviour, input_file: Option<&Path>) -> Result<Self, BotError> {
+        Ok(match behaviour {
+            BotBehaviour::RandomWalk => Behaviour::RandomWalk,
+            BotBehaviour::ChaseBall => Behaviour::ChaseBall,
+            BotBehaviour::CircleStrafe => Behaviour::CircleStrafe,
+            BotBehaviour::Replay => {
+                let recording = Recording::load(input_file.ok_or(BotError::MissingInputFile)?)?;
+                let client_id = *recording.inputs.keys().next().ok_or(BotError::NoInputs)?;
+                Behaviour::Replay {
+                    recording: Arc::new(recording),
+                    client_id,
+                }
+            }
+        })
+    }
+
+    /// Behaviour of the `index`th bot: bots replaying a recording take its players in turn
+    fn for_bot(&self, index: u64) -> Self {
+        match self {
+            Behaviour::Replay { recording, .. } => {
+                let mut players: Vec<_> = recording.inputs.keys().copied().collect();
+                players.sort_by_key(ClientId::to_bits);
+                Behaviour::Replay {
+                    recording: recording.clone(),
+                    client_id: players[index as usize % players.len()],
+                }
+            }
+            behaviour => behaviour.clone(),
+        }
+    }
+}
+
+/// Sets the inputs of the client's player from its behaviour instead of the keyboard
+pub struct BotPlugin {
+    pub(crate) behaviour: Behaviour,
+    /// Seed of the random walk
+    pub(crate) seed: u64,
+}
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Bot {
+            behaviour: self.behaviour.clone(),
+            rng: XorShift::new(self.seed),
+            direction: Vec2::ZERO,
+            next_turn: Duration::ZERO,
+            tick: 0,
+        })
+        .add_systems(
+            FixedPreUpdate,
+            drive_bot
+                .before(InputSystemSet::BufferClientInputs)
+                .run_if(not(is_in_rollback)),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct Bot {
+    behaviour: Behaviour,
+    /// Generator of the random walk
+    rng: XorShift,
+    direction: Vec2,
+    /// Time at which the random walk picks a new direction
+    next_turn: Duration,
+    /// Ticks played since the bot started, to index the replayed inputs
+    tick: u32,
+}
+
+#[allow(clippy::type_complexity)]
+fn drive_bot(
+    time: Res<Time>,
+    connection: Res<ClientConnection>,
+    mut bot: ResMut<Bot>,
+    mut players: Query<
+        (&PlayerId, &Position, &mut ActionState<PlayerActions>),
+        (With<Predicted>, With<InputMap<PlayerActions>>),
+    >,
+    others: Query<(&PlayerId, &Position), (Without<Confirmed>, Without<BallMarker>)>,
+    balls: Query<&Position, (With<BallMarker>, Without<Confirmed>)>,
+) {
+    let client_id = connection.id();
+    let Some((_, position, mut action)) = players.iter_mut().find(|(id, ..)| id.0 == client_id)
+    else {
+        return;
+    };
+    let position = position.0;
+    let closest = |points: &mut dyn Iterator<Item = Vec2>| {
+        points.min_by(|a, b| {
+            a.distance_squared(position)
+                .total_cmp(&b.distance_squared(position))
+        })
+    };
+    let direction = match bot.behaviour.clone() {
+        Behaviour::RandomWalk => {
+            if time.elapsed() >= bot.next_turn {
+                bot.next_turn = time.elapsed() + WALK_DURATION;
+                // stop one time out of five
+                bot.direction = if bot.rng.next_f32() < 0.2 {
+                    Vec2::ZERO
+                } else {
+                    Vec2::from_angle(bot.rng.next_f32() * std::f32::consts::TAU)
+                };
+            }
+            bot.direction
+        }
+        Behaviour::ChaseBall => closest(&mut balls.iter().map(|ball| ball.0))
+            .map_or(Vec2::ZERO, |ball| (ball - position).normalize_or_zero()),
+        Behaviour::CircleStrafe => {
+            let center = closest(
+                &mut others
+                    .iter()
+                    .filter(|(id, _)| id.0 != client_id)
+                    .map(|(_, position)| position.0),
+            )
+            .unwrap_or(Vec2::ZERO);
+            let offset = position - center;
+            let radial = offset.normalize_or(Vec2::X);
+            // move along the circle, and back toward it when too close or too far
+            let correction = (STRAFE_RADIUS - offset.length()) / STRAFE_RADIUS;
+            (radial.perp() + radial * correction).normalize_or_zero()
+        }
+        Behaviour::Replay {
+            recording,
+            client_id,
+        } => {
+            let tick = bot.tick % recording.ticks.max(1);
+            bot.tick += 1;
+            let replayed = recording
+                .input(client_id, tick)
+                .and_then(|recorded| recorded.axis_pair(&PlayerActions::Move));
+            replayed.map_or(Vec2::ZERO, |pair| pair.xy())
+        }
+    };
+    if direction == Vec2::ZERO {
+        action.release(&PlayerActions::Move);
+    } else {
+        action.press(&PlayerActions::Move);
+    }
+    action
+        .action_data_mut_or_default(&PlayerActions::Move)
+        .axis_pair = Some(DualAxisData::from_xy(direction));
+}
+
+/// Builds `count` headless bots connected to the server with their own socket, updated by an app
+/// that logs their metrics
+pub(crate) fn bots(
+    settings: &MySettings,
+    count: u64,
+    behaviour: Behaviour,
+    server_ip: IpAddr,
+) -> Apps {
+    let mut settings = settings.clone();
+    settings.common.client.headless = true;
+    settings.common.client.spectator = false;
+    // every bot binds its own socket
+    settings.common.client.client_port = 0;
+
+    let mut bots = vec![];
+    for index in 0..count {
+        let mut bot_settings = settings.clone();
+        // one metrics file per bot
+        if let Some(export) = &mut bot_settings.metrics_export {
+            let stem = export
+                .path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let extension = export
+                .path
+                .extension()
+                .unwrap_or_default()
+                .to_string_lossy();
+            export.path = export
+                .path
+                .with_file_name(format!("{stem}-bot{index}.{extension}"));
+        }
+        let token_port = bot_settings.common.client.token_port;
+        let client_id = match bot_settings.common.client.client_id {
+            Some(first) => first + index,
+            None if token_port.is_some() => ASSIGN_CLIENT_ID,
+            None => random_client_id(),
+        };
+        let net_config = get_client_net_config(&bot_settings.common, client_id, server_ip);
+        let (app, config) = client_app(bot_settings.common.clone(), net_config);
+        let mut bot = Apps::Client { app, config };
+        #[cfg(not(target_family = "wasm"))]
+        if let Some(port) = token_port {
+            bot.add_plugins(TokenClientPlugin {
+                service_addr: SocketAddr::new(server_ip, port),
+                client_id,
+            });
+        }
+        crate::add_plugins(&mut bot, &bot_settings);
+        let Apps::Client { mut app, .. } = bot else {
+            unreachable!("the bots are clients");
+        };
+        app.add_plugins(BotPlugin {
+            behaviour: behaviour.for_bot(index),
+            seed: client_id ^ index.wrapping_mul(0x9E37_79B9_7F4A_7C15),
+        });
+        app.finish();
+        app.cleanup();
+        bots.push(app);
+    }
+
+    let mut app = App::new();
+    app.add_plugins(
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            1.0 / FIXED_TIMESTEP_HZ,
+        ))),
+    )
+    .insert_non_send_resource(SwarmClients(bots))
+    .add_systems(Last, update_clients)
+    .add_systems(Last, report_bots.run_if(on_timer(REPORT_INTERVAL)));
+    Apps::Bots { app }
+}
+
+/// Logs the RTT, rollbacks and bandwidth of every bot, then their average
+fn report_bots(mut bots: NonSendMut<SwarmClients>) {
+    let mut connected = 0;
+    let (mut rtt_ms, mut rollbacks, mut kb_received, mut kb_sent) = (0.0, 0, 0.0, 0.0);
+    for bot in bots.0.iter_mut() {
+        let world = bot.world_mut();
+        if *world.resource::<State<NetworkingState>>().get() != NetworkingState::Connected {
+            continue;
+        }
+        let client_id = world.resource::<ClientConnection>().id();
+        let metrics = world.resource::<Metrics>();
+        let sample = &metrics.sample;
+        info!(
+            "Bot {client_id}: rtt {:.0}ms, {} rollbacks, {:.1} kB/s in, {:.1} kB/s out",
+            sample.rtt_ms, metrics.rollbacks, sample.kb_received_per_s, sample.kb_sent_per_s
+        );
+        connected += 1;
+        rtt_ms += sample.rtt_ms;
+        rollbacks += metrics.rollbacks;
+        kb_received += sample.kb_received_per_s;
+        kb_sent += sample.kb_sent_per_s;
+    }
+    if connected == 0 {
+        info!("No bot is connected");
+        return;
+    }
+    info!(
+        "{connected}/{} bots connected: average rtt {:.0}ms, {rollbacks} rollbacks, {kb_received:.1} kB/s in, {kb_sent:.1} kB/s out",
+        bots.0.len(),
+        rtt_ms / connected as f64,
+    );
+}
@@ -2,18 +2,48 @@ use avian2d::prelude::*;
 use bevy::ecs::system::SystemState;
 use bevy::ecs::world;
 use bevy::prelude::*;
+use bevy::utils::Duration;
 use leafwing_input_manager::prelude::*;
 use lightyear::prelude::client::*;
 use lightyear::prelude::*;
 
+use crate::auth::Connect;
+use crate::bindings::{Bindings, BindingsPlugin};
+use crate::diagnostics::MetricsPlugin;
+use crate::level::{spawn_level, Level, LevelGeometry};
 use crate::protocol::*;
-use crate::shared::{shared_movement_behaviour, FixedSet};
+use crate::settings::{ConditionerPreset, MetricsExport};
+use crate::shared::{
+    shared_action_behaviour, shared_aim_behaviour, shared_movement_behaviour, FixedSet,
+};
+use crate::snapshot::SnapshotReceiverPlugin;
+use crate::soccer::ScoreboardPlugin;
+use crate::spectator::{Spectator, SpectatorPlugin};
+use crate::team::RosterPlugin;
 
-pub struct ClientPlugin;
+pub struct ClientPlugin {
+    pub(crate) spectator: bool,
+    pub(crate) show_diagnostics: bool,
+    pub(crate) metrics_export: Option<MetricsExport>,
+}
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
+        app.add_plugins((
+            BindingsPlugin,
+            ScoreboardPlugin,
+            RosterPlugin,
+            SnapshotReceiverPlugin,
+            MetricsPlugin {
+                show_overlay: self.show_diagnostics,
+                export: self.metrics_export.clone(),
+            },
+        ));
+        if self.spectator {
+            app.add_plugins(SpectatorPlugin);
+        }
         app.insert_resource(MockInputSettings::default())
+            .init_resource::<LinkProfile>()
             .add_systems(Startup, init)
             .add_systems(
                 PreUpdate,
@@ -27,6 +57,16 @@ impl Plugin for ClientPlugin {
                 (
                     add_ball_physics,
                     add_player_physics,
+                    add_player_inputs,
+                    load_level,
+                    (
+                        notify_player_left,
+                        notify_goal,
+                        notify_link_profile,
+                        expire_notices,
+                    )
+                        .chain(),
+                    cycle_link_profile,
                     handle_predicted_spawn,
                     handle_interpolated_spawn,
                     mock_input.run_if(|mis: Res<MockInputSettings>| mis.enabled),
@@ -36,52 +76,183 @@ impl Plugin for ClientPlugin {
     }
 }
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct MockInputSettings {
-    enabled: bool,
-}
-
-impl Default for MockInputSettings {
-    fn default() -> Self {
-        Self { enabled: false }
-    }
+    pub(crate) enabled: bool,
 }
 
 pub(crate) fn init(mut commands: Commands) {
-    commands.connect_client();
+    commands.add(Connect);
 }
 
 pub(crate) fn handle_connection(
     mut commands: Commands,
+    spectator: Option<Res<Spectator>>,
+    mut connection: ResMut<ConnectionManager>,
     mut connection_event: EventReader<ConnectEvent>,
 ) {
     for event in connection_event.read() {
         let client_id = event.client_id();
+        let role = if spectator.is_some() { "Spectator" } else { "Client" };
         commands.spawn(TextBundle::from_section(
-            format!("Client {}", client_id),
+            format!("{role} {client_id}"),
             TextStyle {
                 font_size: 30.0,
                 color: Color::WHITE,
                 ..default()
             },
         ));
-        let y = (client_id.to_bits() as f32 * 50.0) % 500.0 - 250.0;
-        commands.spawn(PlayerBundle::new(
-            client_id,
-            Vec2::new(-50.0, y),
-            InputMap::new([(
-                PlayerActions::Move,
-                VirtualDPad {
-                    up: KeyCode::KeyW.into(),
-                    down: KeyCode::KeyS.into(),
-                    left: KeyCode::KeyA.into(),
-                    right: KeyCode::KeyD.into(),
-                },
-            )]),
-        ));
+        let join = JoinGame {
+            spectator: spectator.is_some(),
+        };
+        if let Err(e) = connection.send_message::<LobbyChannel, _>(&join) {
+            error!("Could not join the game: {e}");
+        }
+    }
+}
+
+/// Replaces the level with the one the server plays on
+fn load_level(
+    mut commands: Commands,
+    level: Option<Res<Level>>,
+    mut events: EventReader<MessageEvent<ActiveLevel>>,
+    geometry: Query<Entity, With<LevelGeometry>>,
+) {
+    let Some(event) = events.read().last() else {
+        return;
+    };
+    let active = &event.message().level;
+    // the host-server's client shares the server's level
+    if level.is_some_and(|level| *level == *active) {
+        return;
+    }
+    info!("Playing on level {}", active.name);
+    for entity in geometry.iter() {
+        commands.entity(entity).despawn();
+    }
+    spawn_level(&mut commands, active);
+    commands.insert_resource(active.clone());
+}
+
+const NOTICE_DURATION: Duration = Duration::from_secs(4);
+
+/// Short-lived message shown under the client id
+#[derive(Component)]
+struct Notice(Timer);
+
+/// Shows `text` as the `index`th notice
+fn spawn_notice(commands: &mut Commands, text: String, index: usize) {
+    commands.spawn((
+        TextBundle::from_section(
+            text,
+            TextStyle {
+                font_size: 20.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(40.0 + 24.0 * index as f32),
+            left: Val::Px(0.0),
+            ..default()
+        }),
+        Notice(Timer::new(NOTICE_DURATION, TimerMode::Once)),
+    ));
+}
+
+fn notify_player_left(
+    mut commands: Commands,
+    mut events: EventReader<MessageEvent<PlayerLeft>>,
+    notices: Query<(), With<Notice>>,
+) {
+    for (index, event) in (notices.iter().count()..).zip(events.read()) {
+        let client_id = event.message().client_id;
+        info!("Player {client_id} left");
+        spawn_notice(&mut commands, format!("Player {client_id} left"), index);
+    }
+}
+
+fn notify_goal(
+    mut commands: Commands,
+    mut events: EventReader<MessageEvent<GoalScored>>,
+    notices: Query<(), With<Notice>>,
+) {
+    for (index, event) in (notices.iter().count()..).zip(events.read()) {
+        let goal = event.message();
+        let text = format!("{} scored, {} - {}", goal.team, goal.red, goal.blue);
+        info!("{text}");
+        spawn_notice(&mut commands, text, index);
+    }
+}
+
+/// Conditions the server simulates on the link of this client
+#[derive(Resource, Default)]
+pub(crate) struct LinkProfile(pub(crate) Option<ConditionerPreset>);
+
+/// Asks the server to simulate the next link profile with L
+fn cycle_link_profile(
+    keys: Res<ButtonInput<KeyCode>>,
+    profile: Res<LinkProfile>,
+    mut connection: ResMut<ConnectionManager>,
+) {
+    if !keys.just_pressed(KeyCode::KeyL) {
+        return;
+    }
+    let request = RequestLinkProfile {
+        preset: ConditionerPreset::next(profile.0),
+    };
+    if let Err(e) = connection.send_message::<LobbyChannel, _>(&request) {
+        error!("Could not request the link profile: {e}");
+    }
+}
+
+fn notify_link_profile(
+    mut commands: Commands,
+    mut profile: ResMut<LinkProfile>,
+    mut events: EventReader<MessageEvent<LinkProfileChanged>>,
+    notices: Query<(), With<Notice>>,
+) {
+    for (index, event) in (notices.iter().count()..).zip(events.read()) {
+        profile.0 = event.message().preset;
+        let text = match profile.0 {
+            Some(preset) => format!("Simulating {preset} on the link"),
+            None => "Not simulating network conditions".to_string(),
+        };
+        info!("{text}");
+        spawn_notice(&mut commands, text, index);
+    }
+}
+
+fn expire_notices(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut notices: Query<(Entity, &mut Notice)>,
+) {
+    for (entity, mut notice) in notices.iter_mut() {
+        if notice.0.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// The server spawns the player, the client adds the input map once it is predicted
+fn add_player_inputs(
+    connection: Res<ClientConnection>,
+    bindings: Res<Bindings>,
+    mut commands: Commands,
+    players: Query<(Entity, &PlayerId), Added<Predicted>>,
+) {
+    let client_id = connection.id();
+    for (entity, player_id) in players.iter() {
+        if player_id.0 != client_id {
+            continue;
+        }
+        commands.entity(entity).insert(bindings.input_map());
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn add_ball_physics(
     mut commands: Commands,
     mut ball_query: Query<
@@ -97,20 +268,17 @@ fn add_ball_physics(
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn add_player_physics(
-    connection: Res<ClientConnection>,
     mut commands: Commands,
-    mut player_query: Query<(Entity, &PlayerId), (Or<(Added<Interpolated>, Added<Predicted>)>,)>,
+    mut player_query: Query<Entity, (With<PlayerId>, Or<(Added<Interpolated>, Added<Predicted>)>)>,
 ) {
-    let client_id = connection.id();
-    for (entity, player_id) in player_query.iter_mut() {
-        if player_id.0 == client_id {
-            continue;
-        }
+    for entity in player_query.iter_mut() {
         commands.entity(entity).insert(PhysicsBundle::player());
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn player_movement(
     tick_manager: Res<TickManager>,
     mut velocity_query: Query<
@@ -118,39 +286,57 @@ fn player_movement(
             Entity,
             &PlayerId,
             &Position,
+            &Rotation,
             &mut LinearVelocity,
+            &mut AngularVelocity,
+            &mut Cooldowns,
             &ActionState<PlayerActions>,
         ),
-        With<Predicted>,
+        (With<Predicted>, Without<BallMarker>),
     >,
+    mut balls: Query<(&Position, &mut LinearVelocity), (With<BallMarker>, With<Predicted>)>,
 ) {
-    for (entity, player_id, position, velocity, action_state) in velocity_query.iter_mut() {
+    for (
+        entity,
+        player_id,
+        position,
+        rotation,
+        mut velocity,
+        mut angular_velocity,
+        mut cooldowns,
+        action_state,
+    ) in velocity_query.iter_mut()
+    {
         if !action_state.get_pressed().is_empty() {
-            trace!(?entity, tick = ?tick_manager.tick(), ?position, actions = ?action_state.get_pressed(), "applying movement to predicted player");
-
-            shared_movement_behaviour(velocity, action_state);
+            trace!(?entity, tick = ?tick_manager.tick(), ?position, actions = ?action_state.get_pressed(), "applying actions to predicted player");
+        }
+        if action_state.pressed(&PlayerActions::Move) {
+            shared_movement_behaviour(velocity.reborrow(), action_state);
         }
+        shared_aim_behaviour(action_state, rotation, &mut angular_velocity);
+        shared_action_behaviour(
+            action_state,
+            position,
+            rotation,
+            &mut velocity,
+            &mut cooldowns,
+            &mut balls,
+        );
     }
 }
 
-fn mock_input_toggle() {}
+/// Turns the mock input on and off with T
+fn mock_input_toggle(keys: Res<ButtonInput<KeyCode>>, mut mis: ResMut<MockInputSettings>) {
+    if keys.just_pressed(KeyCode::KeyT) {
+        mis.enabled = !mis.enabled;
+    }
+}
 
-fn mock_input(
-    world: &mut World,
-    params: &mut SystemState<(
-        Res<Time>,
-        Res<ButtonInput<KeyCode>>,
-        ResMut<MockInputSettings>,
-    )>,
-) {
+fn mock_input(world: &mut World, params: &mut SystemState<Res<Time>>) {
     world.release_input(KeyCode::KeyA);
     world.release_input(KeyCode::KeyD);
 
-    let (time, keys, mut mis) = params.get_mut(world);
-
-    if keys.just_pressed(KeyCode::KeyT) {
-        mis.enabled = !mis.enabled;
-    }
+    let time = params.get_mut(world);
 
     if (time.elapsed_seconds() * 1.5) as i32 & 1 == 0 {
         world.send_input(KeyCode::KeyA)
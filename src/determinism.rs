@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+
+use avian2d::collision::narrow_phase::NarrowPhaseSet;
+use avian2d::dynamics::solver::SolverConfig;
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use lightyear::prelude::client::{is_synced, Predicted, PredictionSet, Rollback};
+use lightyear::prelude::server::is_started;
+use lightyear::prelude::*;
+use lightyear::shared::replication::components::Replicating;
+use lightyear::shared::run_conditions::is_mode_separate;
+
+use crate::protocol::*;
+
+/// Substeps used by both the client and the server in deterministic mode
+const DETERMINISTIC_SUBSTEPS: u32 = 6;
+/// Number of predicted checksums kept to compare with the ones sent by the server
+const CHECKSUM_HISTORY_LEN: usize = 256;
+/// Consecutive server checksums over a different number of bodies after which the predicted world
+/// is considered impossible to check, rather than waiting for spawned bodies to be replicated
+const MAX_MISMATCHED_BODIES: usize = 128;
+
+/// Makes the avian2d step independent of local state, and checks that the client predicts the
+/// same world as the server by comparing a checksum of every tick
+pub struct DeterminismPlugin;
+
+impl Plugin for DeterminismPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(DeterministicPhysicsPlugin)
+            .init_resource::<ChecksumHistory>()
+            .add_systems(
+                FixedPostUpdate,
+                (
+                    send_checksum.run_if(is_started),
+                    record_checksum
+                        .run_if(is_synced.and_then(is_mode_separate))
+                        .before(PredictionSet::IncrementRollbackTick),
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    compare_checksums.run_if(is_synced.and_then(is_mode_separate)),
+                    log_desync_reports.run_if(is_started),
+                ),
+            );
+    }
+}
+
+/// Only the physics configuration of [`DeterminismPlugin`], for apps that are not networked
+pub struct DeterministicPhysicsPlugin;
+
+impl Plugin for DeterministicPhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SubstepCount(DETERMINISTIC_SUBSTEPS))
+            // warm starting reuses the impulses of the previous step, which are not restored on rollback
+            .insert_resource(SolverConfig {
+                warm_start_coefficient: 0.0,
+                ..default()
+            })
+            .add_systems(
+                PhysicsSchedule,
+                sort_collisions
+                    .after(NarrowPhaseSet::PostProcess)
+                    .before(NarrowPhaseSet::GenerateConstraints),
+            );
+    }
+}
+
+/// Checksums of the predicted world, indexed by tick
+#[derive(Resource, Default)]
+pub struct ChecksumHistory {
+    checksums: VecDeque<(Tick, u32, u64)>,
+    diverged: bool,
+    /// Number of server checksums that matched the predicted world
+    pub(crate) matched: usize,
+    /// Number of consecutive server checksums over a different number of bodies
+    mismatched_bodies: usize,
+    /// The client doesn't predict every body of the server, because of `predict_all: false` or the
+    /// area of interest, so the checksums are not compared
+    pub(crate) uncheckable: bool,
+}
+
+impl ChecksumHistory {
+    fn insert(&mut self, tick: Tick, bodies: u32, checksum: u64) {
+        // ticks that are re-simulated during a rollback replace their previous checksum
+        if let Some(entry) = self.checksums.iter_mut().find(|(t, ..)| *t == tick) {
+            *entry = (tick, bodies, checksum);
+            return;
+        }
+        if self.checksums.len() == CHECKSUM_HISTORY_LEN {
+            self.checksums.pop_front();
+        }
+        self.checksums.push_back((tick, bodies, checksum));
+    }
+
+    fn get(&self, tick: Tick) -> Option<(u32, u64)> {
+        self.checksums
+            .iter()
+            .find(|(t, ..)| *t == tick)
+            .map(|(_, bodies, checksum)| (*bodies, *checksum))
+    }
+}
+
+/// Order independent checksum of the physics state of the given bodies
+pub(crate) fn world_checksum<'a>(
+    bodies: impl Iterator<Item = (&'a Position, &'a Rotation, &'a LinearVelocity)>,
+) -> u64 {
+    bodies
+        .map(|(position, rotation, velocity)| {
+            // FNV-1a over the raw bits, so that any difference is detected
+            [
+                position.x,
+                position.y,
+                rotation.cos,
+                rotation.sin,
+                velocity.x,
+                velocity.y,
+            ]
+            .iter()
+            .fold(0xcbf29ce484222325, |hash: u64, value| {
+                (hash ^ value.to_bits() as u64).wrapping_mul(0x100000001b3)
+            })
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+/// Solve the contacts in an order that only depends on the positions of the bodies,
+/// since entities are not the same on the client and the server
+fn sort_collisions(mut collisions: ResMut<Collisions>, positions: Query<&Position>) {
+    let key = |entity: Entity| {
+        positions
+            .get(entity)
+            .map_or((0, 0), |p| (p.x.to_bits(), p.y.to_bits()))
+    };
+    collisions
+        .get_internal_mut()
+        .sort_by_cached_key(|(entity1, entity2), _| {
+            let (key1, key2) = (key(*entity1), key(*entity2));
+            (key1.min(key2), key1.max(key2))
+        });
+}
+
+fn send_checksum(
+    tick_manager: Res<TickManager>,
+    mut connection: ResMut<server::ConnectionManager>,
+    bodies: Query<(&Position, &Rotation, &LinearVelocity), With<Replicating>>,
+) {
+    let message = WorldChecksum {
+        tick: tick_manager.tick(),
+        bodies: bodies.iter().len() as u32,
+        checksum: world_checksum(bodies.iter()),
+    };
+    connection
+        .send_message_to_target::<ChecksumChannel, _>(&message, NetworkTarget::All)
+        .unwrap_or_else(|e| error!("Could not send the world checksum: {e}"));
+}
+
+fn record_checksum(
+    tick_manager: Res<TickManager>,
+    rollback: Res<Rollback>,
+    mut history: ResMut<ChecksumHistory>,
+    bodies: Query<(&Predicted, &Position, &Rotation, &LinearVelocity)>,
+) {
+    let tick = tick_manager.tick_or_rollback_tick(&rollback);
+    // pre-predicted entities don't exist on the server until they are confirmed
+    let bodies: Vec<_> = bodies
+        .iter()
+        .filter(|(predicted, ..)| predicted.confirmed_entity.is_some())
+        .map(|(_, position, rotation, velocity)| (position, rotation, velocity))
+        .collect();
+    history.insert(tick, bodies.len() as u32, world_checksum(bodies.into_iter()));
+}
+
+/// Report the first tick where the predicted world differs from the server
+fn compare_checksums(
+    mut history: ResMut<ChecksumHistory>,
+    mut connection: ResMut<client::ConnectionManager>,
+    mut checksums: EventReader<client::MessageEvent<WorldChecksum>>,
+) {
+    for event in checksums.read() {
+        let WorldChecksum {
+            tick,
+            bodies,
+            checksum,
+        } = *event.message();
+        // bodies that were just spawned or despawned can't be compared until they are replicated
+        let Some((predicted_bodies, predicted)) = history.get(tick) else {
+            continue;
+        };
+        if predicted_bodies != bodies {
+            history.mismatched_bodies += 1;
+            if history.mismatched_bodies == MAX_MISMATCHED_BODIES {
+                history.uncheckable = true;
+                warn!(
+                    predicted_bodies,
+                    server_bodies = bodies,
+                    "The predicted world can't be checked against the server, which simulates \
+                     bodies this client doesn't predict: set predict_all and disable the area of \
+                     interest"
+                );
+            }
+            continue;
+        }
+        history.mismatched_bodies = 0;
+        if predicted == checksum {
+            history.diverged = false;
+            history.matched += 1;
+            continue;
+        }
+        if !history.diverged {
+            history.diverged = true;
+            warn!(?tick, "Predicted world diverged from the server");
+            connection
+                .send_message::<ChecksumChannel, _>(&DesyncReport { tick })
+                .unwrap_or_else(|e| error!("Could not send the desync report: {e}"));
+        }
+    }
+}
+
+fn log_desync_reports(mut reports: EventReader<server::MessageEvent<DesyncReport>>) {
+    for event in reports.read() {
+        warn!(client_id = ?event.context(), tick = ?event.message().tick, "Client predicted world diverged");
+    }
+}
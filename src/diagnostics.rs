@@ -0,0 +1,315 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use bevy::diagnostic::DiagnosticsStore;
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::time::common_conditions::on_timer;
+use bevy::utils::Duration;
+use lightyear::client::prediction::diagnostics::{PredictionDiagnosticsPlugin, PredictionMetrics};
+use lightyear::prelude::client::*;
+use lightyear::prelude::*;
+use lightyear::transport::io::IoDiagnosticsPlugin;
+use serde::Serialize;
+
+use crate::protocol::*;
+use crate::settings::{MetricsExport, MetricsFormat};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Samples the prediction and network metrics of the client, shows them in an overlay
+/// and writes them to a file
+///
+/// The received bytes are broken down by channel, the sent ones are only measured in total. The
+/// breakdown is an estimate: the game messages are re-encoded the way lightyear encodes them,
+/// without their message and packet headers, and the rest of the measured traffic is attributed
+/// to replication, the inputs of the other players and the packet headers
+pub struct MetricsPlugin {
+    pub(crate) show_overlay: bool,
+    pub(crate) export: Option<MetricsExport>,
+}
+
+impl Plugin for MetricsPlugin {
+    fn build(&self, app: &mut App) {
+        // lightyear only updates the io and rollback diagnostics when their plugins are added
+        if !app.is_plugin_added::<IoDiagnosticsPlugin>() {
+            app.add_plugins(IoDiagnosticsPlugin);
+        }
+        if !app.is_plugin_added::<PredictionDiagnosticsPlugin>() {
+            app.add_plugins(PredictionDiagnosticsPlugin::default());
+        }
+        app.insert_resource(ShowDiagnostics(self.show_overlay))
+            .init_resource::<Metrics>()
+            .init_resource::<ReceivedBytes>()
+            .add_systems(
+                PreUpdate,
+                (
+                    count_received::<BallSnapshot>(|bytes| &mut bytes.snapshots),
+                    count_received::<WorldChecksum>(|bytes| &mut bytes.checksums),
+                    count_received::<ActiveLevel>(|bytes| &mut bytes.lobby),
+                    count_received::<GoalScored>(|bytes| &mut bytes.lobby),
+                    count_received::<PlayerLeft>(|bytes| &mut bytes.lobby),
+                    count_received::<LinkProfileChanged>(|bytes| &mut bytes.lobby),
+                )
+                    .after(MainSet::EmitEvents),
+            )
+            .add_systems(
+                PostUpdate,
+                sample_metrics.run_if(on_timer(SAMPLE_INTERVAL).and_then(is_connected)),
+            );
+        if let Some(export) = &self.export {
+            match MetricsWriter::create(export) {
+                Ok(writer) => {
+                    app.insert_resource(writer).add_systems(
+                        PostUpdate,
+                        write_metrics
+                            .after(sample_metrics)
+                            .run_if(resource_changed::<Metrics>),
+                    );
+                }
+                Err(e) => error!("Could not create the metrics file {}: {e}", export.path.display()),
+            }
+        }
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, spawn_overlay).add_systems(
+                Update,
+                (
+                    update_overlay.run_if(resource_changed::<Metrics>),
+                    toggle_overlay.run_if(resource_changed::<ShowDiagnostics>),
+                ),
+            );
+        }
+    }
+}
+
+/// Whether to draw the diagnostics overlay
+#[derive(Resource)]
+pub struct ShowDiagnostics(pub(crate) bool);
+
+/// Latest sample of the client metrics, rates are averaged since the previous sample
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct MetricsSample {
+    pub time_s: f64,
+    pub tick: u16,
+    pub rollbacks_per_s: f64,
+    /// Average number of ticks resimulated by a rollback
+    pub rollback_depth: f64,
+    pub rtt_ms: f64,
+    pub jitter_ms: f64,
+    pub kb_received_per_s: f64,
+    pub kb_sent_per_s: f64,
+    /// Estimated from the messages received on the [`SnapshotChannel`]
+    pub estimated_kb_snapshots_per_s: f64,
+    /// Estimated from the messages received on the [`ChecksumChannel`]
+    pub estimated_kb_checksums_per_s: f64,
+    /// Estimated from the messages received on the [`LobbyChannel`]
+    pub estimated_kb_lobby_per_s: f64,
+    /// Rest of the received bytes: replication, inputs and packet headers
+    pub estimated_kb_replication_per_s: f64,
+    pub input_delay_ticks: u16,
+}
+
+impl MetricsSample {
+    const CSV_HEADER: &'static str = "time_s,tick,rollbacks_per_s,rollback_depth,rtt_ms,jitter_ms,kb_received_per_s,kb_sent_per_s,estimated_kb_snapshots_per_s,estimated_kb_checksums_per_s,estimated_kb_lobby_per_s,estimated_kb_replication_per_s,input_delay_ticks";
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{:.3},{},{:.2},{:.2},{:.2},{:.2},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{}",
+            self.time_s,
+            self.tick,
+            self.rollbacks_per_s,
+            self.rollback_depth,
+            self.rtt_ms,
+            self.jitter_ms,
+            self.kb_received_per_s,
+            self.kb_sent_per_s,
+            self.estimated_kb_snapshots_per_s,
+            self.estimated_kb_checksums_per_s,
+            self.estimated_kb_lobby_per_s,
+            self.estimated_kb_replication_per_s,
+            self.input_delay_ticks,
+        )
+    }
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct Metrics {
+    pub(crate) sample: MetricsSample,
+    /// Cumulative rollback counters at the time of the last sample
+    pub(crate) rollbacks: u32,
+    rollback_ticks: u32,
+}
+
+/// Encoded size of the game messages received on each channel since the last sample
+#[derive(Resource, Default)]
+struct ReceivedBytes {
+    snapshots: usize,
+    checksums: usize,
+    lobby: usize,
+}
+
+fn count_received<M: Message + Serialize>(
+    counter: fn(&mut ReceivedBytes) -> &mut usize,
+) -> impl FnMut(EventReader<client::MessageEvent<M>>, ResMut<ReceivedBytes>) {
+    move |mut messages, mut received| {
+        for event in messages.read() {
+            *counter(&mut received) +=
+                bincode::serde::encode_to_vec(event.message(), bincode::config::standard())
+                    .map_or(0, |bytes| bytes.len());
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sample_metrics(
+    time: Res<Time<Real>>,
+    tick_manager: Res<TickManager>,
+    config: Res<ClientConfig>,
+    connection: Res<ConnectionManager>,
+    prediction: Res<PredictionMetrics>,
+    diagnostics: Res<DiagnosticsStore>,
+    mut metrics: ResMut<Metrics>,
+    mut received: ResMut<ReceivedBytes>,
+) {
+    let elapsed = time.elapsed_seconds_f64() - metrics.sample.time_s;
+    let rollbacks = prediction.rollbacks.wrapping_sub(metrics.rollbacks);
+    let rollback_ticks = prediction.rollback_ticks.wrapping_sub(metrics.rollback_ticks);
+    let io = |path| {
+        diagnostics
+            .get(path)
+            .and_then(|d| d.smoothed())
+            .unwrap_or_default()
+    };
+    let kb_per_s = |bytes: usize| bytes as f64 / 1000.0 / elapsed;
+    let ReceivedBytes {
+        snapshots,
+        checksums,
+        lobby,
+    } = std::mem::take(received.as_mut());
+    let kb_received_per_s = io(&IoDiagnosticsPlugin::BYTES_IN);
+    let rtt = connection.ping_manager.rtt();
+    metrics.sample = MetricsSample {
+        time_s: time.elapsed_seconds_f64(),
+        tick: tick_manager.tick().0,
+        rollbacks_per_s: rollbacks as f64 / elapsed,
+        rollback_depth: if rollbacks == 0 {
+            0.0
+        } else {
+            rollback_ticks as f64 / rollbacks as f64
+        },
+        rtt_ms: rtt.as_secs_f64() * 1000.0,
+        jitter_ms: connection.ping_manager.jitter().as_secs_f64() * 1000.0,
+        kb_received_per_s,
+        kb_sent_per_s: io(&IoDiagnosticsPlugin::BYTES_OUT),
+        estimated_kb_snapshots_per_s: kb_per_s(snapshots),
+        estimated_kb_checksums_per_s: kb_per_s(checksums),
+        estimated_kb_lobby_per_s: kb_per_s(lobby),
+        // the smoothed total can lag behind the messages counted since the last sample
+        estimated_kb_replication_per_s: (kb_received_per_s
+            - kb_per_s(snapshots + checksums + lobby))
+        .max(0.0),
+        input_delay_ticks: config
+            .prediction
+            .input_delay_ticks(rtt, config.shared.tick.tick_duration),
+    };
+    metrics.rollbacks = prediction.rollbacks;
+    metrics.rollback_ticks = prediction.rollback_ticks;
+}
+
+#[derive(Resource)]
+struct MetricsWriter {
+    format: MetricsFormat,
+    file: BufWriter<File>,
+}
+
+impl MetricsWriter {
+    fn create(export: &MetricsExport) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(&export.path)?);
+        if export.format == MetricsFormat::Csv {
+            writeln!(file, "{}", MetricsSample::CSV_HEADER)?;
+        }
+        Ok(Self {
+            format: export.format,
+            file,
+        })
+    }
+
+    fn write(&mut self, sample: &MetricsSample) -> std::io::Result<()> {
+        match self.format {
+            MetricsFormat::Csv => writeln!(self.file, "{}", sample.to_csv())?,
+            MetricsFormat::JsonLines => {
+                serde_json::to_writer(&mut self.file, sample)?;
+                writeln!(self.file)?;
+            }
+        }
+        // flush every sample so that the file can be read while the client runs
+        self.file.flush()
+    }
+}
+
+fn write_metrics(metrics: Res<Metrics>, mut writer: ResMut<MetricsWriter>) {
+    writer
+        .write(&metrics.sample)
+        .unwrap_or_else(|e| error!("Could not write the metrics: {e}"));
+}
+
+#[derive(Component)]
+struct DiagnosticsOverlay;
+
+fn spawn_overlay(mut commands: Commands, show: Res<ShowDiagnostics>) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        }),
+        if show.0 {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        },
+        DiagnosticsOverlay,
+    ));
+}
+
+fn update_overlay(metrics: Res<Metrics>, mut overlay: Query<&mut Text, With<DiagnosticsOverlay>>) {
+    let sample = &metrics.sample;
+    for mut text in overlay.iter_mut() {
+        text.sections[0].value = format!(
+            "rollbacks: {:.1}/s\nrollback depth: {:.1} ticks\nrtt: {:.0} ms\njitter: {:.1} ms\nreceived: {:.1} KB/s, estimated:\n  replication: {:.1} KB/s\n  snapshots: {:.1} KB/s\n  checksums: {:.1} KB/s\n  lobby: {:.1} KB/s\nsent: {:.1} KB/s\ninput delay: {} ticks",
+            sample.rollbacks_per_s,
+            sample.rollback_depth,
+            sample.rtt_ms,
+            sample.jitter_ms,
+            sample.kb_received_per_s,
+            sample.estimated_kb_replication_per_s,
+            sample.estimated_kb_snapshots_per_s,
+            sample.estimated_kb_checksums_per_s,
+            sample.estimated_kb_lobby_per_s,
+            sample.kb_sent_per_s,
+            sample.input_delay_ticks,
+        );
+    }
+}
+
+fn toggle_overlay(
+    show: Res<ShowDiagnostics>,
+    mut overlay: Query<&mut Visibility, With<DiagnosticsOverlay>>,
+) {
+    for mut visibility in overlay.iter_mut() {
+        *visibility = if show.0 {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
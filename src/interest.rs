@@ -0,0 +1,102 @@
+//! Area of interest replication: the client of a player only receives the entities around it
+//!
+//! The players are not added to [`GAME_ROOM`], their relevance is managed here instead, while the
+//! spectators still receive everything through the room
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use lightyear::prelude::server::RelevanceManager;
+use lightyear::prelude::ClientId;
+
+use crate::protocol::*;
+use crate::settings::Interest;
+
+pub struct InterestPlugin {
+    pub(crate) settings: Interest,
+}
+
+/// Entities replicated to the client of each player
+#[derive(Resource)]
+pub(crate) struct AreaOfInterest {
+    settings: Interest,
+    relevant: HashMap<ClientId, HashSet<Entity>>,
+}
+
+impl AreaOfInterest {
+    /// Clients that don't receive `entity`
+    pub(crate) fn unaware_of(&self, entity: Entity) -> impl Iterator<Item = ClientId> + '_ {
+        self.relevant
+            .iter()
+            .filter(move |(_, entities)| !entities.contains(&entity))
+            .map(|(client_id, _)| *client_id)
+    }
+
+    /// Whether `entity` is replicated to the client, spectators receive every entity
+    pub(crate) fn is_relevant(&self, client_id: ClientId, entity: Entity) -> bool {
+        self.relevant
+            .get(&client_id)
+            .map_or(true, |entities| entities.contains(&entity))
+    }
+}
+
+/// Whether an entity at `distance` from a player is replicated to its client, given whether it
+/// already was
+pub(crate) fn in_range(distance: f32, radius: f32, hysteresis: f32, relevant: bool) -> bool {
+    if relevant {
+        distance <= radius + hysteresis
+    } else {
+        distance <= radius
+    }
+}
+
+impl Plugin for InterestPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(AreaOfInterest {
+            settings: self.settings.clone(),
+            relevant: default(),
+        })
+        .add_systems(Update, update_interest);
+    }
+}
+
+fn update_interest(
+    mut interest: ResMut<AreaOfInterest>,
+    mut relevance: ResMut<RelevanceManager>,
+    players: Query<(Entity, &PlayerId, &Position)>,
+    balls: Query<(Entity, &Position), With<BallMarker>>,
+) {
+    let AreaOfInterest { settings, relevant } = &mut *interest;
+    // the clients that left are forgotten by the relevance manager too
+    relevant.retain(|client_id, _| players.iter().any(|(_, id, _)| id.0 == *client_id));
+    for (player, player_id, center) in players.iter() {
+        let client_id = player_id.0;
+        let entities = relevant.entry(client_id).or_default();
+        entities.retain(|entity| players.contains(*entity) || balls.contains(*entity));
+        let bodies = players
+            .iter()
+            .map(|(entity, _, position)| (entity, position, settings.player_radius))
+            .chain(
+                balls
+                    .iter()
+                    .map(|(entity, position)| (entity, position, settings.ball_radius)),
+            );
+        for (entity, position, radius) in bodies {
+            let was_relevant = entities.contains(&entity);
+            // a client always receives its own player
+            let is_relevant = entity == player
+                || in_range(
+                    center.distance(position.0),
+                    radius,
+                    settings.hysteresis,
+                    was_relevant,
+                );
+            if is_relevant && !was_relevant {
+                entities.insert(entity);
+                relevance.gain_relevance(client_id, entity);
+            } else if !is_relevant && was_relevant {
+                entities.remove(&entity);
+                relevance.lose_relevance(client_id, entity);
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Arenas described by a RON or JSON file, loaded by the server and sent to the clients
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{PhysicsBundle, Team};
+use crate::settings::{read_settings, SettingsError};
+use crate::shared::WallBundle;
+
+/// Levels embedded in the binary, which browser clients can use too
+const BUILTIN_LEVELS: [(&str, &str); 2] = [
+    ("arena", include_str!("../assets/levels/arena.ron")),
+    ("pillars", include_str!("../assets/levels/pillars.ron")),
+];
+
+#[derive(Resource, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Level {
+    pub name: String,
+    /// Area the camera shows, every spawn point is inside it
+    pub bounds: Rect,
+    /// Each polyline is a chain of wall segments
+    pub walls: Vec<Vec<Vec2>>,
+    pub obstacles: Vec<Obstacle>,
+    pub balls: Vec<BallPattern>,
+    /// Where players spawn, the first one that is clear of other bodies is used
+    pub spawn_points: Vec<Vec2>,
+    /// Areas the balls score in, for the soccer mode
+    #[serde(default)]
+    pub goals: Vec<Goal>,
+}
+
+/// Area that scores for the other team when a ball enters it
+#[derive(Component, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Goal {
+    /// Team that defends the goal
+    pub team: Team,
+    pub area: Rect,
+}
+
+/// Static body inside the arena
+#[derive(Component, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Obstacle {
+    Circle { center: Vec2, radius: f32 },
+    Rectangle { center: Vec2, size: Vec2 },
+}
+
+/// Where balls are placed when the server starts
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum BallPattern {
+    Grid {
+        center: Vec2,
+        columns: u32,
+        rows: u32,
+        spacing: f32,
+    },
+    Ring {
+        center: Vec2,
+        radius: f32,
+        count: u32,
+    },
+    Points(Vec<Vec2>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LevelError {
+    #[error("could not read level file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    Ron(#[from] SettingsError),
+    #[error("invalid level file {}: {source}", path.display())]
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("the level has no spawn points")]
+    NoSpawnPoints,
+    #[error("spawn point {0} is out of the level bounds")]
+    SpawnOutOfBounds(Vec2),
+    #[error("wall {0} needs at least two points")]
+    ShortWall(usize),
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        read_settings(BUILTIN_LEVELS[0].1).expect("the embedded levels are valid")
+    }
+}
+
+impl Level {
+    /// Loads a built-in level by name, or a `.ron` or `.json` level file
+    pub fn load(level: &str) -> Result<Self, LevelError> {
+        let level = match BUILTIN_LEVELS.iter().find(|(name, _)| *name == level) {
+            Some((_, content)) => read_settings(content)?,
+            None => Self::read_file(Path::new(level))?,
+        };
+        level.validate()
+    }
+
+    fn read_file(path: &Path) -> Result<Self, LevelError> {
+        let content = fs::read_to_string(path).map_err(|source| LevelError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&content).map_err(|source| LevelError::Json {
+                path: path.to_path_buf(),
+                source,
+            })
+        } else {
+            Ok(read_settings(&content)?)
+        }
+    }
+
+    fn validate(self) -> Result<Self, LevelError> {
+        if self.spawn_points.is_empty() {
+            return Err(LevelError::NoSpawnPoints);
+        }
+        if let Some(point) = self
+            .spawn_points
+            .iter()
+            .find(|point| !self.bounds.contains(**point))
+        {
+            return Err(LevelError::SpawnOutOfBounds(*point));
+        }
+        if let Some(index) = self.walls.iter().position(|wall| wall.len() < 2) {
+            return Err(LevelError::ShortWall(index));
+        }
+        Ok(self)
+    }
+
+    /// Whether each team has a goal to defend
+    pub fn has_goals(&self) -> bool {
+        Team::ALL
+            .iter()
+            .all(|team| self.goals.iter().any(|goal| goal.team == *team))
+    }
+
+    /// Spawn points closer to the goal of `team` than to the other goals, so that teams start on
+    /// their own half
+    pub fn team_spawn_points(&self, team: Team) -> Vec<Vec2> {
+        let distance = |point: Vec2, own: bool| {
+            self.goals
+                .iter()
+                .filter(|goal| (goal.team == team) == own)
+                .map(|goal| goal.area.center().distance(point))
+                .fold(f32::INFINITY, f32::min)
+        };
+        self.spawn_points
+            .iter()
+            .copied()
+            .filter(|point| distance(*point, true) < distance(*point, false))
+            .collect()
+    }
+
+    /// Initial position of every ball, pattern after pattern
+    pub fn ball_positions(&self) -> Vec<Vec2> {
+        self.balls.iter().flat_map(BallPattern::positions).collect()
+    }
+}
+
+impl BallPattern {
+    fn positions(&self) -> Vec<Vec2> {
+        match self {
+            BallPattern::Grid {
+                center,
+                columns,
+                rows,
+                spacing,
+            } => {
+                let offset = Vec2::new(*columns as f32 - 1.0, *rows as f32 - 1.0) * spacing / 2.0;
+                (0..*rows)
+                    .flat_map(|y| (0..*columns).map(move |x| Vec2::new(x as f32, y as f32)))
+                    .map(|cell| *center - offset + cell * *spacing)
+                    .collect()
+            }
+            BallPattern::Ring {
+                center,
+                radius,
+                count,
+            } => (0..*count)
+                .map(|index| {
+                    let angle = std::f32::consts::TAU * index as f32 / *count as f32;
+                    *center + Vec2::from_angle(angle) * *radius
+                })
+                .collect(),
+            BallPattern::Points(points) => points.clone(),
+        }
+    }
+}
+
+/// Walls and obstacles of the level, spawned by the server and every client
+#[derive(Component)]
+pub struct LevelGeometry;
+
+pub(crate) fn spawn_level(commands: &mut Commands, level: &Level) {
+    for wall in &level.walls {
+        for segment in wall.windows(2) {
+            commands.spawn((
+                WallBundle::new(segment[0], segment[1], Color::WHITE),
+                LevelGeometry,
+            ));
+        }
+    }
+    for obstacle in &level.obstacles {
+        let (center, collider) = match obstacle {
+            Obstacle::Circle { center, radius } => (*center, Collider::circle(*radius)),
+            Obstacle::Rectangle { center, size } => (*center, Collider::rectangle(size.x, size.y)),
+        };
+        commands.spawn((
+            PhysicsBundle {
+                collider,
+                collider_density: ColliderDensity(1.0),
+                rigid_body: RigidBody::Static,
+            },
+            Position(center),
+            obstacle.clone(),
+            LevelGeometry,
+        ));
+    }
+}
@@ -0,0 +1,618 @@
+//! Simulates network conditions on the server, per client and changeable while running
+//!
+//! The UDP clients send to a relay socket on the port of the transport, which forwards their
+//! packets to lightyear's socket from one socket per client, so that lightyear still tells them
+//! apart. Lightyear's socket is only bound on the loopback interface, so the clients can't go
+//! around the relay. The in-process clients are relayed between their channels. Both directions of every
+//! client go through a [`Link`] that delays, drops, duplicates or reorders the packets
+//!
+//! The relay runs on a thread of its own, with its own clock, so that the delivery times don't
+//! depend on the frame rate of the server
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap, Instant};
+use clap::ValueEnum;
+use crossbeam_channel::{Receiver, Sender};
+use lightyear::connection::server::NetServer;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+
+use crate::protocol::*;
+use crate::settings::{Conditioner, ConditionerPreset, ServerSettings, ServerTransports};
+use crate::shared::XorShift;
+
+/// Packets that waited this long for the bandwidth cap are dropped, like by a full router queue
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(500);
+/// Extra delay of the reordered packets, longer than the interval between two server packets
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+/// Clients that sent nothing for this long are forgotten by the UDP relay
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_PACKET_SIZE: usize = 1500;
+/// How often the relay thread forwards the packets that arrived or are due
+const RELAY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Conditions of one direction of a link
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct LinkConditions {
+    pub(crate) latency: Duration,
+    pub(crate) jitter: Duration,
+    pub(crate) loss: f32,
+    pub(crate) duplicate: f32,
+    pub(crate) reorder: f32,
+    pub(crate) bandwidth_kbps: Option<u32>,
+}
+
+impl Conditioner {
+    /// Conditions of the packets received by the app the conditioner is set on
+    pub(crate) fn incoming(&self) -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(self.latency_ms as u64),
+            jitter: Duration::from_millis(self.jitter_ms as u64),
+            loss: self.packet_loss,
+            duplicate: self.duplicate,
+            reorder: self.reorder,
+            bandwidth_kbps: self.bandwidth_kbps,
+        }
+    }
+
+    /// Conditions of the packets sent by the app the conditioner is set on
+    pub(crate) fn outgoing(&self) -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(self.outgoing_latency_ms as u64),
+            jitter: Duration::from_millis(self.outgoing_jitter_ms as u64),
+            loss: self.outgoing_loss,
+            ..self.incoming()
+        }
+    }
+}
+
+/// One direction of a simulated link, the packets are in flight until their delivery time
+pub(crate) struct Link {
+    pub(crate) conditions: LinkConditions,
+    /// Packets by delivery time, then by order of sending
+    in_flight: BTreeMap<(Duration, u64), Vec<u8>>,
+    sent: u64,
+    /// When the packets sent so far have all gone through the bandwidth cap
+    busy_until: Duration,
+    rng: XorShift,
+}
+
+impl Link {
+    pub(crate) fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            busy_until: Duration::ZERO,
+            rng: XorShift::new(seed),
+        }
+    }
+
+    /// Sends `packet` at `now`, it may be dropped, duplicated or overtaken by the next ones
+    pub(crate) fn send(&mut self, now: Duration, packet: Vec<u8>) {
+        if self.rng.next_f32() < self.conditions.loss {
+            return;
+        }
+        let mut departure = now;
+        if let Some(kbps) = self.conditions.bandwidth_kbps {
+            let start = self.busy_until.max(now);
+            if start - now > MAX_QUEUE_DELAY {
+                return;
+            }
+            let bits = packet.len() as f64 * 8.0;
+            self.busy_until = start + Duration::from_secs_f64(bits / (kbps.max(1) as f64 * 1000.0));
+            departure = self.busy_until;
+        }
+        if self.rng.next_f32() < self.conditions.duplicate {
+            let delivery = self.delivery(departure);
+            self.push(delivery, packet.clone());
+        }
+        let mut delivery = self.delivery(departure);
+        if self.rng.next_f32() < self.conditions.reorder {
+            delivery += REORDER_DELAY;
+        }
+        self.push(delivery, packet);
+    }
+
+    /// Packets delivered by `now`, in order of delivery
+    pub(crate) fn receive(&mut self, now: Duration) -> impl Iterator<Item = Vec<u8>> + '_ {
+        std::iter::from_fn(move || {
+            let packet = self.in_flight.first_entry()?;
+            (packet.key().0 <= now).then(|| packet.remove())
+        })
+    }
+
+    /// Latency of a packet that leaves at `departure`, shifted by up to the jitter either way
+    fn delivery(&mut self, departure: Duration) -> Duration {
+        let jitter =
+            self.conditions.jitter.as_secs_f64() * (self.rng.next_f32() as f64 * 2.0 - 1.0);
+        let latency = (self.conditions.latency.as_secs_f64() + jitter).max(0.0);
+        departure + Duration::from_secs_f64(latency)
+    }
+
+    fn push(&mut self, delivery: Duration, packet: Vec<u8>) {
+        self.in_flight.insert((delivery, self.sent), packet);
+        self.sent += 1;
+    }
+}
+
+/// Both directions of the link of a client, from the point of view of the server
+struct Peer {
+    /// Address lightyear sees the client at
+    addr: SocketAddr,
+    /// From the client to the server
+    incoming: Link,
+    /// From the server to the client
+    outgoing: Link,
+    last_received: Duration,
+}
+
+impl Peer {
+    fn new(addr: SocketAddr, conditioner: &Conditioner, seed: u64, now: Duration) -> Self {
+        Self {
+            addr,
+            incoming: Link::new(conditioner.incoming(), seed),
+            outgoing: Link::new(conditioner.outgoing(), seed.rotate_left(32)),
+            last_received: now,
+        }
+    }
+
+    fn set_conditioner(&mut self, conditioner: &Conditioner) {
+        self.incoming.conditions = conditioner.incoming();
+        self.outgoing.conditions = conditioner.outgoing();
+    }
+}
+
+/// Relays a UDP transport of the server
+struct UdpRelay {
+    /// Socket the clients send to, on the port of the transport
+    public: UdpSocket,
+    /// Address of lightyear's socket, known once the server is started
+    server_addr: Option<SocketAddr>,
+    /// Clients by their address, each with its own socket connected to lightyear's
+    clients: HashMap<SocketAddr, (UdpSocket, Peer)>,
+}
+
+/// Relays the channels of an in-process client
+struct ChannelRelay {
+    from_client: Receiver<Vec<u8>>,
+    to_server: Sender<Vec<u8>>,
+    from_server: Receiver<Vec<u8>>,
+    to_client: Sender<Vec<u8>>,
+    peer: Peer,
+}
+
+/// Links of the clients of the server, created by [`LinkRelay::new`] when `link_relay` is set
+///
+/// The packets are forwarded by a thread that stops once the resource is dropped
+#[derive(Resource)]
+pub struct LinkRelay(Arc<Mutex<Relay>>);
+
+impl LinkRelay {
+    /// Moves the UDP transports of `settings` and the `Channels` transports of `extra_transports`
+    /// behind the relay, which takes over `conditioner`, and starts forwarding their packets
+    ///
+    /// The relayed UDP transports are replaced by loopback sockets on free ports, appended to
+    /// `extra_transports`
+    pub(crate) fn new(
+        settings: &mut ServerSettings,
+        extra_transports: &mut Vec<ServerTransport>,
+    ) -> Self {
+        let relay = Arc::new(Mutex::new(Relay::new(settings, extra_transports)));
+        let weak = Arc::downgrade(&relay);
+        thread::spawn(move || {
+            let start = Instant::now();
+            while let Some(relay) = weak.upgrade() {
+                relay
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .relay(start.elapsed());
+                drop(relay);
+                thread::sleep(RELAY_INTERVAL);
+            }
+        });
+        LinkRelay(relay)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Relay> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+struct Relay {
+    /// Conditioner of the clients that were not given one
+    default: Conditioner,
+    /// Conditioners given to clients while running, kept for when they reconnect
+    profiles: HashMap<ClientId, Conditioner>,
+    udp: Vec<UdpRelay>,
+    channels: Vec<ChannelRelay>,
+    seeds: u64,
+}
+
+impl Relay {
+    fn new(settings: &mut ServerSettings, extra_transports: &mut Vec<ServerTransport>) -> Self {
+        let mut relay = Self {
+            default: settings.conditioner.take().unwrap_or_default(),
+            profiles: HashMap::default(),
+            udp: vec![],
+            channels: vec![],
+            seeds: 0,
+        };
+        settings.transport.retain(|transport| {
+            let ServerTransports::Udp { local_port } = transport else {
+                return true;
+            };
+            match UdpRelay::bind(*local_port) {
+                Ok(udp) => {
+                    relay.udp.push(udp);
+                    false
+                }
+                Err(e) => {
+                    error!("Not relaying UDP port {local_port}: {e}");
+                    true
+                }
+            }
+        });
+        for transport in extra_transports.iter_mut() {
+            let ServerTransport::Channels { channels } = transport else {
+                continue;
+            };
+            for (addr, from_client, to_client) in channels.iter_mut() {
+                let (to_server, server_recv) = crossbeam_channel::unbounded();
+                let (server_send, from_server) = crossbeam_channel::unbounded();
+                let peer = Peer::new(*addr, &relay.default, relay.next_seed(), Duration::ZERO);
+                relay.channels.push(ChannelRelay {
+                    from_client: std::mem::replace(from_client, server_recv),
+                    to_server,
+                    from_server,
+                    to_client: std::mem::replace(to_client, server_send),
+                    peer,
+                });
+            }
+        }
+        // lightyear picks the ports, the relay reads them once the server is started
+        extra_transports.extend(
+            relay.udp.iter().map(|_| {
+                ServerTransport::UdpSocket(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            }),
+        );
+        relay
+    }
+
+    /// Reads the addresses lightyear bound the relayed UDP transports to, they are the last
+    /// servers since their transports were the last ones added
+    fn find_server_addrs(&mut self, connections: &ServerConnections) {
+        let relayed = connections.servers.len().saturating_sub(self.udp.len());
+        for (udp, server) in self.udp.iter_mut().zip(&connections.servers[relayed..]) {
+            let server_addr = server.io().map(|io| io.local_addr());
+            if server_addr != udp.server_addr {
+                udp.clients.clear();
+                udp.server_addr = server_addr;
+            }
+        }
+    }
+
+    fn next_seed(&mut self) -> u64 {
+        self.seeds += 1;
+        self.seeds.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+
+    /// Forwards the packets received since the last call, and the ones due by `now`, measured from
+    /// the start of the relay thread
+    fn relay(&mut self, now: Duration) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        for index in 0..self.udp.len() {
+            self.receive_udp(index, now, &mut buffer);
+            let udp = &mut self.udp[index];
+            for (addr, (socket, peer)) in udp.clients.iter_mut() {
+                loop {
+                    match socket.recv(&mut buffer) {
+                        Ok(len) => peer.outgoing.send(now, buffer[..len].to_vec()),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            debug!("Could not receive the packets to {addr}: {e}");
+                            break;
+                        }
+                    }
+                }
+                for packet in peer.incoming.receive(now) {
+                    let _ = socket.send(&packet);
+                }
+                for packet in peer.outgoing.receive(now) {
+                    let _ = udp.public.send_to(&packet, addr);
+                }
+            }
+            udp.clients
+                .retain(|_, (_, peer)| now.saturating_sub(peer.last_received) < PEER_TIMEOUT);
+        }
+        for channel in self.channels.iter_mut() {
+            for packet in channel.from_client.try_iter() {
+                channel.peer.incoming.send(now, packet);
+            }
+            for packet in channel.from_server.try_iter() {
+                channel.peer.outgoing.send(now, packet);
+            }
+            for packet in channel.peer.incoming.receive(now) {
+                let _ = channel.to_server.send(packet);
+            }
+            for packet in channel.peer.outgoing.receive(now) {
+                let _ = channel.to_client.send(packet);
+            }
+        }
+    }
+
+    /// Receives the packets of the clients on the public socket of a UDP relay
+    fn receive_udp(&mut self, index: usize, now: Duration, buffer: &mut [u8]) {
+        loop {
+            let (len, from) = match self.udp[index].public.recv_from(buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // reported on some platforms when a client is gone
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    warn!("Could not receive the packets of the clients: {e}");
+                    break;
+                }
+            };
+            if !self.udp[index].clients.contains_key(&from) {
+                // the server is not started yet, the client will retry
+                let Some(server_addr) = self.udp[index].server_addr else {
+                    continue;
+                };
+                let seed = self.next_seed();
+                let udp = &mut self.udp[index];
+                match UdpRelay::connect(server_addr) {
+                    Ok(socket) => {
+                        let addr = socket.local_addr().unwrap_or(server_addr);
+                        let peer = Peer::new(addr, &self.default, seed, now);
+                        udp.clients.insert(from, (socket, peer));
+                    }
+                    Err(e) => {
+                        warn!("Could not relay the packets of {from}: {e}");
+                        continue;
+                    }
+                }
+            }
+            let (_, peer) = self.udp[index]
+                .clients
+                .get_mut(&from)
+                .expect("inserted above");
+            peer.last_received = now;
+            peer.incoming.send(now, buffer[..len].to_vec());
+        }
+    }
+
+    fn peers_mut(&mut self) -> impl Iterator<Item = &mut Peer> {
+        let udp = self
+            .udp
+            .iter_mut()
+            .flat_map(|udp| udp.clients.values_mut().map(|(_, peer)| peer));
+        udp.chain(self.channels.iter_mut().map(|channel| &mut channel.peer))
+    }
+
+    /// Applies the conditioner of the client to its link, returns false if it isn't relayed
+    fn apply_profile(&mut self, client_id: ClientId, connections: &ServerConnections) -> bool {
+        let Some(addr) = connections
+            .servers
+            .iter()
+            .find_map(|server| server.client_addr(client_id))
+        else {
+            return false;
+        };
+        let conditioner = self
+            .profiles
+            .get(&client_id)
+            .unwrap_or(&self.default)
+            .clone();
+        match self.peers_mut().find(|peer| peer.addr == addr) {
+            Some(peer) => {
+                peer.set_conditioner(&conditioner);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl UdpRelay {
+    /// Binds the public socket on `port`, lightyear then listens on a free loopback port
+    fn bind(port: u16) -> io::Result<Self> {
+        let public = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        public.set_nonblocking(true)?;
+        Ok(Self {
+            public,
+            server_addr: None,
+            clients: HashMap::default(),
+        })
+    }
+
+    fn connect(server_addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        socket.connect(server_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
+
+/// Changes the conditions simulated on the link of a client, or of every client
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub struct SetLinkProfile {
+    /// Every client, and the ones that connect later, if `None`
+    pub target: Option<ClientId>,
+    /// No simulated conditions if `None`
+    pub preset: Option<ConditionerPreset>,
+}
+
+/// Applies the profiles asked for with [`SetLinkProfile`] or by the clients to the [`LinkRelay`],
+/// if there is one
+pub struct LinkRelayPlugin;
+
+impl Plugin for LinkRelayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SetLinkProfile>().add_systems(
+            PreUpdate,
+            (
+                find_server_addrs.run_if(resource_exists::<LinkRelay>),
+                request_link_profiles,
+                set_link_profiles,
+            )
+                .chain()
+                .after(MainSet::EmitEvents),
+        );
+    }
+}
+
+/// Follows the server being started or restarted on other ports
+fn find_server_addrs(relay: Res<LinkRelay>, connections: Res<ServerConnections>) {
+    relay.lock().find_server_addrs(&connections);
+}
+
+fn request_link_profiles(
+    mut requests: EventReader<MessageEvent<RequestLinkProfile>>,
+    mut profiles: EventWriter<SetLinkProfile>,
+) {
+    for request in requests.read() {
+        profiles.send(SetLinkProfile {
+            target: Some(*request.context()),
+            preset: request.message().preset,
+        });
+    }
+}
+
+fn set_link_profiles(
+    relay: Option<Res<LinkRelay>>,
+    connections: Res<ServerConnections>,
+    mut connection: ResMut<ConnectionManager>,
+    mut profiles: EventReader<SetLinkProfile>,
+    mut connects: EventReader<ConnectEvent>,
+) {
+    let Some(relay) = relay else {
+        if profiles.read().count() > 0 {
+            warn!("Link profiles can't be changed, `link_relay` is not set");
+        }
+        return;
+    };
+    let mut relay = relay.lock();
+    // the relay only learns which link is the client's once it is connected
+    for event in connects.read() {
+        if relay.profiles.contains_key(&event.client_id) {
+            relay.apply_profile(event.client_id, &connections);
+        }
+    }
+    for profile in profiles.read() {
+        let conditioner = profile
+            .preset
+            .map(ConditionerPreset::conditioner)
+            .unwrap_or_default();
+        let targets = match profile.target {
+            Some(client_id) => {
+                relay.profiles.insert(client_id, conditioner);
+                vec![client_id]
+            }
+            None => {
+                relay.default = conditioner;
+                relay.profiles.clear();
+                connections
+                    .servers
+                    .iter()
+                    .flat_map(|server| server.connected_client_ids())
+                    .collect()
+            }
+        };
+        let name = profile
+            .preset
+            .map_or_else(|| "no conditions".to_string(), |preset| preset.to_string());
+        for client_id in targets {
+            if !relay.apply_profile(client_id, &connections) {
+                warn!("The link of client {client_id} is not relayed");
+                continue;
+            }
+            info!("Simulating {name} on the link of client {client_id}");
+            let changed = LinkProfileChanged {
+                preset: profile.preset,
+            };
+            connection
+                .send_message_to_target::<LobbyChannel, _>(
+                    &changed,
+                    NetworkTarget::Single(client_id),
+                )
+                .unwrap_or_else(|e| error!("Could not send the link profile: {e}"));
+        }
+    }
+}
+
+/// Reads `link <client id|all> <profile>` commands on the standard input of the server
+pub struct LinkConsolePlugin;
+
+#[derive(Resource)]
+struct Console(Receiver<String>);
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum LinkCommandError {
+    #[error("unknown command `{0}`, expected `link <client id|all> <profile>`")]
+    UnknownCommand(String),
+    #[error("invalid client id `{0}`")]
+    ClientId(String),
+    #[error("unknown profile `{0}`, expected off, lan, 4g, bad-wifi or intercontinental")]
+    Profile(String),
+}
+
+/// Parses a console command, blank lines are ignored
+pub fn parse_link_command(line: &str) -> Result<Option<SetLinkProfile>, LinkCommandError> {
+    let words: Vec<_> = line.split_whitespace().collect();
+    let (target, profile) = match words[..] {
+        [] => return Ok(None),
+        ["link", target, profile] => (target, profile),
+        _ => return Err(LinkCommandError::UnknownCommand(line.trim().to_string())),
+    };
+    let target = match target {
+        "all" => None,
+        id => Some(ClientId::Netcode(
+            id.parse()
+                .map_err(|_| LinkCommandError::ClientId(id.to_string()))?,
+        )),
+    };
+    let preset = match profile {
+        "off" => None,
+        name => Some(
+            ConditionerPreset::from_str(name, true)
+                .map_err(|_| LinkCommandError::Profile(name.to_string()))?,
+        ),
+    };
+    Ok(Some(SetLinkProfile { target, preset }))
+}
+
+impl Plugin for LinkConsolePlugin {
+    fn build(&self, app: &mut App) {
+        let (send, recv) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        app.insert_resource(Console(recv))
+            .add_systems(Update, read_console);
+    }
+}
+
+fn read_console(console: Res<Console>, mut profiles: EventWriter<SetLinkProfile>) {
+    for line in console.0.try_iter() {
+        match parse_link_command(&line) {
+            Ok(Some(profile)) => {
+                profiles.send(profile);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("{e}"),
+        }
+    }
+}
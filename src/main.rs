@@ -1,50 +1,266 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(dead_code)]
+use crate::bindings::{Bindings, RebindingPlugin};
+use crate::bot::Behaviour;
 use crate::client::ClientPlugin;
+use crate::level::Level;
+use crate::link::LinkConsolePlugin;
+use crate::recording::RecordingPlugin;
+use crate::reload::SettingsReloadPlugin;
 use crate::server::ServerPlugin;
 use crate::shared::SharedPlugin;
-use app::{Apps, Cli};
+use app::{Apps, Args, Cli};
+use std::path::Path;
+
+use bevy::log::LogPlugin;
 use bevy::prelude::*;
+use bevy::utils::Duration;
 use lightyear::prelude::client::PredictionConfig;
 use serde::{Deserialize, Serialize};
-use settings::{read_settings, Settings};
+use settings::{load_settings, Conditioner, GameMode, MetricsExport, ReplicationRates, Settings};
 
+mod auth;
+mod bindings;
+mod bot;
 mod client;
+mod determinism;
+mod diagnostics;
+mod interest;
+mod level;
+mod link;
 mod protocol;
+mod recording;
+mod reload;
+mod replay;
 mod server;
 mod shared;
+mod snapshot;
+mod soccer;
+mod spectator;
+mod swarm;
+mod team;
 mod app;
 mod settings;
+#[cfg(test)]
+mod tests;
 
 fn main() {
-    let cli = Cli::default();
-    let settings_str = include_str!("../assets/settings.ron");
-    let settings = read_settings::<MySettings>(settings_str);
-    let mut apps = Apps::new(settings.common, cli);
+    #[cfg(not(target_family = "wasm"))]
+    let args = Args::default();
+    // browsers have no command line, they run a client with the embedded settings
+    #[cfg(target_family = "wasm")]
+    let args = Args {
+        settings: None,
+        bindings: None,
+        device: None,
+        overrides: default(),
+        cli: Cli::Client {
+            client_id: None,
+            server_ip: None,
+        },
+    };
+    let Args {
+        settings: settings_path,
+        bindings: bindings_path,
+        device,
+        mut overrides,
+        cli,
+    } = args;
+    let default_settings = include_str!("../assets/settings.ron");
+    let mut settings = load_settings::<MySettings>(settings_path.as_deref(), default_settings)
+        .unwrap_or_else(|err| {
+            eprintln!("Could not load the settings: {err}");
+            std::process::exit(1);
+        });
+    overrides.spectator = matches!(cli, Cli::Spectate { .. });
+    overrides.apply(&mut settings.common);
+    // browsers can't open the TCP connection to the token service
+    #[cfg(target_family = "wasm")]
+    let without_token = settings.common.client.token_port.take().is_some();
+    let record = cli.record_path().map(Path::to_path_buf);
+    let plays = matches!(cli, Cli::Client { .. } | Cli::HostServer { .. });
+    let runs_server = matches!(
+        cli,
+        Cli::Server { .. } | Cli::HostServer { .. } | Cli::LocalSwarm { .. }
+    );
+    if runs_server {
+        match Level::load(&settings.common.server.level) {
+            Err(err) => {
+                eprintln!("Could not load the level: {err}");
+                std::process::exit(1);
+            }
+            Ok(level)
+                if matches!(settings.common.server.mode, GameMode::Soccer { .. })
+                    && !level.has_goals() =>
+            {
+                eprintln!("The soccer mode needs a level with a goal for each team");
+                std::process::exit(1);
+            }
+            Ok(_) => {}
+        }
+        if settings.common.server.ball_snapshots.is_some() && !settings.predict_all {
+            eprintln!("The ball snapshots only update predicted balls, set predict_all");
+            std::process::exit(1);
+        }
+        if !settings.replication.player_rates_match(settings.predict_all) {
+            eprintln!(
+                "Each client predicts its own player, set predict_all or the same \
+                 predicted_players_ms and interpolated_players_ms"
+            );
+            std::process::exit(1);
+        }
+        if !settings.replication.ball_rates_match(settings.predict_all) {
+            eprintln!(
+                "With predict_all the predicted balls are sent with the players, set the same \
+                 predicted_balls_ms and predicted_players_ms or a bandwidth_cap_kbps"
+            );
+            std::process::exit(1);
+        }
+    }
+    // the swarm and the bots add the game plugins to each of their apps
+    let built_with_plugins = matches!(cli, Cli::LocalSwarm { .. } | Cli::Bot { .. });
+    let mut apps = match cli {
+        Cli::LocalSwarm { clients } => {
+            // the window shows the server, unless --headless is given, and the reloaded settings
+            // get the same override
+            overrides.headless = Some(overrides.headless.unwrap_or(false));
+            overrides.apply(&mut settings.common);
+            let mut apps = swarm::local_swarm(&settings, clients);
+            apps.add_plugins(LogPlugin::default());
+            apps
+        }
+        Cli::Bot {
+            count,
+            behaviour,
+            input_file,
+            server_ip,
+        } => {
+            let behaviour =
+                Behaviour::load(behaviour, input_file.as_deref()).unwrap_or_else(|err| {
+                    eprintln!("Could not start the bots: {err}");
+                    std::process::exit(1);
+                });
+            let server_ip = server_ip.unwrap_or(settings.common.client.server_addr).into();
+            let mut apps = bot::bots(&settings, count, behaviour, server_ip);
+            apps.add_plugins(LogPlugin::default());
+            apps
+        }
+        Cli::HostServer { client_id, .. } => Apps::host_server(settings.common.clone(), client_id),
+        Cli::Server { .. } => Apps::server(settings.common.clone()),
+        Cli::Client {
+            client_id,
+            server_ip,
+        }
+        | Cli::Spectate {
+            client_id,
+            server_ip,
+        } => Apps::client(settings.common.clone(), client_id, server_ip),
+        Cli::Replay { file } => Apps::replay(&file),
+    };
+    if !built_with_plugins {
+        add_plugins(&mut apps, &settings);
+    }
+    #[cfg(target_family = "wasm")]
+    if without_token {
+        warn!("Ignoring the token service, the server needs to run without one for browser clients");
+    }
+    if plays {
+        // a missing file is created when the bindings are changed
+        let path = bindings_path.as_deref().filter(|path| path.exists());
+        let mut bindings = load_settings::<Bindings>(path, include_str!("../assets/bindings.ron"))
+            .unwrap_or_else(|err| {
+                eprintln!("Could not load the bindings: {err}");
+                std::process::exit(1);
+            });
+        if let Some(device) = device {
+            bindings.device = device;
+        }
+        apps.add_plugins(RebindingPlugin {
+            bindings,
+            path: bindings_path,
+        });
+    }
+    if runs_server && settings.common.server.link_relay {
+        apps.add_plugins(LinkConsolePlugin);
+    }
+    if let Some(path) = record {
+        apps.add_plugins(RecordingPlugin {
+            path,
+            deterministic: settings.deterministic,
+        });
+    }
+    if let Some(path) = settings_path {
+        apps.add_plugins(SettingsReloadPlugin {
+            path,
+            overrides,
+            settings,
+        });
+    }
+    // run the app
+    apps.run();
+}
+
+/// Adds the lightyear and game plugins, configured from the settings
+pub(crate) fn add_plugins(apps: &mut Apps, settings: &MySettings) {
     apps.update_lightyear_client_config(|config| {
         config.prediction.minimum_input_delay_ticks = settings.input_delay_ticks;
         config.prediction.correction_ticks_factor = settings.correction_ticks_factor;
     })
-    .add_lightyear_plugins()
-    .add_user_plugins(
-        ClientPlugin,
+    .with_server_replication_send_interval(settings.replication.send_interval())
+    .with_server_bandwidth_cap(settings.replication.bandwidth_cap())
+    .add_lightyear_plugins();
+    // checked by main for the apps that run a server, clients get the level from the server
+    let level = Level::load(&settings.common.server.level).unwrap_or_default();
+    apps.add_user_plugins(
+        ClientPlugin {
+            spectator: settings.common.client.spectator,
+            show_diagnostics: settings.show_diagnostics,
+            metrics_export: settings.metrics_export.clone(),
+        },
         ServerPlugin {
             predict_all: settings.predict_all,
+            max_clients: settings.common.server.max_clients,
+            level,
+            mode: settings.common.server.mode.clone(),
+            teams: settings.common.server.teams,
+            friendly_collisions: settings.common.server.friendly_collisions,
+            interest: settings.common.server.interest.clone(),
+            ball_snapshots: settings.common.server.ball_snapshots.clone(),
+            replication: settings.replication.clone(),
+            reconnect_grace_period: Duration::from_millis(settings.common.server.reconnect_grace_ms),
         },
         SharedPlugin {
             show_confirmed: settings.show_confirmed,
+            deterministic: settings.deterministic,
         },
     );
-    // run the app
-    apps.run();
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Resource, Clone, Debug, Deserialize, Serialize)]
 pub struct MySettings {
+    #[serde(default = "settings::embedded_common")]
     pub common: Settings,
+    #[serde(default = "settings::embedded_predict_all")]
     pub(crate) predict_all: bool,
+    #[serde(default = "settings::embedded_input_delay_ticks")]
     pub(crate) input_delay_ticks: u16,
+    #[serde(default = "settings::embedded_correction_ticks_factor")]
     pub(crate) correction_ticks_factor: f32,
+    #[serde(default = "settings::embedded_show_confirmed")]
     pub(crate) show_confirmed: bool,
+    /// Step the physics identically on the client and the server, and compare world checksums
+    #[serde(default)]
+    pub(crate) deterministic: bool,
+    /// Show the rollback and network metrics overlay on the client
+    #[serde(default = "settings::default_true")]
+    pub(crate) show_diagnostics: bool,
+    /// Write the client metrics to a file
+    #[serde(default)]
+    pub(crate) metrics_export: Option<MetricsExport>,
+    /// Link conditioners of the `local-swarm` clients, given in turn
+    #[serde(default)]
+    pub(crate) swarm_conditioners: Vec<Conditioner>,
+    #[serde(default)]
+    pub(crate) replication: ReplicationRates,
 }
\ No newline at end of file
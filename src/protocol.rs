@@ -1,7 +1,8 @@
+use std::fmt;
 
 use avian2d::prelude::*;
+use bevy::color::palettes::css;
 use bevy::prelude::*;
-use leafwing_input_manager::InputManagerBundle;
 use lightyear::{
     prelude::*,
     client::{
@@ -12,6 +13,8 @@ use lightyear::{
 };
 use leafwing_input_manager::prelude::*;
 
+use crate::level::Level;
+use crate::settings::ConditionerPreset;
 use crate::shared::color_from_id;
 
 pub const BALL_SIZE: f32 = 15.0;
@@ -23,29 +26,37 @@ pub(crate) struct PlayerBundle {
     id: PlayerId,
     position: Position,
     color: ColorComponent,
-    replicate: client::Replicate,
+    replicate: server::Replicate,
     physics: PhysicsBundle,
-    inputs: InputManagerBundle<PlayerActions>,
-    pre_predicted: PrePredicted,
+    action_state: ActionState<PlayerActions>,
+    cooldowns: Cooldowns,
 }
 
 impl PlayerBundle {
-    pub(crate) fn new(id: ClientId, position: Vec2, input_map: InputMap<PlayerActions>) -> Self {
-        let color = color_from_id(id);
+    pub(crate) fn new(
+        id: ClientId,
+        position: Vec2,
+        sync: server::SyncTarget,
+        group: ReplicationGroup,
+    ) -> Self {
         Self {
             id: PlayerId(id),
             position: Position(position),
-            color: ColorComponent(color),
-            replicate: client::Replicate {
-                group: REPLICATION_GROUP,
+            color: ColorComponent(color_from_id(id)),
+            replicate: server::Replicate {
+                sync,
+                // despawned by the server, which keeps its state for a while in case it reconnects
+                controlled_by: server::ControlledBy {
+                    target: NetworkTarget::Single(id),
+                    lifetime: server::Lifetime::Persistent,
+                },
+                relevance_mode: NetworkRelevanceMode::InterestManagement,
+                group,
                 ..default()
             },
             physics: PhysicsBundle::player(),
-            inputs: InputManagerBundle::<PlayerActions> {
-                action_state: ActionState::default(),
-                input_map,
-            },
-            pre_predicted: PrePredicted::default(),
+            action_state: ActionState::default(),
+            cooldowns: Cooldowns::default(),
         }
     }
 }
@@ -60,17 +71,23 @@ pub(crate) struct BallBundle {
 }
 
 impl BallBundle {
-    pub(crate) fn new(position: Vec2, color: Color, predicted: bool) -> Self {
+    /// Each ball should be in its own replication `group`, so that its priority can follow its
+    /// distance to the players
+    pub(crate) fn new(
+        position: Vec2,
+        color: Color,
+        predicted: bool,
+        group: ReplicationGroup,
+    ) -> Self {
         let mut sync_target = server::SyncTarget::default();
-        let mut group = ReplicationGroup::default();
         if predicted {
             sync_target.prediction = NetworkTarget::All;
-            group = REPLICATION_GROUP;
         } else {
             sync_target.interpolation = NetworkTarget::All;
         }
         let replicate = server::Replicate {
             sync: sync_target,
+            relevance_mode: NetworkRelevanceMode::InterestManagement,
             group,
             ..default()
         };
@@ -111,6 +128,10 @@ impl PhysicsBundle {
 
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Reflect)]
 pub struct PlayerId(pub ClientId);
+
+/// Index of a ball in the initial layout, identifies it in the [`BallSnapshot`]s
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Reflect)]
+pub struct BallId(pub u32);
  
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ColorComponent(pub(crate) Color);
@@ -118,9 +139,180 @@ pub struct ColorComponent(pub(crate) Color);
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BallMarker;
 
+/// Team of a player, in the soccer mode or with `teams` set
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+impl Team {
+    pub const ALL: [Team; 2] = [Team::Red, Team::Blue];
+
+    pub fn other(self) -> Self {
+        match self {
+            Team::Red => Team::Blue,
+            Team::Blue => Team::Red,
+        }
+    }
+
+    pub(crate) fn color(self) -> Color {
+        match self {
+            Team::Red => css::TOMATO.into(),
+            Team::Blue => css::DODGER_BLUE.into(),
+        }
+    }
+}
+
+impl fmt::Display for Team {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Collision layers of the players, one per team, every other body is on the default layer
+#[derive(PhysicsLayer, Clone, Copy, Debug, Default)]
+pub enum GameLayer {
+    #[default]
+    Default,
+    Red,
+    Blue,
+}
+
+/// Score and clock of the soccer match, replicated from the server
+#[derive(Resource, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MatchState {
+    pub red: u32,
+    pub blue: u32,
+    /// Whole seconds left in the match, rounded up
+    pub remaining_secs: u32,
+    pub over: bool,
+}
+
+impl MatchState {
+    pub fn score(&self, team: Team) -> u32 {
+        match team {
+            Team::Red => self.red,
+            Team::Blue => self.blue,
+        }
+    }
+
+    pub fn score_mut(&mut self, team: Team) -> &mut u32 {
+        match team {
+            Team::Red => &mut self.red,
+            Team::Blue => &mut self.blue,
+        }
+    }
+}
+
+/// Ticks left before each action of a player can be used again, predicted so that rollbacks
+/// restore it with the rest of the player
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cooldowns {
+    pub dash: u16,
+    /// The brake is applied during the first ticks of its cooldown
+    pub brake: u16,
+    pub kick: u16,
+}
+
+/// Checksum of the physics state of every replicated body at the end of a server tick
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct WorldChecksum {
+    pub tick: Tick,
+    /// Number of bodies included in the checksum
+    pub bodies: u32,
+    pub checksum: u64,
+}
+
+/// Sent by a client when its predicted world stops matching the server checksum
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct DesyncReport {
+    pub tick: Tick,
+}
+
+#[derive(Channel)]
+pub struct ChecksumChannel;
+
+/// Quantized and delta-encoded states of some of the balls at `tick`, see [`crate::snapshot`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BallSnapshot {
+    pub sequence: u32,
+    pub tick: Tick,
+    pub data: Vec<u8>,
+}
+
+/// Sent back by a client for every [`BallSnapshot`] it receives
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SnapshotAck {
+    pub sequence: u32,
+}
+
+#[derive(Channel)]
+pub struct SnapshotChannel;
+
+/// Sent by a client once connected, the server spawns a player for it unless it is a spectator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct JoinGame {
+    pub spectator: bool,
+}
+
+/// Sent to a client when it connects, so that it plays on the same level as the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ActiveLevel {
+    pub level: Level,
+}
+
+/// Sent to every client when a ball enters a goal, before the kickoff
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct GoalScored {
+    /// Team that scored
+    pub team: Team,
+    pub red: u32,
+    pub blue: u32,
+}
+
+/// Sent to the other clients when a player disconnects
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlayerLeft {
+    pub client_id: ClientId,
+}
+
+/// Sent by a client to have the server simulate other network conditions on its link
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RequestLinkProfile {
+    /// No simulated conditions if `None`
+    pub preset: Option<ConditionerPreset>,
+}
+
+/// Sent by a client to move its player to another team
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RequestTeam {
+    pub team: Team,
+}
+
+/// Sent to a client when the server changed the conditions simulated on its link
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LinkProfileChanged {
+    pub preset: Option<ConditionerPreset>,
+}
+
+#[derive(Channel)]
+pub struct LobbyChannel;
+
+/// Entities are only replicated to the clients that joined the game
+pub const GAME_ROOM: server::RoomId = server::RoomId(0);
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Hash, Reflect, Actionlike)]
 pub enum PlayerActions {
-    Move
+    Move,
+    /// Burst of speed where the player faces
+    Dash,
+    /// Strong damping of the player's velocity
+    Brake,
+    /// Pushes the balls within range away
+    Kick,
+    /// Direction the player turns toward, from the player to the cursor or from the right stick
+    Aim,
 }
 
 pub struct ProtocolPlugin;
@@ -129,6 +321,34 @@ impl Plugin for ProtocolPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(LeafwingInputPlugin::<PlayerActions>::default());
 
+        app.add_channel::<ChecksumChannel>(ChannelSettings {
+            mode: ChannelMode::UnorderedUnreliable,
+            ..default()
+        });
+
+        app.add_channel::<SnapshotChannel>(ChannelSettings {
+            mode: ChannelMode::UnorderedUnreliable,
+            ..default()
+        });
+
+        app.add_channel::<LobbyChannel>(ChannelSettings {
+            mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+            ..default()
+        });
+
+        app.register_message::<JoinGame>(ChannelDirection::ClientToServer);
+        app.register_message::<ActiveLevel>(ChannelDirection::ServerToClient);
+        app.register_message::<PlayerLeft>(ChannelDirection::ServerToClient);
+        app.register_message::<GoalScored>(ChannelDirection::ServerToClient);
+        app.register_message::<RequestTeam>(ChannelDirection::ClientToServer);
+        app.register_resource::<MatchState>(ChannelDirection::ServerToClient);
+        app.register_message::<WorldChecksum>(ChannelDirection::ServerToClient);
+        app.register_message::<DesyncReport>(ChannelDirection::ClientToServer);
+        app.register_message::<RequestLinkProfile>(ChannelDirection::ClientToServer);
+        app.register_message::<LinkProfileChanged>(ChannelDirection::ServerToClient);
+        app.register_message::<BallSnapshot>(ChannelDirection::ServerToClient);
+        app.register_message::<SnapshotAck>(ChannelDirection::ClientToServer);
+
         app.register_component::<PlayerId>(ChannelDirection::Bidirectional)
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
@@ -141,6 +361,22 @@ impl Plugin for ProtocolPlugin {
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
 
+        app.register_component::<BallId>(ChannelDirection::Bidirectional)
+            .add_prediction(ComponentSyncMode::Once)
+            .add_interpolation(ComponentSyncMode::Once);
+
+        // players can change team
+        app.register_component::<Team>(ChannelDirection::Bidirectional)
+            .add_prediction(ComponentSyncMode::Simple)
+            .add_interpolation(ComponentSyncMode::Simple);
+
+        app.register_component::<CollisionLayers>(ChannelDirection::Bidirectional)
+            .add_prediction(ComponentSyncMode::Simple)
+            .add_interpolation(ComponentSyncMode::Simple);
+
+        app.register_component::<Cooldowns>(ChannelDirection::Bidirectional)
+            .add_prediction(ComponentSyncMode::Full);
+
         app.register_component::<Position>(ChannelDirection::Bidirectional)
             .add_prediction(ComponentSyncMode::Full)
             .add_interpolation(ComponentSyncMode::Full)
@@ -0,0 +1,473 @@
+//! Records a session on the server, to be played back with [`crate::replay::ReplayPlugin`]
+//!
+//! The recording is written as one RON value per line, so that it can be read even if the server
+//! did not stop cleanly
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use avian2d::prelude::*;
+use bevy::asset::ron;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use leafwing_input_manager::timing::Timing;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::level::Level;
+use crate::protocol::*;
+use crate::server::movement;
+use crate::shared::FixedSet;
+use crate::soccer::Kickoff;
+
+/// Number of ticks between two snapshots of the authoritative state
+pub(crate) const SNAPSHOT_INTERVAL: u32 = 64;
+
+/// Identifies a body across the server and the replay, where entities are different
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BodyId {
+    /// Index of the ball in the initial layout
+    Ball(u32),
+    Player(ClientId),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BodyState {
+    pub position: Position,
+    pub rotation: Rotation,
+    pub linear_velocity: LinearVelocity,
+    pub angular_velocity: AngularVelocity,
+    /// Only players have cooldowns, and recordings made before they were recorded have none
+    #[serde(default)]
+    pub cooldowns: Option<Cooldowns>,
+    /// Team of a player with its collision layers, the other bodies are on the default layer
+    #[serde(default)]
+    pub team: Option<(Team, CollisionLayers)>,
+}
+
+impl BodyState {
+    pub(crate) fn new(
+        position: &Position,
+        rotation: Option<&Rotation>,
+        linear_velocity: Option<&LinearVelocity>,
+        angular_velocity: Option<&AngularVelocity>,
+        cooldowns: Option<&Cooldowns>,
+        team: Option<(&Team, &CollisionLayers)>,
+    ) -> Self {
+        Self {
+            position: *position,
+            rotation: rotation.copied().unwrap_or_default(),
+            linear_velocity: linear_velocity.copied().unwrap_or_default(),
+            angular_velocity: angular_velocity.copied().unwrap_or_default(),
+            cooldowns: cooldowns.copied(),
+            team: team.map(|(team, layers)| (*team, *layers)),
+        }
+    }
+
+    /// Whether the simulated state is the recorded one, the cooldowns are only compared when they
+    /// were recorded
+    pub(crate) fn matches(&self, recorded: &BodyState) -> bool {
+        let cooldowns = recorded.cooldowns.and(self.cooldowns);
+        BodyState {
+            cooldowns,
+            ..self.clone()
+        } == *recorded
+    }
+
+    /// Largest difference between the positions or the velocities of the two states
+    pub(crate) fn error(&self, other: &BodyState) -> f32 {
+        [
+            self.position.distance(other.position.0),
+            self.linear_velocity.distance(other.linear_velocity.0),
+            (self.rotation.as_radians() - other.rotation.as_radians()).abs(),
+            (self.angular_velocity.0 - other.angular_velocity.0).abs(),
+        ]
+        .into_iter()
+        .fold(0.0, f32::max)
+    }
+}
+
+/// A line of the recording file, ticks count the fixed updates since the recording started
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RecordEntry {
+    Header {
+        deterministic: bool,
+        /// Initial position of every ball, indexed by [`BodyId::Ball`]
+        balls: Vec<Vec2>,
+        /// Recordings made before levels were configurable were on the default one
+        #[serde(default)]
+        level: Level,
+    },
+    Spawn {
+        tick: u32,
+        client_id: ClientId,
+        state: BodyState,
+    },
+    Despawn {
+        tick: u32,
+        client_id: ClientId,
+    },
+    /// The player of `client_id` changed team, with the collision layers of the new one
+    TeamChange {
+        tick: u32,
+        client_id: ClientId,
+        team: Team,
+        layers: CollisionLayers,
+    },
+    /// Inputs applied to the player of `client_id` from `tick` until the next `Input` entry
+    Input {
+        tick: u32,
+        client_id: ClientId,
+        action: ActionState<PlayerActions>,
+    },
+    /// Authoritative state at the end of `tick`
+    Snapshot {
+        tick: u32,
+        bodies: Vec<(BodyId, BodyState)>,
+    },
+    /// State at the end of a soccer kickoff `tick`, which teleports the bodies outside of the
+    /// simulation
+    Kickoff {
+        tick: u32,
+        bodies: Vec<(BodyId, BodyState)>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordingError {
+    #[error("could not read recording {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid recording entry at line {line}: {source}")]
+    Parse {
+        line: usize,
+        source: ron::error::SpannedError,
+    },
+    #[error("the recording does not start with a header")]
+    MissingHeader,
+}
+
+/// A recorded session, indexed by tick
+#[derive(Resource, Clone, Debug, Default)]
+pub struct Recording {
+    pub deterministic: bool,
+    pub balls: Vec<Vec2>,
+    pub level: Level,
+    pub spawns: BTreeMap<u32, Vec<(ClientId, BodyState)>>,
+    pub despawns: BTreeMap<u32, Vec<ClientId>>,
+    pub team_changes: BTreeMap<u32, Vec<(ClientId, Team, CollisionLayers)>>,
+    pub inputs: HashMap<ClientId, BTreeMap<u32, ActionState<PlayerActions>>>,
+    pub snapshots: BTreeMap<u32, Vec<(BodyId, BodyState)>>,
+    pub kickoffs: BTreeMap<u32, Vec<(BodyId, BodyState)>>,
+    /// Number of recorded ticks
+    pub ticks: u32,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self, RecordingError> {
+        let content = fs::read_to_string(path).map_err(|source| RecordingError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut lines = content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+        let parse = |(index, line): (usize, &str)| {
+            ron::from_str::<RecordEntry>(line).map_err(|source| RecordingError::Parse {
+                line: index + 1,
+                source,
+            })
+        };
+        let Some(RecordEntry::Header {
+            deterministic,
+            balls,
+            level,
+        }) = lines.next().map(parse).transpose()?
+        else {
+            return Err(RecordingError::MissingHeader);
+        };
+        let mut recording = Recording {
+            deterministic,
+            balls,
+            level,
+            ..default()
+        };
+        for line in lines {
+            match parse(line)? {
+                RecordEntry::Header { .. } => {}
+                RecordEntry::Spawn {
+                    tick,
+                    client_id,
+                    state,
+                } => {
+                    recording.spawns.entry(tick).or_default().push((client_id, state));
+                    recording.ticks = recording.ticks.max(tick + 1);
+                }
+                RecordEntry::Despawn { tick, client_id } => {
+                    recording.despawns.entry(tick).or_default().push(client_id);
+                    recording.ticks = recording.ticks.max(tick + 1);
+                }
+                RecordEntry::TeamChange {
+                    tick,
+                    client_id,
+                    team,
+                    layers,
+                } => {
+                    recording
+                        .team_changes
+                        .entry(tick)
+                        .or_default()
+                        .push((client_id, team, layers));
+                    recording.ticks = recording.ticks.max(tick + 1);
+                }
+                RecordEntry::Input {
+                    tick,
+                    client_id,
+                    action,
+                } => {
+                    recording.inputs.entry(client_id).or_default().insert(tick, action);
+                    recording.ticks = recording.ticks.max(tick + 1);
+                }
+                RecordEntry::Snapshot { tick, bodies } => {
+                    recording.snapshots.insert(tick, bodies);
+                    recording.ticks = recording.ticks.max(tick + 1);
+                }
+                RecordEntry::Kickoff { tick, bodies } => {
+                    recording.kickoffs.insert(tick, bodies);
+                    recording.ticks = recording.ticks.max(tick + 1);
+                }
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Inputs of the player of `client_id` at `tick`
+    pub(crate) fn input(&self, client_id: ClientId, tick: u32) -> Option<&ActionState<PlayerActions>> {
+        self.inputs
+            .get(&client_id)?
+            .range(..=tick)
+            .next_back()
+            .map(|(_, action)| action)
+    }
+}
+
+/// Writes the initial ball layout, the inputs and spawns of every player, periodic snapshots of the
+/// server and its state after each kickoff to a file
+pub struct RecordingPlugin {
+    pub(crate) path: PathBuf,
+    pub(crate) deterministic: bool,
+}
+
+impl Plugin for RecordingPlugin {
+    fn build(&self, app: &mut App) {
+        let file = match File::create(&self.path) {
+            Ok(file) => BufWriter::new(file),
+            Err(e) => {
+                error!("Could not create the recording {}: {e}", self.path.display());
+                return;
+            }
+        };
+        info!("Recording the session to {}", self.path.display());
+        app.insert_resource(Recorder {
+            file,
+            deterministic: self.deterministic,
+            tick: 0,
+            players: default(),
+            inputs: default(),
+        })
+        // read even without the soccer mode
+        .add_event::<Kickoff>()
+        .add_systems(PostStartup, record_layout)
+        .add_systems(
+            FixedUpdate,
+            (record_spawns, record_inputs)
+                .chain()
+                .in_set(FixedSet::Main)
+                .before(movement),
+        )
+        .add_systems(FixedPostUpdate, record_snapshot);
+    }
+}
+
+#[derive(Resource)]
+struct Recorder {
+    file: BufWriter<File>,
+    deterministic: bool,
+    /// Number of fixed updates since the recording started
+    tick: u32,
+    players: HashMap<Entity, ClientId>,
+    /// Last recorded inputs of every player
+    inputs: HashMap<ClientId, ActionState<PlayerActions>>,
+}
+
+impl Recorder {
+    fn write(&mut self, entry: &RecordEntry) {
+        let result = ron::to_string(entry)
+            .map_err(io::Error::other)
+            .and_then(|line| writeln!(self.file, "{line}"));
+        if let Err(e) = result {
+            error!("Could not write to the recording: {e}");
+        }
+    }
+}
+
+fn record_layout(
+    mut commands: Commands,
+    mut recorder: ResMut<Recorder>,
+    level: Res<Level>,
+    balls: Query<(Entity, &Position), With<BallMarker>>,
+) {
+    let balls = balls
+        .iter()
+        .enumerate()
+        .map(|(index, (entity, position))| {
+            commands.entity(entity).insert(BodyId::Ball(index as u32));
+            position.0
+        })
+        .collect();
+    let deterministic = recorder.deterministic;
+    recorder.write(&RecordEntry::Header {
+        deterministic,
+        balls,
+        level: level.clone(),
+    });
+}
+
+#[allow(clippy::type_complexity)]
+fn record_spawns(
+    mut commands: Commands,
+    mut recorder: ResMut<Recorder>,
+    players: Query<
+        (
+            Entity,
+            &PlayerId,
+            &Position,
+            Option<&Rotation>,
+            Option<&LinearVelocity>,
+            Option<&AngularVelocity>,
+            Option<&Cooldowns>,
+            Option<(&Team, &CollisionLayers)>,
+        ),
+        Without<BodyId>,
+    >,
+    team_changes: Query<(&PlayerId, &Team, &CollisionLayers), (With<BodyId>, Changed<Team>)>,
+    mut despawned: RemovedComponents<BodyId>,
+) {
+    let tick = recorder.tick;
+    for entity in despawned.read() {
+        if let Some(client_id) = recorder.players.remove(&entity) {
+            recorder.inputs.remove(&client_id);
+            recorder.write(&RecordEntry::Despawn { tick, client_id });
+        }
+    }
+    for (player_id, team, layers) in &team_changes {
+        recorder.write(&RecordEntry::TeamChange {
+            tick,
+            client_id: player_id.0,
+            team: *team,
+            layers: *layers,
+        });
+    }
+    for (
+        entity,
+        player_id,
+        position,
+        rotation,
+        linear_velocity,
+        angular_velocity,
+        cooldowns,
+        team,
+    ) in &players
+    {
+        let client_id = player_id.0;
+        commands.entity(entity).insert(BodyId::Player(client_id));
+        recorder.players.insert(entity, client_id);
+        recorder.write(&RecordEntry::Spawn {
+            tick,
+            client_id,
+            state: BodyState::new(
+                position,
+                rotation,
+                linear_velocity,
+                angular_velocity,
+                cooldowns,
+                team,
+            ),
+        });
+    }
+}
+
+fn record_inputs(
+    mut recorder: ResMut<Recorder>,
+    players: Query<(&PlayerId, &ActionState<PlayerActions>)>,
+) {
+    let tick = recorder.tick;
+    for (player_id, action) in &players {
+        // the timings change every tick while an action is held, but are not used by the game
+        let mut action = action.clone();
+        for key in action.keys() {
+            if let Some(data) = action.action_data_mut(&key) {
+                data.timing = Timing::default();
+            }
+        }
+        if recorder.inputs.get(&player_id.0) == Some(&action) {
+            continue;
+        }
+        recorder.inputs.insert(player_id.0, action.clone());
+        recorder.write(&RecordEntry::Input {
+            tick,
+            client_id: player_id.0,
+            action,
+        });
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn record_snapshot(
+    mut recorder: ResMut<Recorder>,
+    mut kickoffs: EventReader<Kickoff>,
+    bodies: Query<(
+        &BodyId,
+        &Position,
+        Option<&Rotation>,
+        Option<&LinearVelocity>,
+        Option<&AngularVelocity>,
+        Option<&Cooldowns>,
+        Option<(&Team, &CollisionLayers)>,
+    )>,
+) {
+    let tick = recorder.tick;
+    let kickoff = kickoffs.read().count() > 0;
+    if tick % SNAPSHOT_INTERVAL == 0 || kickoff {
+        let bodies: Vec<_> = bodies
+            .iter()
+            .map(
+                |(id, position, rotation, linear_velocity, angular_velocity, cooldowns, team)| {
+                    (
+                        *id,
+                        BodyState::new(
+                            position,
+                            rotation,
+                            linear_velocity,
+                            angular_velocity,
+                            cooldowns,
+                            team,
+                        ),
+                    )
+                },
+            )
+            .collect();
+        if kickoff {
+            recorder.write(&RecordEntry::Kickoff {
+                tick,
+                bodies: bodies.clone(),
+            });
+        }
+        if tick % SNAPSHOT_INTERVAL == 0 {
+            recorder.write(&RecordEntry::Snapshot { tick, bodies });
+        }
+    }
+    recorder.tick += 1;
+    recorder
+        .file
+        .flush()
+        .unwrap_or_else(|e| error!("Could not write to the recording: {e}"));
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::client::*;
+
+use crate::app::SettingsOverrides;
+use crate::auth::Connect;
+use crate::diagnostics::ShowDiagnostics;
+use crate::settings::{read_settings_file, Conditioner};
+use crate::shared::ShowConfirmed;
+use crate::MySettings;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches the settings file and applies changes while the app is running
+///
+/// The input delay and the correction factor are read by lightyear every time it updates the
+/// prediction, and are changed in place. Lightyear builds the link conditioner into the transport
+/// when connecting, so changing it makes the client disconnect and connect again, its player is
+/// given back within the server's `reconnect_grace_ms`
+pub struct SettingsReloadPlugin {
+    pub(crate) path: PathBuf,
+    pub(crate) overrides: SettingsOverrides,
+    pub(crate) settings: MySettings,
+}
+
+impl Plugin for SettingsReloadPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.settings.clone())
+            .insert_resource(SettingsWatcher {
+                path: self.path.clone(),
+                overrides: self.overrides.clone(),
+                modified: modified_time(&self.path),
+                timer: Timer::new(POLL_INTERVAL, TimerMode::Repeating),
+            })
+            .add_event::<SettingsReloaded>()
+            .add_systems(
+                Update,
+                (
+                    watch_settings,
+                    // the replay and bot apps don't draw the confirmed shadows
+                    apply_shared_settings.run_if(resource_exists::<ShowConfirmed>),
+                    apply_client_settings.run_if(resource_exists::<ClientConfig>),
+                    reconnect.run_if(
+                        resource_exists::<PendingReconnect>
+                            .and_then(in_state(NetworkingState::Disconnected)),
+                    ),
+                )
+                    .chain(),
+            );
+    }
+}
+
+#[derive(Resource)]
+struct SettingsWatcher {
+    path: PathBuf,
+    overrides: SettingsOverrides,
+    modified: Option<SystemTime>,
+    timer: Timer,
+}
+
+/// Sent after [`MySettings`] has been replaced by the content of the settings file
+#[derive(Event)]
+pub struct SettingsReloaded {
+    pub previous: MySettings,
+}
+
+#[derive(Resource)]
+struct PendingReconnect;
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn watch_settings(
+    time: Res<Time>,
+    mut watcher: ResMut<SettingsWatcher>,
+    mut settings: ResMut<MySettings>,
+    mut reloaded: EventWriter<SettingsReloaded>,
+) {
+    if !watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let modified = modified_time(&watcher.path);
+    if modified == watcher.modified {
+        return;
+    }
+    watcher.modified = modified;
+
+    let mut new_settings = match read_settings_file::<MySettings>(&watcher.path) {
+        Ok(new_settings) => new_settings,
+        Err(err) => {
+            warn!("Could not reload the settings: {err}");
+            return;
+        }
+    };
+    watcher.overrides.apply(&mut new_settings.common);
+    for field in restart_required(&settings, &new_settings) {
+        warn!(
+            "`{field}` changed in {}, restart required to apply it",
+            watcher.path.display()
+        );
+    }
+    info!("Reloaded settings from {}", watcher.path.display());
+    let previous = std::mem::replace(settings.as_mut(), new_settings);
+    reloaded.send(SettingsReloaded { previous });
+}
+
+/// Fields that are only read when the app starts
+pub(crate) fn restart_required(old: &MySettings, new: &MySettings) -> Vec<&'static str> {
+    let (old_client, new_client) = (&old.common.client, &new.common.client);
+    let (old_shared, new_shared) = (&old.common.shared, &new.common.shared);
+    [
+        ("predict_all", old.predict_all != new.predict_all),
+        ("deterministic", old.deterministic != new.deterministic),
+        ("metrics_export", old.metrics_export != new.metrics_export),
+        ("replication", old.replication != new.replication),
+        ("common.server", old.common.server != new.common.server),
+        ("common.client.client_id", old_client.client_id != new_client.client_id),
+        ("common.client.client_port", old_client.client_port != new_client.client_port),
+        ("common.client.server_addr", old_client.server_addr != new_client.server_addr),
+        ("common.client.server_port", old_client.server_port != new_client.server_port),
+        ("common.client.transport", old_client.transport != new_client.transport),
+        ("common.client.token_port", old_client.token_port != new_client.token_port),
+        ("common.client.spectator", old_client.spectator != new_client.spectator),
+        (
+            "common.shared",
+            old_shared.protocol_id != new_shared.protocol_id
+                || old_shared.private_key != new_shared.private_key
+                // CompressionConfig does not implement PartialEq
+                || format!("{:?}", old_shared.compression) != format!("{:?}", new_shared.compression),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(field, changed)| changed.then_some(field))
+    .collect()
+}
+
+fn apply_shared_settings(
+    mut reloaded: EventReader<SettingsReloaded>,
+    settings: Res<MySettings>,
+    mut show_confirmed: ResMut<ShowConfirmed>,
+) {
+    if reloaded.read().count() > 0 {
+        show_confirmed.0 = settings.show_confirmed;
+    }
+}
+
+fn apply_client_settings(
+    mut commands: Commands,
+    mut reloaded: EventReader<SettingsReloaded>,
+    settings: Res<MySettings>,
+    mut config: ResMut<ClientConfig>,
+    mut show_diagnostics: Option<ResMut<ShowDiagnostics>>,
+    state: Res<State<NetworkingState>>,
+) {
+    for SettingsReloaded { previous } in reloaded.read() {
+        config.prediction.correction_ticks_factor = settings.correction_ticks_factor;
+        config.prediction.minimum_input_delay_ticks = settings.input_delay_ticks;
+        if let Some(show_diagnostics) = show_diagnostics.as_mut() {
+            show_diagnostics.0 = settings.show_diagnostics;
+        }
+
+        if previous.common.client.conditioner == settings.common.client.conditioner {
+            continue;
+        }
+        let conditioner = settings
+            .common
+            .client
+            .conditioner
+            .as_ref()
+            .map(Conditioner::build);
+        match &mut config.net {
+            NetConfig::Netcode { io, .. } => io.conditioner = conditioner,
+            #[cfg(not(target_family = "wasm"))]
+            NetConfig::Steam {
+                conditioner: steam_conditioner,
+                ..
+            } => *steam_conditioner = conditioner,
+            NetConfig::Local { .. } => {}
+        }
+        // the link conditioner is built from the config when connecting
+        if *state.get() != NetworkingState::Disconnected {
+            info!("Reconnecting to apply the new link conditioner");
+            commands.disconnect_client();
+            commands.insert_resource(PendingReconnect);
+        }
+    }
+}
+
+fn reconnect(mut commands: Commands) {
+    commands.remove_resource::<PendingReconnect>();
+    commands.add(Connect);
+}
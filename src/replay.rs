@@ -0,0 +1,484 @@
+//! Plays back a [`Recording`] in a local app, re-simulating the physics from the recorded inputs
+//!
+//! The soccer kickoffs teleport the bodies outside of the simulation, the replay loads their
+//! recorded state instead
+use std::collections::{BTreeMap, HashMap};
+
+use avian2d::prelude::*;
+use bevy::app::FixedMain;
+use bevy::color::palettes::css;
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::ui::RelativeCursorPosition;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::ClientId;
+
+use crate::determinism::DeterministicPhysicsPlugin;
+use crate::level::spawn_level;
+use crate::protocol::*;
+use crate::recording::{BodyId, BodyState, Recording, SNAPSHOT_INTERVAL};
+use crate::server::movement;
+use crate::shared::{
+    add_physics_plugins, color_from_id, draw_elements, init_camera, FixedSet, FIXED_TIMESTEP_HZ,
+};
+
+const MAX_SPEED: f64 = 8.0;
+
+pub struct ReplayPlugin {
+    pub(crate) recording: Recording,
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        add_physics_plugins(app);
+        if self.recording.deterministic {
+            app.add_plugins(DeterministicPhysicsPlugin);
+        }
+        app.insert_resource(Time::<Fixed>::from_hz(FIXED_TIMESTEP_HZ))
+            .insert_resource(self.recording.clone())
+            .insert_resource(ReplayState {
+                next_tick: 0,
+                paused: false,
+                speed: 1.0,
+            })
+            .init_resource::<Divergences>()
+            .add_systems(Startup, (spawn_recorded_level, spawn_balls))
+            .add_systems(
+                FixedUpdate,
+                (
+                    (apply_spawns, apply_inputs).chain().before(movement),
+                    movement,
+                )
+                    .in_set(FixedSet::Main),
+            )
+            .add_systems(FixedPostUpdate, (apply_kickoff, compare_snapshot).chain())
+            .add_systems(Update, (seek, sync_time).chain());
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, (init_camera, spawn_timeline))
+                .add_systems(Update, controls.before(seek))
+                .add_systems(
+                    PostUpdate,
+                    (
+                        draw_elements,
+                        update_timeline,
+                        update_markers.run_if(resource_changed::<Divergences>),
+                    ),
+                );
+        }
+    }
+}
+
+/// Playback position, the world holds the state at the end of the tick before `next_tick`
+#[derive(Resource)]
+pub struct ReplayState {
+    pub next_tick: u32,
+    pub paused: bool,
+    pub speed: f64,
+}
+
+/// Snapshots where the re-simulated state differs from the recording, with the largest error
+#[derive(Resource, Default)]
+pub struct Divergences {
+    pub ticks: BTreeMap<u32, f32>,
+    /// Number of snapshots compared so far
+    pub compared: usize,
+}
+
+/// Jump to the end of this tick
+#[derive(Resource)]
+pub struct SeekTarget(pub u32);
+
+fn spawn_recorded_level(mut commands: Commands, recording: Res<Recording>) {
+    spawn_level(&mut commands, &recording.level);
+    commands.insert_resource(recording.level.clone());
+}
+
+fn spawn_balls(mut commands: Commands, recording: Res<Recording>) {
+    for (index, position) in recording.balls.iter().enumerate() {
+        commands.spawn((
+            BodyId::Ball(index as u32),
+            BallMarker,
+            Position(*position),
+            ColorComponent(css::AZURE.into()),
+            PhysicsBundle::ball(),
+        ));
+    }
+}
+
+fn spawn_player(world: &mut World, client_id: ClientId, state: BodyState) {
+    let mut player = world.spawn((
+        BodyId::Player(client_id),
+        PlayerId(client_id),
+        PhysicsBundle::player(),
+        ActionState::<PlayerActions>::default(),
+    ));
+    restore_player(&mut player, client_id, state);
+}
+
+/// Gives a player the recorded state, its team and the color and collision layers that go with it
+fn restore_player(player: &mut EntityWorldMut, client_id: ClientId, state: BodyState) {
+    player.insert((
+        state.cooldowns.unwrap_or_default(),
+        state.position,
+        state.rotation,
+        state.linear_velocity,
+        state.angular_velocity,
+    ));
+    match state.team {
+        Some((team, layers)) => {
+            player.insert((team, ColorComponent(team.color()), layers));
+        }
+        None => {
+            player
+                .insert(ColorComponent(color_from_id(client_id)))
+                .remove::<(Team, CollisionLayers)>();
+        }
+    }
+}
+
+fn apply_spawns(
+    mut commands: Commands,
+    recording: Res<Recording>,
+    state: Res<ReplayState>,
+    bodies: Query<(Entity, &BodyId)>,
+) {
+    let tick = state.next_tick;
+    for client_id in recording.despawns.get(&tick).into_iter().flatten() {
+        for (entity, id) in &bodies {
+            if *id == BodyId::Player(*client_id) {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+    for (client_id, body) in recording.spawns.get(&tick).into_iter().flatten() {
+        let (client_id, body) = (*client_id, body.clone());
+        commands.add(move |world: &mut World| spawn_player(world, client_id, body));
+    }
+    for (client_id, team, layers) in recording.team_changes.get(&tick).into_iter().flatten() {
+        for (entity, id) in &bodies {
+            if *id == BodyId::Player(*client_id) {
+                commands
+                    .entity(entity)
+                    .insert((*team, ColorComponent(team.color()), *layers));
+            }
+        }
+    }
+}
+
+fn apply_inputs(
+    recording: Res<Recording>,
+    state: Res<ReplayState>,
+    mut players: Query<(&PlayerId, &mut ActionState<PlayerActions>)>,
+) {
+    for (player_id, mut action) in players.iter_mut() {
+        *action = recording
+            .input(player_id.0, state.next_tick)
+            .cloned()
+            .unwrap_or_default();
+    }
+}
+
+/// Loads the state recorded after a kickoff, at the end of its tick
+fn apply_kickoff(world: &mut World) {
+    let tick = world.resource::<ReplayState>().next_tick;
+    if let Some(bodies) = world.resource::<Recording>().kickoffs.get(&tick).cloned() {
+        restore(world, bodies);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn compare_snapshot(
+    recording: Res<Recording>,
+    mut state: ResMut<ReplayState>,
+    mut divergences: ResMut<Divergences>,
+    bodies: Query<(
+        &BodyId,
+        &Position,
+        Option<&Rotation>,
+        Option<&LinearVelocity>,
+        Option<&AngularVelocity>,
+        Option<&Cooldowns>,
+        Option<(&Team, &CollisionLayers)>,
+    )>,
+) {
+    let tick = state.next_tick;
+    if let Some(snapshot) = recording.snapshots.get(&tick) {
+        let simulated: HashMap<_, _> = bodies
+            .iter()
+            .map(
+                |(id, position, rotation, linear_velocity, angular_velocity, cooldowns, team)| {
+                    (
+                        *id,
+                        BodyState::new(
+                            position,
+                            rotation,
+                            linear_velocity,
+                            angular_velocity,
+                            cooldowns,
+                            team,
+                        ),
+                    )
+                },
+            )
+            .collect();
+        let mut diverged = simulated.len() != snapshot.len();
+        let mut error: f32 = 0.0;
+        for (id, recorded) in snapshot {
+            match simulated.get(id) {
+                Some(body) if body.matches(recorded) => {}
+                Some(body) => {
+                    diverged = true;
+                    error = error.max(body.error(recorded));
+                }
+                None => {
+                    diverged = true;
+                    error = f32::INFINITY;
+                }
+            }
+        }
+        divergences.compared += 1;
+        if !diverged {
+            divergences.ticks.remove(&tick);
+        } else if divergences.ticks.insert(tick, error).is_none() {
+            warn!(tick, error, "Replay diverged from the recorded snapshot");
+        }
+    }
+    state.next_tick += 1;
+    if state.next_tick >= recording.ticks {
+        state.paused = true;
+    }
+}
+
+/// Move the replay to the end of the [`SeekTarget`] tick, from the closest snapshot if it is far
+fn seek(world: &mut World) {
+    let Some(SeekTarget(target)) = world.remove_resource::<SeekTarget>() else {
+        return;
+    };
+    let recording = world.resource::<Recording>();
+    let target = target.min(recording.ticks.saturating_sub(1));
+    let next_tick = world.resource::<ReplayState>().next_tick;
+    // re-simulating from the current state is only worth it for short jumps forward
+    if target < next_tick || target - next_tick > SNAPSHOT_INTERVAL {
+        let snapshot = recording
+            .snapshots
+            .range(..=target)
+            .next_back()
+            .map(|(tick, bodies)| (*tick, bodies.clone()));
+        if let Some((tick, bodies)) = snapshot {
+            restore(world, bodies);
+            world.resource_mut::<ReplayState>().next_tick = tick + 1;
+        }
+    }
+    while world.resource::<ReplayState>().next_tick <= target {
+        world.run_schedule(FixedMain);
+    }
+}
+
+/// Replace the state of every body with the recorded one
+fn restore(world: &mut World, bodies: Vec<(BodyId, BodyState)>) {
+    let mut bodies: HashMap<_, _> = bodies.into_iter().collect();
+    let entities: Vec<_> = world
+        .query::<(Entity, &BodyId)>()
+        .iter(world)
+        .map(|(entity, id)| (entity, *id))
+        .collect();
+    for (entity, id) in entities {
+        match bodies.remove(&id) {
+            Some(state) => {
+                let mut body = world.entity_mut(entity);
+                match id {
+                    BodyId::Player(client_id) => restore_player(&mut body, client_id, state),
+                    BodyId::Ball(_) => {
+                        body.insert((
+                            state.position,
+                            state.rotation,
+                            state.linear_velocity,
+                            state.angular_velocity,
+                        ));
+                    }
+                }
+            }
+            None => {
+                world.despawn(entity);
+            }
+        }
+    }
+    for (id, state) in bodies {
+        if let BodyId::Player(client_id) = id {
+            spawn_player(world, client_id, state);
+        }
+    }
+}
+
+fn sync_time(state: Res<ReplayState>, mut time: ResMut<Time<Virtual>>) {
+    if state.paused {
+        time.pause();
+    } else {
+        time.unpause();
+    }
+    time.set_relative_speed_f64(state.speed);
+}
+
+fn controls(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    recording: Res<Recording>,
+    mut state: ResMut<ReplayState>,
+    timeline: Query<&RelativeCursorPosition, With<Timeline>>,
+) {
+    if keys.just_pressed(KeyCode::Space) {
+        state.paused = !state.paused;
+    }
+    if keys.just_pressed(KeyCode::ArrowUp) {
+        state.speed = (state.speed * 2.0).min(MAX_SPEED);
+    }
+    if keys.just_pressed(KeyCode::ArrowDown) {
+        state.speed = (state.speed / 2.0).max(1.0 / MAX_SPEED);
+    }
+    // step one tick, the state shown is the end of the tick before `next_tick`
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        state.paused = true;
+        commands.insert_resource(SeekTarget(state.next_tick));
+    }
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        state.paused = true;
+        commands.insert_resource(SeekTarget(state.next_tick.saturating_sub(2)));
+    }
+    if mouse.pressed(MouseButton::Left) {
+        for cursor in &timeline {
+            if let Some(position) = cursor.normalized.filter(|_| cursor.mouse_over()) {
+                commands.insert_resource(SeekTarget((position.x * recording.ticks as f32) as u32));
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+struct Timeline;
+
+#[derive(Component)]
+struct TimelineCursor;
+
+#[derive(Component)]
+struct DivergenceMarker;
+
+#[derive(Component)]
+struct ReplayText;
+
+fn spawn_timeline(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        }),
+        ReplayText,
+    ));
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(10.0),
+                    left: Val::Percent(5.0),
+                    width: Val::Percent(90.0),
+                    height: Val::Px(16.0),
+                    ..default()
+                },
+                background_color: css::DARK_SLATE_GRAY.into(),
+                ..default()
+            },
+            RelativeCursorPosition::default(),
+            Timeline,
+        ))
+        .with_children(|timeline| {
+            timeline.spawn((
+                NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        width: Val::Px(3.0),
+                        height: Val::Percent(100.0),
+                        ..default()
+                    },
+                    background_color: Color::WHITE.into(),
+                    z_index: ZIndex::Local(1),
+                    ..default()
+                },
+                TimelineCursor,
+            ));
+        });
+}
+
+fn timeline_percent(tick: u32, ticks: u32) -> Val {
+    Val::Percent(100.0 * tick as f32 / ticks.max(1) as f32)
+}
+
+fn update_timeline(
+    recording: Res<Recording>,
+    state: Res<ReplayState>,
+    divergences: Res<Divergences>,
+    mut cursor: Query<&mut Style, With<TimelineCursor>>,
+    mut text: Query<&mut Text, With<ReplayText>>,
+) {
+    let tick = state.next_tick.saturating_sub(1).min(recording.ticks);
+    for mut style in cursor.iter_mut() {
+        style.left = timeline_percent(tick, recording.ticks);
+    }
+    let status = if state.paused { "paused" } else { "playing" };
+    let diverged = match divergences.ticks.last_key_value() {
+        Some((last, error)) => format!(
+            "{} of {} snapshots diverged, last at tick {last} (error {error:.4})",
+            divergences.ticks.len(),
+            divergences.compared
+        ),
+        None => format!("{} snapshots match", divergences.compared),
+    };
+    for mut text in text.iter_mut() {
+        text.sections[0].value = format!(
+            "tick {tick}/{} {status} x{}\n{diverged}\nspace: pause, left/right: step, up/down: speed, click the timeline to seek",
+            recording.ticks, state.speed
+        );
+    }
+}
+
+fn update_markers(
+    mut commands: Commands,
+    recording: Res<Recording>,
+    divergences: Res<Divergences>,
+    timeline: Query<Entity, With<Timeline>>,
+    markers: Query<Entity, With<DivergenceMarker>>,
+) {
+    for marker in &markers {
+        commands.entity(marker).despawn_recursive();
+    }
+    for timeline in &timeline {
+        commands.entity(timeline).with_children(|timeline| {
+            for tick in divergences.ticks.keys() {
+                timeline.spawn((
+                    NodeBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            left: timeline_percent(*tick, recording.ticks),
+                            width: Val::Px(2.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        background_color: css::RED.into(),
+                        ..default()
+                    },
+                    DivergenceMarker,
+                ));
+            }
+        });
+    }
+}
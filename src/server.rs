@@ -1,36 +1,209 @@
 use avian2d::prelude::*;
 use bevy::color::palettes::css;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap};
 use leafwing_input_manager::prelude::*;
 use lightyear::prelude::client::{Confirmed, Predicted};
 use lightyear::prelude::server::*;
 use lightyear::prelude::*;
 
+use crate::interest::{AreaOfInterest, InterestPlugin};
+use crate::level::{spawn_level, Level};
 use crate::protocol::*;
-use crate::shared::{shared_movement_behaviour, FixedSet};
+use crate::recording::BodyState;
+use crate::settings::{BallSnapshots, GameMode, Interest, ReplicationRates};
+use crate::shared::{
+    shared_action_behaviour, shared_aim_behaviour, shared_movement_behaviour, FixedSet,
+};
+use crate::snapshot::{snapshot_components, SnapshotPlugin};
+use crate::soccer::SoccerPlugin;
+use crate::team::{TeamPlugin, Teams};
 
 pub struct ServerPlugin {
     pub(crate) predict_all: bool,
+    pub(crate) max_clients: usize,
+    pub(crate) level: Level,
+    pub(crate) mode: GameMode,
+    /// Split the players in teams, always done in the soccer mode
+    pub(crate) teams: bool,
+    pub(crate) friendly_collisions: bool,
+    pub(crate) interest: Option<Interest>,
+    pub(crate) ball_snapshots: Option<BallSnapshots>,
+    pub(crate) replication: ReplicationRates,
+    pub(crate) reconnect_grace_period: Duration,
 }
 
 #[derive(Resource)]
 pub struct Global {
     predict_all: bool,
+    max_clients: usize,
+    /// The players only receive the entities around them instead of joining the game room
+    area_of_interest: bool,
+    /// The balls are replicated through the [`SnapshotPlugin`]
+    ball_snapshots: bool,
+    /// Replication group of the players, with their send interval
+    player_group: ReplicationGroup,
+    /// Replication group of each ball, with their send interval
+    ball_group: ReplicationGroup,
+    reconnect_grace_period: Duration,
+}
+
+/// Clients that joined the game
+#[derive(Resource, Default)]
+pub(crate) struct Lobby {
+    pub(crate) players: Vec<ClientId>,
+    pub(crate) spectators: Vec<ClientId>,
+}
+
+impl Lobby {
+    /// Sync target of the entities that every player predicts, spectators interpolate them instead
+    fn predicted_sync_target(&self) -> SyncTarget {
+        SyncTarget {
+            prediction: NetworkTarget::AllExcept(self.spectators.clone()),
+            interpolation: NetworkTarget::Only(self.spectators.clone()),
+        }
+    }
+
+    fn update_sync_targets(&self, sync_targets: &mut Query<&mut SyncTarget>) {
+        let sync_target = self.predicted_sync_target();
+        for mut target in sync_targets.iter_mut() {
+            *target = sync_target.clone();
+        }
+    }
+}
+
+/// State of the players that disconnected, and when they did
+#[derive(Resource, Default)]
+struct DepartedPlayers(HashMap<ClientId, (BodyState, Duration)>);
+
+/// The clients in the game and the players that can still reconnect, updated when clients join
+/// and leave
+#[derive(SystemParam)]
+struct Membership<'w, 's> {
+    time: Res<'w, Time>,
+    global: Res<'w, Global>,
+    lobby: ResMut<'w, Lobby>,
+    departed: ResMut<'w, DepartedPlayers>,
+    sync_targets: Query<'w, 's, &'static mut SyncTarget>,
+}
+
+/// Bodies a player must not spawn on
+type SpawnBlockers = Or<(With<BallMarker>, With<PlayerId>)>;
+
+/// Where the players can spawn
+#[derive(SystemParam)]
+struct SpawnArea<'w, 's> {
+    level: Res<'w, Level>,
+    bodies: Query<'w, 's, (&'static Position, Has<BallMarker>), SpawnBlockers>,
+}
+
+impl SpawnArea<'_, '_> {
+    /// Circles around the bodies a player must not spawn on
+    fn occupied(&self) -> Vec<(Vec2, f32)> {
+        self.bodies
+            .iter()
+            .map(|(position, is_ball)| {
+                (position.0, if is_ball { BALL_SIZE } else { PLAYER_RADIUS })
+            })
+            .collect()
+    }
 }
 
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Global {
             predict_all: self.predict_all,
-        });
+            max_clients: self.max_clients,
+            area_of_interest: self.interest.is_some(),
+            ball_snapshots: self.ball_snapshots.is_some(),
+            player_group: with_send_interval(
+                REPLICATION_GROUP,
+                self.replication.players(self.predict_all),
+            ),
+            // the predicted balls are confirmed at the same ticks as the players, unless each ball
+            // needs its own group for its priority
+            ball_group: if self.predict_all && self.replication.bandwidth_cap_kbps.is_none() {
+                with_send_interval(REPLICATION_GROUP, self.replication.players(true))
+            } else {
+                with_send_interval(
+                    ReplicationGroup::default(),
+                    self.replication.balls(self.predict_all),
+                )
+            },
+            reconnect_grace_period: self.reconnect_grace_period,
+        })
+        .insert_resource(self.level.clone())
+        .init_resource::<Lobby>()
+        .init_resource::<DepartedPlayers>();
+        if let GameMode::Soccer { match_secs } = self.mode {
+            app.add_plugins(SoccerPlugin {
+                match_duration: Duration::from_secs(match_secs),
+            });
+        }
+        if self.teams || matches!(self.mode, GameMode::Soccer { .. }) {
+            app.add_plugins(TeamPlugin {
+                friendly_collisions: self.friendly_collisions,
+            });
+        }
+        if let Some(settings) = &self.interest {
+            app.add_plugins(InterestPlugin {
+                settings: settings.clone(),
+            });
+        }
+        if let Some(settings) = &self.ball_snapshots {
+            app.add_plugins(SnapshotPlugin {
+                bandwidth_kbps: settings.bandwidth_kbps,
+            });
+        }
 
         app.add_systems(Startup, (start_server, init));
-        app.add_systems(PreUpdate, replicate_inputs.after(MainSet::EmitEvents));
         app.add_systems(
             PreUpdate,
-            replicate_players.in_set(ServerReplicationSet::ClientReplication),
+            (send_level, handle_join, handle_disconnect, replicate_inputs)
+                .chain()
+                .after(MainSet::EmitEvents),
         );
         app.add_systems(FixedUpdate, movement.in_set(FixedSet::Main));
+        if self.replication.bandwidth_cap_kbps.is_some() {
+            app.add_systems(Update, prioritize_balls);
+        }
+    }
+}
+
+fn with_send_interval(group: ReplicationGroup, interval: Duration) -> ReplicationGroup {
+    if interval.is_zero() {
+        group
+    } else {
+        group.set_send_frequency(interval)
+    }
+}
+
+/// Distance from the closest player at which a ball has half the priority of the players
+const PRIORITY_DISTANCE: f32 = 300.0;
+
+/// Priority of a ball's replication group, rounded so that it doesn't change every tick
+pub(crate) fn ball_priority(distance: f32) -> f32 {
+    let priority = PRIORITY_DISTANCE / (PRIORITY_DISTANCE + distance);
+    (priority * 10.0).round().max(1.0) / 10.0
+}
+
+/// With a bandwidth cap, lightyear sends the groups with the highest accumulated priority first,
+/// the balls close to a player accumulate it the fastest. A group has one priority for every
+/// client, so it follows the closest player of any of them
+fn prioritize_balls(
+    players: Query<&Position, With<PlayerId>>,
+    mut balls: Query<(&Position, &mut ReplicationGroup), With<BallMarker>>,
+) {
+    for (position, mut group) in balls.iter_mut() {
+        let distance = players
+            .iter()
+            .map(|player| player.distance(position.0))
+            .fold(f32::INFINITY, f32::min);
+        let priority = ball_priority(distance);
+        if group.priority() != priority {
+            *group = group.clone().set_priority(priority);
+        }
     }
 }
 
@@ -38,7 +211,12 @@ fn start_server(mut commands: Commands) {
     commands.start_server();
 }
 
-fn init(mut commands: Commands, global: Res<Global>) {
+fn init(
+    mut commands: Commands,
+    global: Res<Global>,
+    level: Res<Level>,
+    mut rooms: ResMut<RoomManager>,
+) {
     commands.spawn(
         TextBundle::from_section(
             "Server",
@@ -54,83 +232,285 @@ fn init(mut commands: Commands, global: Res<Global>) {
         }),
     );
 
-    let spacing = 40;
-    for y in -5..5 {
-        for x in -8..8 {
-            commands.spawn(BallBundle::new(
-                Vec2::new((x * spacing + spacing / 2) as f32, (y * spacing + spacing * 4) as f32),
-                css::AZURE.into(),
-                global.predict_all,
-            ));
+    info!("Playing on level {}", level.name);
+    spawn_level(&mut commands, &level);
+    for (index, position) in level.ball_positions().into_iter().enumerate() {
+        let mut ball = commands.spawn(BallBundle::new(
+            position,
+            css::AZURE.into(),
+            global.predict_all,
+            global.ball_group.clone(),
+        ));
+        if global.ball_snapshots {
+            ball.insert(snapshot_components(index as u32));
+        }
+        rooms.add_entity(ball.id(), GAME_ROOM);
+    }
+}
+
+/// Tells the clients that connect which level is played
+fn send_level(
+    level: Res<Level>,
+    mut connection: ResMut<ConnectionManager>,
+    mut events: EventReader<ConnectEvent>,
+) {
+    for event in events.read() {
+        let client_id = event.client_id;
+        let message = ActiveLevel {
+            level: level.clone(),
+        };
+        if let Err(e) = connection.send_message_to_target::<LobbyChannel, _>(
+            &message,
+            NetworkTarget::Single(client_id),
+        ) {
+            error!("Could not send the level to client {client_id}: {e}");
         }
     }
 }
 
+#[allow(clippy::type_complexity)]
 pub(crate) fn movement(
     mut action_query: Query<
         (
             Entity,
             &Position,
+            &Rotation,
             &mut LinearVelocity,
+            &mut AngularVelocity,
+            &mut Cooldowns,
             &ActionState<PlayerActions>,
         ),
-        (Without<Confirmed>, Without<Predicted>),
+        (Without<BallMarker>, Without<Confirmed>, Without<Predicted>),
+    >,
+    mut balls: Query<
+        (&Position, &mut LinearVelocity),
+        (With<BallMarker>, Without<Confirmed>, Without<Predicted>),
     >,
 ) {
-    for (_, _, velocity, action) in action_query.iter_mut() {
-        if !action.get_pressed().is_empty() {
-            shared_movement_behaviour(velocity, action);
+    for (_, position, rotation, mut velocity, mut angular_velocity, mut cooldowns, action) in
+        action_query.iter_mut()
+    {
+        if action.pressed(&PlayerActions::Move) {
+            shared_movement_behaviour(velocity.reborrow(), action);
         }
+        shared_aim_behaviour(action, rotation, &mut angular_velocity);
+        shared_action_behaviour(
+            action,
+            position,
+            rotation,
+            &mut velocity,
+            &mut cooldowns,
+            &mut balls,
+        );
     }
 }
 
-pub(crate) fn replicate_inputs(
-    mut connection: ResMut<ConnectionManager>,
-    mut input_events: EventReader<MessageEvent<InputMessage<PlayerActions>>>,
+/// Adds the client to the game room and spawns its player, unless it would exceed `max_clients`
+/// players
+fn handle_join(
+    mut commands: Commands,
+    membership: Membership,
+    spawn_area: SpawnArea,
+    mut teams: Option<ResMut<Teams>>,
+    mut rooms: ResMut<RoomManager>,
+    mut connections: ResMut<ServerConnections>,
+    mut events: EventReader<MessageEvent<JoinGame>>,
 ) {
-    for event in input_events.read() {
-        let inputs = event.message();
-        let client_id = event.context();
+    let Membership {
+        time,
+        global,
+        mut lobby,
+        mut departed,
+        mut sync_targets,
+    } = membership;
+    let level = &spawn_area.level;
+    let now = time.elapsed();
+    departed
+        .0
+        .retain(|_, (_, left_at)| now - *left_at < global.reconnect_grace_period);
+    let mut occupied = spawn_area.occupied();
+    let mut spectators_joined = false;
+    let mut spawns = vec![];
+    for event in events.read() {
+        let client_id = *event.context();
+        if lobby.players.contains(&client_id) || lobby.spectators.contains(&client_id) {
+            warn!("Client {client_id} already joined the game, ignoring its join request");
+            continue;
+        }
+        if event.message().spectator {
+            info!("Client {client_id} joined as a spectator");
+            lobby.spectators.push(client_id);
+            spectators_joined = true;
+        } else if lobby.players.len() >= global.max_clients {
+            warn!("The server is full, disconnecting client {client_id}");
+            if let Err(e) = connections.disconnect(client_id) {
+                error!("Could not disconnect client {client_id}: {e}");
+            }
+            continue;
+        } else {
+            lobby.players.push(client_id);
+            // with goals, players start on their team's half
+            let team = teams
+                .as_mut()
+                .map(|teams| teams.assign(client_id, &lobby.players));
+            let spawn_points = team
+                .map(|team| level.team_spawn_points(team))
+                .filter(|points| !points.is_empty())
+                .unwrap_or_else(|| level.spawn_points.clone());
+            // players that reconnect in time get their body back, where it was unless another
+            // body took its place
+            let state = departed.0.remove(&client_id).map(|(state, _)| state);
+            let position = state
+                .as_ref()
+                .map(|state| state.position.0)
+                .filter(|position| clearance(*position, &occupied) >= 0.0)
+                .unwrap_or_else(|| spawn_point(&spawn_points, &occupied));
+            occupied.push((position, PLAYER_RADIUS));
+            spawns.push((client_id, position, state, team));
+            // relevance is then managed by the area of interest
+            if global.area_of_interest {
+                continue;
+            }
+        }
+        rooms.add_client(client_id, GAME_ROOM);
+    }
 
-        connection
-            .send_message_to_target::<InputChannel, _>(
-                inputs,
-                NetworkTarget::AllExceptSingle(*client_id),
-            )
-            .unwrap()
+    if spectators_joined && global.predict_all {
+        // the sync target is only read when the entity is spawned on the client, which happens
+        // once it enters the room
+        lobby.update_sync_targets(&mut sync_targets);
+    }
+    for (client_id, position, state, team) in spawns {
+        let sync_target = if global.predict_all {
+            lobby.predicted_sync_target()
+        } else {
+            SyncTarget {
+                prediction: NetworkTarget::Single(client_id),
+                interpolation: NetworkTarget::AllExceptSingle(client_id),
+            }
+        };
+        let mut player = commands.spawn(PlayerBundle::new(
+            client_id,
+            position,
+            sync_target,
+            global.player_group.clone(),
+        ));
+        if let Some(state) = state {
+            info!("Client {client_id} reconnected");
+            player.insert((state.rotation, state.linear_velocity, state.angular_velocity));
+        }
+        if let (Some(teams), Some(team)) = (&teams, team) {
+            player.insert(teams.components(team));
+        }
+        rooms.add_entity(player.id(), GAME_ROOM);
     }
 }
 
-pub(crate) fn replicate_players(
-    global: Res<Global>,
-    mut commands: Commands,
-    query: Query<(Entity, &Replicated), (Added<Replicated>, With<PlayerId>)>,
-) {
-    for (entity, replicated) in query.iter() {
-        let client_id = replicated.client_id();
+/// Radius of the circle around a player
+pub(crate) const PLAYER_RADIUS: f32 = PLAYER_SIZE * std::f32::consts::FRAC_1_SQRT_2;
+
+/// Distance between a player at `point` and the closest of the `occupied` circles, negative if
+/// they overlap
+fn clearance(point: Vec2, occupied: &[(Vec2, f32)]) -> f32 {
+    occupied
+        .iter()
+        .map(|(position, radius)| point.distance(*position) - radius - PLAYER_RADIUS)
+        .fold(f32::INFINITY, f32::min)
+}
 
-        if let Some(mut e) = commands.get_entity(entity) {
-            let mut sync_target = SyncTarget::default();
+/// The first spawn point where a player doesn't overlap any body, or the one with the most room
+pub(crate) fn spawn_point(spawn_points: &[Vec2], occupied: &[(Vec2, f32)]) -> Vec2 {
+    let clearance = |point: Vec2| clearance(point, occupied);
+    spawn_points
+        .iter()
+        .copied()
+        .find(|point| clearance(*point) >= 0.0)
+        .or_else(|| {
+            spawn_points
+                .iter()
+                .copied()
+                .max_by(|a, b| clearance(*a).total_cmp(&clearance(*b)))
+        })
+        .unwrap_or_default()
+}
 
+/// Despawns the player of the client and everything it controls, and tells the other clients
+fn handle_disconnect(
+    mut commands: Commands,
+    membership: Membership,
+    mut connection: ResMut<ConnectionManager>,
+    mut events: EventReader<DisconnectEvent>,
+    players: Query<(&PlayerId, &Position, &Rotation, &LinearVelocity, &AngularVelocity)>,
+    controlled: Query<(Entity, &ControlledBy)>,
+) {
+    let Membership {
+        time,
+        global,
+        mut lobby,
+        mut departed,
+        mut sync_targets,
+    } = membership;
+    for event in events.read() {
+        let client_id = event.client_id;
+        if lobby.players.contains(&client_id) {
+            lobby.players.retain(|id| *id != client_id);
+            info!("Client {client_id} left");
+            if let Some((_, position, rotation, linear_velocity, angular_velocity)) =
+                players.iter().find(|(id, ..)| id.0 == client_id)
+            {
+                let state = BodyState::new(
+                    position,
+                    Some(rotation),
+                    Some(linear_velocity),
+                    Some(angular_velocity),
+                    None,
+                    None,
+                );
+                departed.0.insert(client_id, (state, time.elapsed()));
+            }
+            connection
+                .send_message_to_target::<LobbyChannel, _>(
+                    &PlayerLeft { client_id },
+                    NetworkTarget::AllExceptSingle(client_id),
+                )
+                .unwrap_or_else(|e| error!("Could not send the player left message: {e}"));
+        }
+        for (entity, controlled_by) in controlled.iter() {
+            if controlled_by.targets(&client_id) {
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+        if lobby.spectators.contains(&client_id) {
+            lobby.spectators.retain(|id| *id != client_id);
             if global.predict_all {
-                sync_target.prediction = NetworkTarget::All;
-            } else {
-                sync_target.interpolation = NetworkTarget::AllExceptSingle(client_id);
+                lobby.update_sync_targets(&mut sync_targets);
             }
-            let replicate = Replicate {
-                sync: sync_target,
-                controlled_by: ControlledBy {
-                    target: NetworkTarget::Single(client_id),
-                    ..default()
-                },
-                group: REPLICATION_GROUP,
-                ..default()
-            };
-            e.insert((
-                replicate,
-                OverrideTargetComponent::<PrePredicted>::new(NetworkTarget::Single(client_id)),
-                PhysicsBundle::player(),
-            ));
         }
     }
 }
+
+pub(crate) fn replicate_inputs(
+    lobby: Res<Lobby>,
+    interest: Option<Res<AreaOfInterest>>,
+    mut connection: ResMut<ConnectionManager>,
+    mut input_events: EventReader<MessageEvent<InputMessage<PlayerActions>>>,
+    players: Query<(Entity, &PlayerId)>,
+) {
+    for event in input_events.read() {
+        let inputs = event.message();
+        let client_id = event.context();
+        // spectators don't predict the players
+        let mut excluded = lobby.spectators.clone();
+        excluded.push(*client_id);
+        // nor do the clients that don't receive the player
+        if let Some(interest) = &interest {
+            if let Some((player, _)) = players.iter().find(|(_, id)| id.0 == *client_id) {
+                excluded.extend(interest.unaware_of(player));
+            }
+        }
+
+        connection
+            .send_message_to_target::<InputChannel, _>(inputs, NetworkTarget::AllExcept(excluded))
+            .unwrap()
+    }
+}
@@ -1,26 +1,94 @@
+use std::fmt;
+use std::fs;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
 use bevy::asset::ron;
-use bevy::prelude::{default, Resource};
+use bevy::prelude::{default, error, Resource, Vec2};
 use bevy::utils::Duration;
+use clap::ValueEnum;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use bevy::tasks::IoTaskPool;
 
+use crate::auth::ServerAuth;
+use crate::MySettings;
+
 use lightyear::prelude::client::Authentication;
+#[cfg(not(target_family = "wasm"))]
 use lightyear::prelude::client::{SocketConfig, SteamConfig};
 use lightyear::prelude::{CompressionConfig, LinkConditionerConfig};
 
 use lightyear::prelude::{client, server};
 
-pub fn read_settings<T: DeserializeOwned>(settings_str: &str) -> T {
-    ron::de::from_str::<T>(settings_str).expect("Could not deserialize the settings file")
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsError {
+    #[error("could not read settings file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid settings field `{field}` at {position}: {code}")]
+    Parse {
+        field: String,
+        position: ron::error::Position,
+        code: ron::Error,
+    },
+}
+
+pub fn read_settings<T: DeserializeOwned>(settings_str: &str) -> Result<T, SettingsError> {
+    let parse_error = |field: String, e: ron::error::SpannedError| SettingsError::Parse {
+        field,
+        position: e.position,
+        code: e.code,
+    };
+    // `client_id: 0` in the files written before the ids became optional
+    let options =
+        ron::Options::default().with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME);
+    let mut deserializer = ron::Deserializer::from_str_with_options(settings_str, options)
+        .map_err(|e| parse_error(".".into(), e))?;
+    // track the path of the field being deserialized so errors can name it
+    let settings = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        parse_error(field, deserializer.span_error(e.into_inner()))
+    })?;
+    deserializer
+        .end()
+        .map_err(|e| parse_error(".".into(), deserializer.span_error(e)))?;
+    Ok(settings)
+}
+
+pub fn read_settings_file<T: DeserializeOwned>(path: &Path) -> Result<T, SettingsError> {
+    let settings_str = fs::read_to_string(path).map_err(|source| SettingsError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    read_settings(&settings_str)
+}
+
+/// Read the settings from `path`, or from `default` if no path is given. The fields the file
+/// leaves out are taken from the embedded settings, see [`embedded_settings`]
+pub fn load_settings<T: DeserializeOwned>(
+    path: Option<&Path>,
+    default: &str,
+) -> Result<T, SettingsError> {
+    match path {
+        Some(path) => read_settings_file(path),
+        None => read_settings(default),
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum ClientTransports {
+    /// Not available in browsers
     Udp,
+    WebTransport {
+        /// Hex SHA-256 digest of the server certificate, which browsers need to accept a
+        /// self-signed one
+        certificate_digest: String,
+    },
+    WebSocket,
+    /// Not available in browsers
     Steam {
         app_id: u32,
     },
@@ -31,6 +99,14 @@ pub enum ServerTransports {
     Udp {
         local_port: u16,
     },
+    /// For browser clients, with the server's `certificate`
+    WebTransport {
+        local_port: u16,
+    },
+    /// For browser clients without WebTransport
+    WebSocket {
+        local_port: u16,
+    },
     Steam {
         app_id: u32,
         server_ip: Ipv4Addr,
@@ -39,11 +115,74 @@ pub enum ServerTransports {
     },
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// TLS certificate of the WebTransport server
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Certificate {
+    /// Generated on startup for these domain names or addresses, valid for 14 days
+    SelfSigned(Vec<String>),
+    /// PEM files of the certificate chain and of its private key
+    FromFile { cert: PathBuf, key: PathBuf },
+}
+
+impl Default for Certificate {
+    fn default() -> Self {
+        Certificate::SelfSigned(vec!["localhost".into(), "127.0.0.1".into(), "::1".into()])
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CertificateError {
+    #[error("could not generate a self-signed certificate: {0}")]
+    SelfSigned(String),
+    #[error("could not read the certificate: {0}")]
+    Load(String),
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Certificate {
+    pub(crate) fn load(&self) -> Result<server::Identity, CertificateError> {
+        match self {
+            Certificate::SelfSigned(names) => server::Identity::self_signed(names)
+                .map_err(|e| CertificateError::SelfSigned(e.to_string())),
+            Certificate::FromFile { cert, key } => {
+                // wtransport reads the files with tokio
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(|e| CertificateError::Load(e.to_string()))?;
+                runtime
+                    .block_on(server::Identity::load_pemfiles(cert, key))
+                    .map_err(|e| CertificateError::Load(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Simulated network conditions, incoming and outgoing from the point of view of the app they are
+/// set on
+///
+/// Lightyear's conditioner only simulates the incoming latency, jitter and loss, the other fields
+/// are applied by the server's link relay
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct Conditioner {
     pub(crate) latency_ms: u16,
     pub(crate) jitter_ms: u16,
     pub(crate) packet_loss: f32,
+    #[serde(default)]
+    pub(crate) outgoing_latency_ms: u16,
+    #[serde(default)]
+    pub(crate) outgoing_jitter_ms: u16,
+    #[serde(default)]
+    pub(crate) outgoing_loss: f32,
+    /// Probability that a packet is delivered twice
+    #[serde(default)]
+    pub(crate) duplicate: f32,
+    /// Probability that a packet is held back, so that the next ones overtake it
+    #[serde(default)]
+    pub(crate) reorder: f32,
+    /// Throughput of each direction, the packets over it wait in a queue
+    #[serde(default)]
+    pub(crate) bandwidth_kbps: Option<u32>,
 }
 
 impl Conditioner {
@@ -56,34 +195,326 @@ impl Conditioner {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Rules the server plays by
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum GameMode {
+    /// No objectives
+    #[default]
+    Sandbox,
+    /// Two teams score in each other's goal, the level needs a goal for each team
+    Soccer { match_secs: u64 },
+}
+
+/// Named network conditions that can be applied to a client while it is connected
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ConditionerPreset {
+    Lan,
+    #[value(name = "4g")]
+    FourG,
+    BadWifi,
+    Intercontinental,
+}
+
+impl ConditionerPreset {
+    pub fn conditioner(self) -> Conditioner {
+        let symmetric = |latency_ms, jitter_ms, packet_loss| Conditioner {
+            latency_ms,
+            jitter_ms,
+            packet_loss,
+            outgoing_latency_ms: latency_ms,
+            outgoing_jitter_ms: jitter_ms,
+            outgoing_loss: packet_loss,
+            ..default()
+        };
+        match self {
+            ConditionerPreset::Lan => symmetric(1, 0, 0.0),
+            ConditionerPreset::FourG => Conditioner {
+                reorder: 0.005,
+                bandwidth_kbps: Some(20_000),
+                ..symmetric(35, 10, 0.005)
+            },
+            ConditionerPreset::BadWifi => Conditioner {
+                duplicate: 0.01,
+                reorder: 0.02,
+                bandwidth_kbps: Some(2_000),
+                ..symmetric(20, 40, 0.05)
+            },
+            ConditionerPreset::Intercontinental => Conditioner {
+                bandwidth_kbps: Some(50_000),
+                ..symmetric(90, 5, 0.01)
+            },
+        }
+    }
+
+    /// Preset that follows `preset`, or no preset after the last one
+    pub fn next(preset: Option<Self>) -> Option<Self> {
+        let presets = Self::value_variants();
+        match preset {
+            None => presets.first().copied(),
+            Some(preset) => presets.iter().skip_while(|p| **p != preset).nth(1).copied(),
+        }
+    }
+}
+
+impl fmt::Display for ConditionerPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConditionerPreset::Lan => "LAN",
+            ConditionerPreset::FourG => "4G",
+            ConditionerPreset::BadWifi => "bad wifi",
+            ConditionerPreset::Intercontinental => "intercontinental",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MetricsFormat {
+    Csv,
+    JsonLines,
+}
+
+/// File the client metrics are written to, one line per sample
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MetricsExport {
+    pub(crate) path: PathBuf,
+    pub(crate) format: MetricsFormat,
+}
+
+/// Area of interest of a player, entities out of it are not replicated to its client
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Interest {
+    /// Distance from the player within which the other players are replicated
+    pub(crate) player_radius: f32,
+    /// Distance from the player within which the balls are replicated
+    pub(crate) ball_radius: f32,
+    /// How much further than its radius an entity must go to stop being replicated, so that the
+    /// entities on the edge don't keep appearing and disappearing
+    pub(crate) hysteresis: f32,
+}
+
+/// Compact replication of the balls, see [`crate::snapshot`], the players are still replicated by
+/// lightyear. Needs `predict_all`
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BallSnapshots {
+    /// Bandwidth each client gets for the ball snapshots, the most urgent balls are sent first
+    pub(crate) bandwidth_kbps: u32,
+}
+
+/// How often the server sends the state of each kind of entity, 0 to send it every tick
+///
+/// The players all share a replication group, they use the predicted rate when every client
+/// predicts them (`predict_all`), and the interpolated rate otherwise. Each client predicts its own
+/// player, so without `predict_all` both player rates must be the same. The predicted balls share
+/// the group of the players, and their rate, unless there is a bandwidth cap
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ReplicationRates {
+    pub(crate) predicted_players_ms: u64,
+    pub(crate) interpolated_players_ms: u64,
+    pub(crate) predicted_balls_ms: u64,
+    pub(crate) interpolated_balls_ms: u64,
+    /// Bytes per second sent to each client, when it is reached the entities that waited the
+    /// longest and the balls closest to any player are sent first. The priority of a ball is the
+    /// same for every client, not based on the distance to its own player
+    pub(crate) bandwidth_cap_kbps: Option<u32>,
+}
+
+impl ReplicationRates {
+    pub(crate) fn players(&self, predicted: bool) -> Duration {
+        Duration::from_millis(if predicted {
+            self.predicted_players_ms
+        } else {
+            self.interpolated_players_ms
+        })
+    }
+
+    pub(crate) fn balls(&self, predicted: bool) -> Duration {
+        Duration::from_millis(if predicted {
+            self.predicted_balls_ms
+        } else {
+            self.interpolated_balls_ms
+        })
+    }
+
+    /// Whether the group shared by the players can be sent at the rate of every client: without
+    /// `predict_all` the owner predicts the player that the others interpolate
+    pub(crate) fn player_rates_match(&self, predict_all: bool) -> bool {
+        predict_all || self.predicted_players_ms == self.interpolated_players_ms
+    }
+
+    /// Whether the predicted balls can be sent at their rate: with `predict_all` and without a
+    /// bandwidth cap they are in the group of the players, sent at the predicted player rate
+    pub(crate) fn ball_rates_match(&self, predict_all: bool) -> bool {
+        !predict_all
+            || self.bandwidth_cap_kbps.is_some()
+            || self.predicted_balls_ms == self.predicted_players_ms
+    }
+
+    /// Interval of the replication systems, which must run at least as often as every entity
+    pub(crate) fn send_interval(&self) -> Duration {
+        [true, false]
+            .into_iter()
+            .flat_map(|predicted| [self.players(predicted), self.balls(predicted)])
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Bytes per second of the bandwidth cap
+    pub(crate) fn bandwidth_cap(&self) -> Option<u32> {
+        self.bandwidth_cap_kbps.map(|kbps| kbps * 1000 / 8)
+    }
+}
+
+/// The embedded assets/settings.ron, which the settings files are layered over: a file only needs
+/// the fields it changes
+pub(crate) fn embedded_settings() -> &'static MySettings {
+    static EMBEDDED: OnceLock<MySettings> = OnceLock::new();
+    // the embedded file has every field, so reading it doesn't use these defaults
+    EMBEDDED.get_or_init(|| {
+        read_settings(include_str!("../assets/settings.ron"))
+            .expect("the embedded settings are valid")
+    })
+}
+
+/// Functions returning a field of the embedded settings, for `#[serde(default = "...")]`
+macro_rules! embedded_defaults {
+    ($($name:ident: $ty:ty = $($field:ident).+;)*) => {
+        $(pub(crate) fn $name() -> $ty {
+            ToOwned::to_owned(&embedded_settings().$($field).+)
+        })*
+    };
+}
+
+embedded_defaults! {
+    embedded_common: Settings = common;
+    embedded_predict_all: bool = predict_all;
+    embedded_input_delay_ticks: u16 = input_delay_ticks;
+    embedded_correction_ticks_factor: f32 = correction_ticks_factor;
+    embedded_show_confirmed: bool = show_confirmed;
+    embedded_server: ServerSettings = common.server;
+    embedded_client: ClientSettings = common.client;
+    embedded_shared: SharedSettings = common.shared;
+    embedded_server_headless: bool = common.server.headless;
+    embedded_server_conditioner: Option<Conditioner> = common.server.conditioner;
+    embedded_server_transport: Vec<ServerTransports> = common.server.transport;
+    embedded_client_port: u16 = common.client.client_port;
+    embedded_server_addr: Ipv4Addr = common.client.server_addr;
+    embedded_server_port: u16 = common.client.server_port;
+    embedded_client_transport: ClientTransports = common.client.transport;
+    embedded_client_conditioner: Option<Conditioner> = common.client.conditioner;
+    embedded_protocol_id: u64 = common.shared.protocol_id;
+    embedded_private_key: [u8; 32] = common.shared.private_key;
+    embedded_compression: CompressionConfig = common.shared.compression;
+}
+
+// the fields added after the first settings files default to the behaviour from before them, so
+// that those files still load the same
+
+pub(crate) fn default_true() -> bool {
+    true
+}
+
+fn default_max_clients() -> usize {
+    16
+}
+
+fn default_level() -> String {
+    "arena".into()
+}
+
+fn default_reconnect_grace_ms() -> u64 {
+    10_000
+}
+
+/// Connections accepted on top of `max_clients` by the transports that limit them, for spectators
+pub(crate) const MAX_SPECTATORS: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct ServerSettings {
+    #[serde(default = "embedded_server_headless")]
     pub(crate) headless: bool,
+    /// Maximum number of players, spectators are not counted
+    #[serde(default = "default_max_clients")]
+    pub(crate) max_clients: usize,
+    /// Name of a built-in level from assets/levels, or path to a RON or JSON level file
+    #[serde(default = "default_level")]
+    pub(crate) level: String,
+    #[serde(default)]
+    pub(crate) mode: GameMode,
+    /// Split the players in two teams, always done in the soccer mode
+    #[serde(default)]
+    pub(crate) teams: bool,
+    /// Whether players of the same team collide with each other
+    #[serde(default = "default_true")]
+    pub(crate) friendly_collisions: bool,
+    /// Only replicate to each player the entities around it, everything is replicated when `None`
+    #[serde(default)]
+    pub(crate) interest: Option<Interest>,
+    /// Replicate the balls through compact snapshots instead of their full state every tick
+    #[serde(default)]
+    pub(crate) ball_snapshots: Option<BallSnapshots>,
+    /// How long a disconnected player can reconnect and get its body back
+    #[serde(default = "default_reconnect_grace_ms")]
+    pub(crate) reconnect_grace_ms: u64,
+    /// Port of the TCP service that issues connect tokens, the server then signs them with a
+    /// random key instead of `shared.private_key`
+    #[serde(default)]
+    pub(crate) token_port: Option<u16>,
+    #[serde(default = "embedded_server_conditioner")]
     pub(crate) conditioner: Option<Conditioner>,
+    /// Relay the packets of the UDP and in-process clients through simulated links, `conditioner`
+    /// then applies to both directions and can be changed for each client while running
+    #[serde(default)]
+    pub(crate) link_relay: bool,
+    #[serde(default = "embedded_server_transport")]
     pub transport: Vec<ServerTransports>,
+    /// Used by the WebTransport transports
+    #[serde(default)]
+    pub(crate) certificate: Certificate,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct ClientSettings {
-    pub(crate) client_id: u64,
+    #[serde(default)]
+    pub(crate) headless: bool,
+    /// Assigned by the server's token service, or picked at random without one, if unset
+    #[serde(default)]
+    pub(crate) client_id: Option<u64>,
+    #[serde(default = "embedded_client_port")]
     pub(crate) client_port: u16,
+    #[serde(default = "embedded_server_addr")]
     pub server_addr: Ipv4Addr,
+    #[serde(default = "embedded_server_port")]
     pub server_port: u16,
+    #[serde(default = "embedded_client_transport")]
     pub(crate) transport: ClientTransports,
+    /// Port of the server's token service, the client builds its own token from
+    /// `shared.private_key` if unset
+    #[serde(default)]
+    pub(crate) token_port: Option<u16>,
+    #[serde(default = "embedded_client_conditioner")]
     pub(crate) conditioner: Option<Conditioner>,
+    /// Connect without spawning a player
+    #[serde(default)]
+    pub(crate) spectator: bool,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct SharedSettings {
+    #[serde(default = "embedded_protocol_id")]
     pub protocol_id: u64,
+    #[serde(default = "embedded_private_key")]
     pub private_key: [u8; 32],
+    #[serde(default = "embedded_compression")]
     pub(crate) compression: CompressionConfig,
 }
 
 #[derive(Resource, Debug, Clone, Deserialize, Serialize)]
 pub struct Settings {
+    #[serde(default = "embedded_server")]
     pub server: ServerSettings,
+    #[serde(default = "embedded_client")]
     pub client: ClientSettings,
+    #[serde(default = "embedded_shared")]
     pub shared: SharedSettings,
 }
 
@@ -91,18 +522,17 @@ pub struct Settings {
 pub(crate) fn build_server_netcode_config(
     conditioner: Option<&Conditioner>,
     shared: &SharedSettings,
+    auth: &ServerAuth,
     transport_config: server::ServerTransport,
 ) -> server::NetConfig {
-    let conditioner = conditioner.map_or(None, |c| {
-        Some(LinkConditionerConfig {
-            incoming_latency: Duration::from_millis(c.latency_ms as u64),
-            incoming_jitter: Duration::from_millis(c.jitter_ms as u64),
-            incoming_loss: c.packet_loss,
-        })
+    let conditioner = conditioner.map(|c| LinkConditionerConfig {
+        incoming_latency: Duration::from_millis(c.latency_ms as u64),
+        incoming_jitter: Duration::from_millis(c.jitter_ms as u64),
+        incoming_loss: c.packet_loss,
     });
     let netcode_config = server::NetcodeConfig::default()
         .with_protocol_id(shared.protocol_id)
-        .with_key(shared.private_key);
+        .with_key(auth.private_key);
     let io_config = server::IoConfig {
         transport: transport_config,
         conditioner,
@@ -114,26 +544,56 @@ pub(crate) fn build_server_netcode_config(
     }
 }
 
-pub(crate) fn get_server_net_configs(settings: &Settings) -> Vec<server::NetConfig> {
+pub(crate) fn get_server_net_configs(
+    settings: &Settings,
+    auth: &ServerAuth,
+) -> Vec<server::NetConfig> {
+    let netcode_config = |transport| {
+        build_server_netcode_config(
+            settings.server.conditioner.as_ref(),
+            &settings.shared,
+            auth,
+            transport,
+        )
+    };
     settings
         .server
         .transport
         .iter()
-        .map(|t| match t {
-            ServerTransports::Udp { local_port } => build_server_netcode_config(
-                settings.server.conditioner.as_ref(),
-                &settings.shared,
+        .filter_map(|t| match t {
+            ServerTransports::Udp { local_port } => Some(netcode_config(
                 server::ServerTransport::UdpSocket(SocketAddr::new(
                     Ipv4Addr::UNSPECIFIED.into(),
                     *local_port,
                 )),
-            ),
+            )),
+            #[cfg(not(target_family = "wasm"))]
+            ServerTransports::WebTransport { local_port } => {
+                let certificate = match settings.server.certificate.load() {
+                    Ok(certificate) => certificate,
+                    Err(e) => {
+                        error!("Not listening for WebTransport on port {local_port}: {e}");
+                        return None;
+                    }
+                };
+                Some(netcode_config(server::ServerTransport::WebTransportServer {
+                    server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *local_port),
+                    certificate,
+                }))
+            }
+            #[cfg(not(target_family = "wasm"))]
+            ServerTransports::WebSocket { local_port } => {
+                Some(netcode_config(server::ServerTransport::WebSocketServer {
+                    server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *local_port),
+                }))
+            }
+            #[cfg(not(target_family = "wasm"))]
             ServerTransports::Steam {
                 app_id,
                 server_ip,
                 game_port,
                 query_port,
-            } => server::NetConfig::Steam {
+            } => Some(server::NetConfig::Steam {
                 steamworks_client: None,
                 config: server::SteamConfig {
                     app_id: *app_id,
@@ -142,32 +602,75 @@ pub(crate) fn get_server_net_configs(settings: &Settings) -> Vec<server::NetConf
                         game_port: *game_port,
                         query_port: *query_port,
                     },
-                    max_clients: 16,
+                    max_clients: settings.server.max_clients + MAX_SPECTATORS,
                     ..default()
                 },
                 conditioner: settings
                     .server
                     .conditioner
                     .as_ref()
-                    .map_or(None, |c| Some(c.build())),
-            },
+                    .map(|c| c.build()),
+            }),
+            #[cfg(target_family = "wasm")]
+            transport => {
+                error!("{transport:?} is not available in the browser");
+                None
+            }
+        })
+        .map(|mut config| {
+            config.set_connection_request_handler(Arc::new(auth.registry.clone()));
+            config
         })
         .collect()
 }
 
+/// Hex SHA-256 digest of the leaf certificate, for `ClientTransports::WebTransport`
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn certificate_digest(identity: &server::Identity) -> String {
+    identity
+        .certificate_chain()
+        .as_slice()
+        .first()
+        .map(|certificate| {
+            certificate
+                .hash()
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Port the netcode server listens on with UDP, written in the connect tokens
+pub(crate) fn game_port(settings: &ServerSettings) -> u16 {
+    settings
+        .transport
+        .iter()
+        .find_map(|t| match t {
+            ServerTransports::Udp { local_port } => Some(*local_port),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
 pub(crate) fn build_client_netcode_config(
     client_id: u64,
     server_addr: SocketAddr,
-    conditioner: Option<&Conditioner>,
+    settings: &ClientSettings,
     shared: &SharedSettings,
     transport_config: client::ClientTransport,
 ) -> client::NetConfig {
-    let conditioner = conditioner.map_or(None, |c| Some(c.build()));
-    let auth = Authentication::Manual {
-        server_addr,
-        client_id,
-        private_key: shared.private_key,
-        protocol_id: shared.protocol_id,
+    let conditioner = settings.conditioner.as_ref().map(|c| c.build());
+    // the token is fetched from the token service before connecting
+    let auth = match settings.token_port {
+        Some(_) => Authentication::None,
+        None => Authentication::Manual {
+            server_addr,
+            client_id,
+            private_key: shared.private_key,
+            protocol_id: shared.protocol_id,
+        },
     };
     let netcode_config = client::NetcodeConfig::default();
     let io_config = client::IoConfig {
@@ -188,14 +691,30 @@ pub fn get_client_net_config(settings: &Settings, client_id: u64, server_ip: IpA
         settings.client.server_port,
     );
     let client_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), settings.client.client_port);
-    match &settings.client.transport {
-        ClientTransports::Udp => build_client_netcode_config(
+    let netcode_config = |transport| {
+        build_client_netcode_config(
             client_id,
             server_addr,
-            settings.client.conditioner.as_ref(),
+            &settings.client,
             &settings.shared,
-            client::ClientTransport::UdpSocket(client_addr),
-        ),
+            transport,
+        )
+    };
+    match &settings.client.transport {
+        #[cfg(not(target_family = "wasm"))]
+        ClientTransports::Udp => netcode_config(client::ClientTransport::UdpSocket(client_addr)),
+        ClientTransports::WebTransport { certificate_digest } => {
+            netcode_config(client::ClientTransport::WebTransportClient {
+                client_addr,
+                server_addr,
+                #[cfg(target_family = "wasm")]
+                certificate_digest: certificate_digest.clone(),
+            })
+        }
+        ClientTransports::WebSocket => {
+            netcode_config(client::ClientTransport::WebSocketClient { server_addr })
+        }
+        #[cfg(not(target_family = "wasm"))]
         ClientTransports::Steam { app_id } => client::NetConfig::Steam {
             steamworks_client: None,
             config: SteamConfig {
@@ -206,7 +725,12 @@ pub fn get_client_net_config(settings: &Settings, client_id: u64, server_ip: IpA
                 .server
                 .conditioner
                 .as_ref()
-                .map_or(None, |c| Some(c.build())),
+                .map(|c| c.build()),
         },
+        #[cfg(target_family = "wasm")]
+        transport => {
+            error!("{transport:?} is not available in the browser, use WebTransport or WebSocket");
+            netcode_config(client::ClientTransport::Dummy)
+        }
     }
 }
@@ -1,20 +1,20 @@
 use avian2d::prelude::*;
 use bevy::color::palettes::css;
-use bevy::diagnostic::LogDiagnosticsPlugin;
+use bevy::ecs::query::QueryFilter;
 use bevy::prelude::*;
+use bevy::render::camera::ScalingMode;
 use bevy::render::RenderPlugin;
 use bevy::utils::Duration;
 use leafwing_input_manager::prelude::ActionState;
 
-use lightyear::client::prediction::diagnostics::PredictionDiagnosticsPlugin;
 use lightyear::prelude::client::*;
 use lightyear::prelude::TickManager;
 use lightyear::prelude::*;
-use lightyear::transport::io::IoDiagnosticsPlugin;
 
+use crate::determinism::DeterminismPlugin;
+use crate::level::{Level, Obstacle};
 use crate::protocol::*;
 const MAX_VELOCITY: f32 = 200.0;
-const WALL_SIZE: f32 = 350.0;
 
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum FixedSet {
@@ -27,76 +27,88 @@ pub enum FixedSet {
 #[derive(Clone)]
 pub struct SharedPlugin {
     pub(crate) show_confirmed: bool,
+    pub(crate) deterministic: bool,
 }
 
+/// Whether to draw the confirmed state of predicted players
+#[derive(Resource)]
+pub struct ShowConfirmed(pub(crate) bool);
+
 impl Plugin for SharedPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(ProtocolPlugin);
+        app.add_plugins(ProtocolPlugin)
+            .insert_resource(ShowConfirmed(self.show_confirmed));
         if app.is_plugin_added::<RenderPlugin>() {
-            app.add_systems(Startup, init_camera);
+            app.add_systems(Startup, init_camera)
+                .add_systems(Update, fit_camera.run_if(resource_exists_and_changed::<Level>));
 
             app.add_systems(
                 PostUpdate,
-                (draw_elements, draw_confirmed_shadows)
+                (
+                    draw_elements,
+                    draw_confirmed_shadows.run_if(|show: Res<ShowConfirmed>| show.0),
+                )
                     .after(InterpolationSet::Interpolate)
                     .after(PredictionSet::VisualCorrection),
             );
         }
 
-        app.add_systems(Startup, init);
-
-        app.add_plugins(PhysicsPlugins::new(FixedUpdate))
-            .insert_resource(Time::new_with(Physics::fixed_once_hz(FIXED_TIMESTEP_HZ)))
-            .insert_resource(Gravity(Vec2::ZERO));
-        app.configure_sets(
-            FixedUpdate,
-            (
-                (
-                    PhysicsSet::Prepare,
-                    PhysicsSet::StepSimulation,
-                    PhysicsSet::Sync,
-                )
-                    .in_set(FixedSet::Physics),
-                (FixedSet::Main, FixedSet::Physics).chain(),
-            ),
-        );
+        add_physics_plugins(app);
+        if self.deterministic {
+            app.add_plugins(DeterminismPlugin);
+        }
 
         app.register_type::<PlayerId>();
     }
 }
 
+/// Steps the physics once per fixed update, after the [`FixedSet::Main`] systems
+pub(crate) fn add_physics_plugins(app: &mut App) {
+    app.add_plugins(PhysicsPlugins::new(FixedUpdate))
+        .insert_resource(Time::new_with(Physics::fixed_once_hz(FIXED_TIMESTEP_HZ)))
+        .insert_resource(Gravity(Vec2::ZERO));
+    app.configure_sets(
+        FixedUpdate,
+        (
+            (
+                PhysicsSet::Prepare,
+                PhysicsSet::StepSimulation,
+                PhysicsSet::Sync,
+            )
+                .in_set(FixedSet::Physics),
+            (FixedSet::Main, FixedSet::Physics).chain(),
+        ),
+    );
+}
+
+/// Hues are spread by the golden ratio, so that consecutive ids are far apart and any id has one
 pub(crate) fn color_from_id(client_id: ClientId) -> Color {
-    let h = client_id.to_bits() as f32 * 10.0;
+    let fraction = client_id.to_bits().wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 40;
+    let h = fraction as f32 / (1u64 << 24) as f32 * 360.0;
     let s = 1.0;
     let l = 0.5;
     Color::hsl(h, s, l)
 }
 
-fn init_camera(mut commands: Commands) {
+pub(crate) fn init_camera(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
 }
 
-pub(crate) fn init(mut commands: Commands) {
-    commands.spawn(WallBundle::new(
-        Vec2::new(-WALL_SIZE, -WALL_SIZE),
-        Vec2::new(-WALL_SIZE, WALL_SIZE),
-        Color::WHITE,
-    ));
-    commands.spawn(WallBundle::new(
-        Vec2::new(-WALL_SIZE, WALL_SIZE),
-        Vec2::new(WALL_SIZE, WALL_SIZE),
-        Color::WHITE,
-    ));
-    commands.spawn(WallBundle::new(
-        Vec2::new(WALL_SIZE, WALL_SIZE),
-        Vec2::new(WALL_SIZE, -WALL_SIZE),
-        Color::WHITE,
-    ));
-    commands.spawn(WallBundle::new(
-        Vec2::new(WALL_SIZE, -WALL_SIZE),
-        Vec2::new(-WALL_SIZE, -WALL_SIZE),
-        Color::WHITE,
-    ));
+/// Shows the whole level, with a margin
+pub(crate) fn fit_camera(
+    level: Res<Level>,
+    mut cameras: Query<(&mut Transform, &mut OrthographicProjection), With<Camera2d>>,
+) {
+    const MARGIN: f32 = 20.0;
+
+    let size = level.bounds.size() + 2.0 * MARGIN;
+    for (mut transform, mut projection) in cameras.iter_mut() {
+        transform.translation = level.bounds.center().extend(transform.translation.z);
+        projection.scaling_mode = ScalingMode::AutoMin {
+            min_width: size.x,
+            min_height: size.y,
+        };
+    }
 }
 
 pub(crate) fn shared_movement_behaviour(
@@ -104,18 +116,101 @@ pub(crate) fn shared_movement_behaviour(
     action: &ActionState<PlayerActions>,
 ) {
     const MOVE_SPEED: f32 = 10.0;
+    // faster than MAX_VELOCITY after a dash, the speed decays back instead of being cut
+    const OVERSPEED_DECAY: f32 = 0.95;
 
+    let max_velocity = MAX_VELOCITY.max(velocity.length() * OVERSPEED_DECAY);
     let axis_pair = action.axis_pair(&PlayerActions::Move).expect("Could not get Move action");
-    velocity.0 += axis_pair.xy() * MOVE_SPEED;
+    // the stick gives partial speed, clients can't send more than full speed
+    velocity.0 += axis_pair.xy().clamp_length_max(1.0) * MOVE_SPEED;
+
+    *velocity = LinearVelocity(velocity.clamp_length_max(max_velocity));
+}
+
+pub(crate) const DASH_SPEED: f32 = 300.0;
+pub(crate) const DASH_COOLDOWN: u16 = 64;
+/// Linear damping while braking, like avian's `LinearDamping`
+pub(crate) const BRAKE_DAMPING: f32 = 12.0;
+pub(crate) const BRAKE_TICKS: u16 = 16;
+pub(crate) const BRAKE_COOLDOWN: u16 = 48;
+/// Largest distance between the centers of the player and a ball it kicks
+pub(crate) const KICK_RANGE: f32 = PLAYER_SIZE + BALL_SIZE;
+pub(crate) const KICK_SPEED: f32 = 400.0;
+pub(crate) const KICK_COOLDOWN: u16 = 32;
+
+/// Largest angular velocity the player turns at while aiming, in radians per second
+pub(crate) const AIM_MAX_SPEED: f32 = 12.0;
+/// Largest change of the angular velocity per tick while aiming, the torque limit over the inertia
+pub(crate) const AIM_MAX_ACCELERATION: f32 = 1.5;
+/// Angular velocity per radian left to turn
+const AIM_STIFFNESS: f32 = 10.0;
+
+/// Turns the player toward its aim, through its angular velocity so that it is predicted and
+/// collisions can still spin it
+pub(crate) fn shared_aim_behaviour(
+    action: &ActionState<PlayerActions>,
+    rotation: &Rotation,
+    angular_velocity: &mut AngularVelocity,
+) {
+    if !action.pressed(&PlayerActions::Aim) {
+        return;
+    }
+    let Some(aim) = action
+        .axis_pair(&PlayerActions::Aim)
+        .and_then(|axis_pair| axis_pair.xy().try_normalize())
+    else {
+        return;
+    };
+    let error = Vec2::from_angle(rotation.as_radians()).angle_between(aim);
+    let target = (error * AIM_STIFFNESS).clamp(-AIM_MAX_SPEED, AIM_MAX_SPEED);
+    angular_velocity.0 +=
+        (target - angular_velocity.0).clamp(-AIM_MAX_ACCELERATION, AIM_MAX_ACCELERATION);
+}
+
+/// Counts the cooldowns down, then uses the dash, brake and kick actions whose cooldown is over
+pub(crate) fn shared_action_behaviour<F: QueryFilter>(
+    action: &ActionState<PlayerActions>,
+    position: &Position,
+    rotation: &Rotation,
+    velocity: &mut LinearVelocity,
+    cooldowns: &mut Cooldowns,
+    balls: &mut Query<(&Position, &mut LinearVelocity), F>,
+) {
+    cooldowns.dash = cooldowns.dash.saturating_sub(1);
+    cooldowns.brake = cooldowns.brake.saturating_sub(1);
+    cooldowns.kick = cooldowns.kick.saturating_sub(1);
+
+    if action.pressed(&PlayerActions::Dash) && cooldowns.dash == 0 {
+        velocity.0 += Vec2::from_angle(rotation.as_radians()) * DASH_SPEED;
+        cooldowns.dash = DASH_COOLDOWN;
+    }
 
-    *velocity = LinearVelocity(velocity.clamp_length_max(MAX_VELOCITY));
+    if action.pressed(&PlayerActions::Brake) && cooldowns.brake == 0 {
+        cooldowns.brake = BRAKE_COOLDOWN;
+    }
+    if cooldowns.brake > BRAKE_COOLDOWN - BRAKE_TICKS {
+        velocity.0 /= 1.0 + BRAKE_DAMPING / FIXED_TIMESTEP_HZ as f32;
+    }
+
+    if action.pressed(&PlayerActions::Kick) && cooldowns.kick == 0 {
+        for (ball_position, mut ball_velocity) in balls.iter_mut() {
+            let offset = ball_position.0 - position.0;
+            if offset.length() <= KICK_RANGE {
+                ball_velocity.0 += offset.normalize_or_zero() * KICK_SPEED;
+            }
+        }
+        cooldowns.kick = KICK_COOLDOWN;
+    }
 }
 
+#[allow(clippy::type_complexity)]
 pub(crate) fn draw_elements(
     mut gizmos: Gizmos,
     players: Query<(&Position, &Rotation, &ColorComponent), (Without<Confirmed>, With<PlayerId>)>,
     balls: Query<(&Position, &ColorComponent), (Without<Confirmed>, With<BallMarker>)>,
     walls: Query<(&Wall, &ColorComponent), (Without<BallMarker>, Without<PlayerId>)>,
+    obstacles: Query<&Obstacle>,
+    level: Option<Res<Level>>,
 ) {
     for (position, rotation, color) in &players {
         gizmos.rect_2d(
@@ -124,6 +219,9 @@ pub(crate) fn draw_elements(
             Vec2::ONE * PLAYER_SIZE,
             color.0,
         );
+        // facing indicator, from the center to the middle of the front side
+        let facing = Vec2::from_angle(rotation.as_radians()) * PLAYER_SIZE / 2.0;
+        gizmos.line_2d(position.0, position.0 + facing, color.0);
     }
     for (position, color) in &balls {
         gizmos.circle_2d(Vec2::new(position.x, position.y), BALL_SIZE, color.0);
@@ -131,6 +229,19 @@ pub(crate) fn draw_elements(
     for (wall, color) in &walls {
         gizmos.line_2d(wall.start, wall.end, color.0);
     }
+    for obstacle in &obstacles {
+        match obstacle {
+            Obstacle::Circle { center, radius } => {
+                gizmos.circle_2d(*center, *radius, Color::WHITE);
+            }
+            Obstacle::Rectangle { center, size } => {
+                gizmos.rect_2d(*center, 0.0, *size, Color::WHITE);
+            }
+        }
+    }
+    for goal in level.iter().flat_map(|level| &level.goals) {
+        gizmos.rect_2d(goal.area.center(), 0.0, goal.area.size(), goal.team.color());
+    }
 }
 
 pub(crate) fn draw_confirmed_shadows(
@@ -194,3 +305,22 @@ pub fn shared_config(mode: Mode) -> SharedConfig {
         mode,
     }
 }
+
+/// Xorshift generator, for randomness that is reproducible from a seed
+#[derive(Clone, Debug)]
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> Self {
+        // a zero state would stay zero
+        Self(seed | 1)
+    }
+
+    /// Uniform in [0, 1)
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
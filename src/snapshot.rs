@@ -0,0 +1,574 @@
+//! Compact replication of the balls, for levels with thousands of them
+//!
+//! The balls are spawned by lightyear with their full state, their physics components are then
+//! sent in [`BallSnapshot`]s: quantized, delta-encoded against the last state the client
+//! acknowledged, and only for the balls that changed since. Each client gets a bandwidth budget,
+//! filled with the balls that are closest to its player and moving the fastest first.
+//!
+//! The server and the clients snap the balls to the quantization grid after every physics step,
+//! so that the predicted balls can match the snapshots exactly
+//!
+//! Only the balls are covered: the players keep lightyear's full-state replication. The snapshots
+//! are applied to the balls the clients predict, so they need `predict_all`
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::client::{Confirmed, NetworkingState, PredictionSet};
+use lightyear::prelude::server::{is_started, ConnectEvent};
+use lightyear::prelude::*;
+use lightyear::shared::run_conditions::is_mode_separate;
+
+use crate::interest::AreaOfInterest;
+use crate::protocol::*;
+use crate::server::Lobby;
+use crate::shared::{FixedSet, FIXED_TIMESTEP_HZ};
+
+pub(crate) const POSITION_STEP: f32 = 1.0 / 64.0;
+pub(crate) const VELOCITY_STEP: f32 = 1.0 / 64.0;
+pub(crate) const ANGULAR_VELOCITY_STEP: f32 = 1.0 / 1024.0;
+/// Number of snapshots the states can be delta-encoded against
+const HISTORY: u32 = 64;
+/// Speed at which a ball is twice as urgent as the same ball at rest
+const PRIORITY_SPEED: f32 = 100.0;
+/// Distance from the player at which a ball is half as urgent as one next to it
+const PRIORITY_DISTANCE: f32 = 300.0;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum SnapshotError {
+    #[error("the snapshot ends in the middle of a ball")]
+    Truncated,
+}
+
+/// State of a body on the quantization grid
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct QuantizedBody {
+    pub(crate) position: IVec2,
+    /// Fraction of a turn, in 65536ths
+    pub(crate) rotation: u16,
+    pub(crate) linear_velocity: IVec2,
+    pub(crate) angular_velocity: i32,
+}
+
+impl QuantizedBody {
+    pub(crate) fn new(
+        position: &Position,
+        rotation: &Rotation,
+        linear_velocity: &LinearVelocity,
+        angular_velocity: &AngularVelocity,
+    ) -> Self {
+        let turns = (rotation.as_radians() / TAU).rem_euclid(1.0);
+        Self {
+            position: (position.0 / POSITION_STEP).round().as_ivec2(),
+            // a full turn wraps to 0
+            rotation: (turns * 65536.0).round() as u32 as u16,
+            linear_velocity: (linear_velocity.0 / VELOCITY_STEP).round().as_ivec2(),
+            angular_velocity: (angular_velocity.0 / ANGULAR_VELOCITY_STEP).round() as i32,
+        }
+    }
+
+    pub(crate) fn position(&self) -> Position {
+        Position(self.position.as_vec2() * POSITION_STEP)
+    }
+
+    pub(crate) fn rotation(&self) -> Rotation {
+        Rotation::radians(self.rotation as f32 / 65536.0 * TAU)
+    }
+
+    pub(crate) fn linear_velocity(&self) -> LinearVelocity {
+        LinearVelocity(self.linear_velocity.as_vec2() * VELOCITY_STEP)
+    }
+
+    pub(crate) fn angular_velocity(&self) -> AngularVelocity {
+        AngularVelocity(self.angular_velocity as f32 * ANGULAR_VELOCITY_STEP)
+    }
+
+    /// Differences with `baseline`, the rotation wraps around
+    fn deltas(&self, baseline: &QuantizedBody) -> [i64; 6] {
+        [
+            self.position.x as i64 - baseline.position.x as i64,
+            self.position.y as i64 - baseline.position.y as i64,
+            self.rotation.wrapping_sub(baseline.rotation) as i16 as i64,
+            self.linear_velocity.x as i64 - baseline.linear_velocity.x as i64,
+            self.linear_velocity.y as i64 - baseline.linear_velocity.y as i64,
+            self.angular_velocity as i64 - baseline.angular_velocity as i64,
+        ]
+    }
+
+    fn apply_deltas(&self, deltas: [i64; 6]) -> QuantizedBody {
+        QuantizedBody {
+            position: IVec2::new(
+                (self.position.x as i64 + deltas[0]) as i32,
+                (self.position.y as i64 + deltas[1]) as i32,
+            ),
+            rotation: self.rotation.wrapping_add(deltas[2] as u16),
+            linear_velocity: IVec2::new(
+                (self.linear_velocity.x as i64 + deltas[3]) as i32,
+                (self.linear_velocity.y as i64 + deltas[4]) as i32,
+            ),
+            angular_velocity: (self.angular_velocity as i64 + deltas[5]) as i32,
+        }
+    }
+}
+
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, SnapshotError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(SnapshotError::Truncated)?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(SnapshotError::Truncated)
+}
+
+/// Small values of either sign take few bytes as varints
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// A ball is a mask of the fields that changed since its baseline, followed by their deltas
+fn write_body(body: &QuantizedBody, baseline: &QuantizedBody, out: &mut Vec<u8>) {
+    let deltas = body.deltas(baseline);
+    let mask = deltas
+        .iter()
+        .enumerate()
+        .filter(|(_, delta)| **delta != 0)
+        .fold(0u8, |mask, (index, _)| mask | 1 << index);
+    out.push(mask);
+    for delta in deltas.into_iter().filter(|delta| *delta != 0) {
+        write_varint(zigzag(delta), out);
+    }
+}
+
+fn read_body(baseline: &QuantizedBody, data: &mut &[u8]) -> Result<QuantizedBody, SnapshotError> {
+    let (&mask, rest) = data.split_first().ok_or(SnapshotError::Truncated)?;
+    *data = rest;
+    let mut deltas = [0; 6];
+    for (index, delta) in deltas.iter_mut().enumerate() {
+        if mask & 1 << index != 0 {
+            *delta = unzigzag(read_varint(data)?);
+        }
+    }
+    Ok(baseline.apply_deltas(deltas))
+}
+
+/// A ball the server can send to a client
+pub(crate) struct SnapshotBody {
+    pub(crate) id: u32,
+    pub(crate) state: QuantizedBody,
+    /// Distance from the player of the client
+    pub(crate) distance: f32,
+}
+
+/// What the server sent to a client, and which of it the client acknowledged
+#[derive(Default)]
+pub(crate) struct ClientSnapshots {
+    next_sequence: u32,
+    /// States sent in the last [`HISTORY`] snapshots
+    sent: VecDeque<(u32, Vec<(u32, QuantizedBody)>)>,
+    /// Latest state of each ball the client received, and in which snapshot
+    acked: HashMap<u32, (u32, QuantizedBody)>,
+    /// Accumulated urgency of the balls that changed since the client received them
+    priorities: HashMap<u32, f32>,
+}
+
+impl ClientSnapshots {
+    /// Encodes the `bodies` that changed since the state the client acknowledged, the most urgent
+    /// first and within `budget` bytes. Returns the sequence of the snapshot and its data, or
+    /// `None` when there is nothing to send
+    pub(crate) fn build(
+        &mut self,
+        bodies: &[SnapshotBody],
+        budget: usize,
+    ) -> Option<(u32, Vec<u8>)> {
+        let sequence = self.next_sequence;
+        let baseline = |id: u32| {
+            self.acked
+                .get(&id)
+                .filter(|(acked, _)| sequence - acked < HISTORY)
+                .copied()
+        };
+        // bodies at rest, sleeping ones included, are not resent once the client has them
+        let mut candidates: Vec<_> = bodies
+            .iter()
+            .filter(|body| baseline(body.id).map_or(true, |(_, state)| state != body.state))
+            .collect();
+        for body in &candidates {
+            let speed = body.state.linear_velocity().length();
+            let urgency =
+                (1.0 + speed / PRIORITY_SPEED) / (1.0 + body.distance / PRIORITY_DISTANCE);
+            *self.priorities.entry(body.id).or_default() += urgency;
+        }
+        candidates.sort_by(|a, b| self.priorities[&b.id].total_cmp(&self.priorities[&a.id]));
+
+        // the ids are delta-encoded too, the size with the full id is an upper bound
+        let mut size = 0;
+        let mut entry = vec![];
+        let mut selected = vec![];
+        for body in candidates {
+            entry.clear();
+            write_varint(body.id as u64, &mut entry);
+            write_varint(HISTORY as u64, &mut entry);
+            let (_, state) = baseline(body.id).unwrap_or_default();
+            write_body(&body.state, &state, &mut entry);
+            if size + entry.len() > budget {
+                break;
+            }
+            size += entry.len();
+            selected.push((body.id, body.state));
+        }
+        if selected.is_empty() {
+            return None;
+        }
+
+        selected.sort_by_key(|(id, _)| *id);
+        let mut data = Vec::with_capacity(size);
+        let mut previous = 0;
+        for (id, state) in &selected {
+            self.priorities.remove(id);
+            write_varint((id - previous) as u64, &mut data);
+            previous = *id;
+            // 0 marks a state that is not delta-encoded
+            let (age, baseline) =
+                baseline(*id).map_or((0, default()), |(acked, state)| (sequence - acked, state));
+            write_varint(age as u64, &mut data);
+            write_body(state, &baseline, &mut data);
+        }
+        self.sent.push_back((sequence, selected));
+        if self.sent.len() > HISTORY as usize {
+            self.sent.pop_front();
+        }
+        self.next_sequence += 1;
+        Some((sequence, data))
+    }
+
+    /// The client received the snapshot `sequence`, its states can be used as baselines
+    pub(crate) fn ack(&mut self, sequence: u32) {
+        let Some((_, states)) = self.sent.iter().find(|(sent, _)| *sent == sequence) else {
+            return;
+        };
+        for (id, state) in states {
+            match self.acked.get(id) {
+                Some((acked, _)) if *acked > sequence => {}
+                _ => {
+                    self.acked.insert(*id, (sequence, *state));
+                }
+            }
+        }
+    }
+}
+
+/// States of the balls received by a client, which the next snapshots are delta-encoded against.
+/// The sequences start over with each connection, so the history is reset when the client connects
+#[derive(Resource, Default)]
+pub(crate) struct SnapshotHistory {
+    states: HashMap<u32, VecDeque<(u32, QuantizedBody)>>,
+}
+
+/// The balls of a snapshot the client could decode
+#[derive(Debug, Default)]
+pub(crate) struct DecodedSnapshot {
+    pub(crate) bodies: Vec<(u32, QuantizedBody)>,
+    /// Number of balls encoded against a state this client doesn't have
+    pub(crate) skipped: usize,
+}
+
+impl SnapshotHistory {
+    /// States of the balls in the snapshot, the ones encoded against a state this client doesn't
+    /// have are skipped
+    pub(crate) fn decode(
+        &mut self,
+        sequence: u32,
+        mut data: &[u8],
+    ) -> Result<DecodedSnapshot, SnapshotError> {
+        let mut bodies = vec![];
+        let mut skipped = 0;
+        let mut id = 0;
+        while !data.is_empty() {
+            id += read_varint(&mut data)? as u32;
+            let age = read_varint(&mut data)? as u32;
+            let baseline = if age == 0 {
+                Some(QuantizedBody::default())
+            } else {
+                self.states
+                    .get(&id)
+                    .and_then(|states| {
+                        let baseline = sequence.wrapping_sub(age);
+                        states.iter().find(|(received, _)| *received == baseline)
+                    })
+                    .map(|(_, state)| *state)
+            };
+            let body = read_body(&baseline.unwrap_or_default(), &mut data)?;
+            if baseline.is_some() {
+                bodies.push((id, body));
+            } else {
+                skipped += 1;
+            }
+        }
+        for (id, body) in &bodies {
+            let states = self.states.entry(*id).or_default();
+            states.push_back((sequence, *body));
+            if states.len() > HISTORY as usize {
+                states.pop_front();
+            }
+        }
+        Ok(DecodedSnapshot { bodies, skipped })
+    }
+}
+
+/// Server side of the ball snapshots
+pub struct SnapshotPlugin {
+    pub(crate) bandwidth_kbps: u32,
+}
+
+#[derive(Resource)]
+struct SnapshotSender {
+    /// Bytes of snapshot each client gets per tick
+    budget: usize,
+    clients: HashMap<ClientId, ClientSnapshots>,
+}
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        let bytes_per_second = self.bandwidth_kbps as f64 * 1000.0 / 8.0;
+        app.insert_resource(SnapshotSender {
+            budget: (bytes_per_second / FIXED_TIMESTEP_HZ) as usize,
+            clients: default(),
+        })
+        .add_systems(FixedUpdate, quantize_balls.after(FixedSet::Physics))
+        .add_systems(FixedPostUpdate, send_snapshots.run_if(is_started))
+        .add_systems(
+            PreUpdate,
+            (reset_clients, receive_acks)
+                .chain()
+                .after(MainSet::EmitEvents),
+        );
+    }
+}
+
+/// Components of a ball replicated through the snapshots. It is in its own replication group, so
+/// that the updates of other entities don't confirm its stale replicated state
+pub(crate) fn snapshot_components(id: u32) -> impl Bundle {
+    (
+        BallId(id),
+        ReplicationGroup::default(),
+        ReplicateOnceComponent::<Position>::default(),
+        ReplicateOnceComponent::<Rotation>::default(),
+        ReplicateOnceComponent::<LinearVelocity>::default(),
+        ReplicateOnceComponent::<AngularVelocity>::default(),
+    )
+}
+
+/// Snaps the simulated balls to the quantization grid after each physics step
+#[allow(clippy::type_complexity)]
+fn quantize_balls(
+    mut balls: Query<
+        (
+            &mut Position,
+            &mut Rotation,
+            &mut LinearVelocity,
+            &mut AngularVelocity,
+        ),
+        (With<BallId>, Without<Confirmed>),
+    >,
+) {
+    for (mut position, mut rotation, mut linear_velocity, mut angular_velocity) in balls.iter_mut()
+    {
+        let state = QuantizedBody::new(&position, &rotation, &linear_velocity, &angular_velocity);
+        // sleeping balls stay unchanged
+        position.set_if_neq(state.position());
+        rotation.set_if_neq(state.rotation());
+        linear_velocity.set_if_neq(state.linear_velocity());
+        angular_velocity.set_if_neq(state.angular_velocity());
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn send_snapshots(
+    tick_manager: Res<TickManager>,
+    lobby: Res<Lobby>,
+    interest: Option<Res<AreaOfInterest>>,
+    mut sender: ResMut<SnapshotSender>,
+    mut connection: ResMut<server::ConnectionManager>,
+    players: Query<(&PlayerId, &Position)>,
+    balls: Query<
+        (
+            Entity,
+            &BallId,
+            &Position,
+            &Rotation,
+            &LinearVelocity,
+            &AngularVelocity,
+        ),
+        Without<Confirmed>,
+    >,
+) {
+    let states: Vec<_> = balls
+        .iter()
+        .map(
+            |(entity, id, position, rotation, linear_velocity, angular_velocity)| {
+                let state =
+                    QuantizedBody::new(position, rotation, linear_velocity, angular_velocity);
+                (entity, id.0, state)
+            },
+        )
+        .collect();
+    let SnapshotSender { budget, clients } = &mut *sender;
+    clients.retain(|client_id, _| {
+        lobby.players.contains(client_id) || lobby.spectators.contains(client_id)
+    });
+    for client_id in lobby.players.iter().chain(&lobby.spectators) {
+        let center = players
+            .iter()
+            .find(|(id, _)| id.0 == *client_id)
+            .map(|(_, position)| position.0);
+        let bodies: Vec<_> = states
+            .iter()
+            .filter(|(entity, ..)| {
+                interest
+                    .as_ref()
+                    .map_or(true, |interest| interest.is_relevant(*client_id, *entity))
+            })
+            .map(|(_, id, state)| SnapshotBody {
+                id: *id,
+                state: *state,
+                distance: center.map_or(0.0, |center| center.distance(state.position().0)),
+            })
+            .collect();
+        let client = clients.entry(*client_id).or_default();
+        let Some((sequence, data)) = client.build(&bodies, *budget) else {
+            continue;
+        };
+        let snapshot = BallSnapshot {
+            sequence,
+            tick: tick_manager.tick(),
+            data,
+        };
+        connection
+            .send_message_to_target::<SnapshotChannel, _>(
+                &snapshot,
+                NetworkTarget::Single(*client_id),
+            )
+            .unwrap_or_else(|e| error!("Could not send the ball snapshot: {e}"));
+    }
+}
+
+/// A client that connects again starts from an empty history, so it gets new snapshots too
+fn reset_clients(mut sender: ResMut<SnapshotSender>, mut events: EventReader<ConnectEvent>) {
+    for event in events.read() {
+        sender.clients.remove(&event.client_id);
+    }
+}
+
+fn receive_acks(
+    mut sender: ResMut<SnapshotSender>,
+    mut events: EventReader<server::MessageEvent<SnapshotAck>>,
+) {
+    for event in events.read() {
+        if let Some(client) = sender.clients.get_mut(event.context()) {
+            client.ack(event.message().sequence);
+        }
+    }
+}
+
+/// Client side of the ball snapshots, does nothing unless the server sends them
+pub struct SnapshotReceiverPlugin;
+
+impl Plugin for SnapshotReceiverPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SnapshotHistory>()
+            .add_systems(OnEnter(NetworkingState::Connected), reset_history)
+            .add_systems(FixedUpdate, quantize_balls.after(FixedSet::Physics))
+            .add_systems(
+                PreUpdate,
+                receive_snapshots
+                    .run_if(is_mode_separate)
+                    .after(MainSet::EmitEvents)
+                    .before(PredictionSet::CheckRollback),
+            );
+    }
+}
+
+fn reset_history(mut history: ResMut<SnapshotHistory>) {
+    *history = default();
+}
+
+/// Writes the snapshots to the confirmed balls, the predicted ones roll back if they differ
+fn receive_snapshots(
+    mut history: ResMut<SnapshotHistory>,
+    mut connection: ResMut<client::ConnectionManager>,
+    mut events: EventReader<client::MessageEvent<BallSnapshot>>,
+    ids: Query<(Entity, &BallId), With<Confirmed>>,
+    mut balls: Query<(
+        &mut Confirmed,
+        &mut Position,
+        &mut Rotation,
+        &mut LinearVelocity,
+        &mut AngularVelocity,
+    )>,
+) {
+    let mut entities: Option<HashMap<u32, Entity>> = None;
+    for event in events.read() {
+        let snapshot = event.message();
+        let DecodedSnapshot { bodies, skipped } =
+            match history.decode(snapshot.sequence, &snapshot.data) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    warn!("Invalid ball snapshot {}: {e}", snapshot.sequence);
+                    continue;
+                }
+            };
+        // the server would delta-encode against the states of the skipped balls, which this
+        // client doesn't have, until their baselines are too old and it sends them in full
+        if skipped == 0 {
+            connection
+                .send_message::<SnapshotChannel, _>(&SnapshotAck {
+                    sequence: snapshot.sequence,
+                })
+                .unwrap_or_else(|e| error!("Could not acknowledge the ball snapshot: {e}"));
+        }
+        let entities =
+            entities.get_or_insert_with(|| ids.iter().map(|(entity, id)| (id.0, entity)).collect());
+        for (id, state) in bodies {
+            let Some(entity) = entities.get(&id) else {
+                continue;
+            };
+            let Ok((
+                mut confirmed,
+                mut position,
+                mut rotation,
+                mut linear_velocity,
+                mut angular_velocity,
+            )) = balls.get_mut(*entity)
+            else {
+                continue;
+            };
+            // snapshots can arrive out of order
+            if confirmed.tick > snapshot.tick {
+                continue;
+            }
+            confirmed.tick = snapshot.tick;
+            *position = state.position();
+            *rotation = state.rotation();
+            *linear_velocity = state.linear_velocity();
+            *angular_velocity = state.angular_velocity();
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Soccer mode: two teams push the balls into each other's goal until the match clock runs out
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::utils::Duration;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+
+use crate::level::{Goal, Level};
+use crate::protocol::*;
+use crate::server::{spawn_point, PLAYER_RADIUS};
+use crate::shared::FixedSet;
+
+/// Pause after full time before the next match starts
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Rings of kickoff points around each spawn point, for teams with more players than spawn points
+const KICKOFF_RINGS: usize = 3;
+
+/// Server side of the soccer mode, goals are only detected by the server. Clients keep predicting
+/// the balls inside the goal until the kickoff that follows the confirmed goal rolls them back
+pub struct SoccerPlugin {
+    pub(crate) match_duration: Duration,
+}
+
+#[derive(Resource)]
+struct MatchClock {
+    clock: Timer,
+    /// Started at full time
+    restart: Timer,
+}
+
+impl MatchClock {
+    fn remaining_secs(&self) -> u32 {
+        self.clock.remaining().as_secs_f32().ceil() as u32
+    }
+}
+
+/// Where a ball goes back to at kickoff
+#[derive(Component)]
+struct KickoffPosition(Vec2);
+
+/// Puts the balls back in place and the players on their team's spawn points
+#[derive(Event)]
+pub(crate) struct Kickoff;
+
+impl Plugin for SoccerPlugin {
+    fn build(&self, app: &mut App) {
+        let clock = MatchClock {
+            clock: Timer::new(self.match_duration, TimerMode::Once),
+            restart: Timer::new(RESTART_DELAY, TimerMode::Once),
+        };
+        app.insert_resource(MatchState {
+            red: 0,
+            blue: 0,
+            remaining_secs: clock.remaining_secs(),
+            over: false,
+        })
+        .insert_resource(clock)
+        .add_event::<Kickoff>()
+        .add_systems(Startup, (replicate_match_state, spawn_goals))
+        .add_systems(PostStartup, remember_kickoff_positions)
+        .add_systems(
+            FixedUpdate,
+            (detect_goals, run_clock, kickoff)
+                .chain()
+                .after(FixedSet::Physics),
+        );
+    }
+}
+
+fn replicate_match_state(mut commands: Commands) {
+    commands.replicate_resource::<MatchState, LobbyChannel>(NetworkTarget::All);
+}
+
+fn spawn_goals(mut commands: Commands, level: Res<Level>) {
+    for goal in &level.goals {
+        commands.spawn((
+            goal.clone(),
+            Collider::rectangle(goal.area.width(), goal.area.height()),
+            Sensor,
+            RigidBody::Static,
+            Position(goal.area.center()),
+            CollidingEntities::default(),
+        ));
+    }
+}
+
+fn remember_kickoff_positions(
+    mut commands: Commands,
+    balls: Query<(Entity, &Position), With<BallMarker>>,
+) {
+    for (entity, position) in balls.iter() {
+        commands.entity(entity).insert(KickoffPosition(position.0));
+    }
+}
+
+fn detect_goals(
+    mut state: ResMut<MatchState>,
+    mut connection: ResMut<ConnectionManager>,
+    mut kickoffs: EventWriter<Kickoff>,
+    goals: Query<(&Goal, &CollidingEntities)>,
+    balls: Query<(), With<BallMarker>>,
+) {
+    if state.over {
+        return;
+    }
+    let Some(goal) = goals
+        .iter()
+        .find(|(_, colliding)| colliding.iter().any(|entity| balls.contains(*entity)))
+        .map(|(goal, _)| goal)
+    else {
+        return;
+    };
+    // every ball is reset, other goals in the same tick don't count
+    let team = goal.team.other();
+    *state.score_mut(team) += 1;
+    info!("{team} scored, {} - {}", state.red, state.blue);
+    let message = GoalScored {
+        team,
+        red: state.red,
+        blue: state.blue,
+    };
+    if let Err(e) =
+        connection.send_message_to_target::<LobbyChannel, _>(&message, NetworkTarget::All)
+    {
+        error!("Could not send the goal: {e}");
+    }
+    kickoffs.send(Kickoff);
+}
+
+fn run_clock(
+    time: Res<Time>,
+    mut clock: ResMut<MatchClock>,
+    mut state: ResMut<MatchState>,
+    mut kickoffs: EventWriter<Kickoff>,
+    players: Query<(), With<Team>>,
+) {
+    if state.over {
+        if clock.restart.tick(time.delta()).just_finished() {
+            info!("Kickoff of a new match");
+            clock.clock.reset();
+            clock.restart.reset();
+            *state = MatchState {
+                red: 0,
+                blue: 0,
+                remaining_secs: clock.remaining_secs(),
+                over: false,
+            };
+            kickoffs.send(Kickoff);
+        }
+        return;
+    }
+    // the clock waits for the first player
+    if players.is_empty() {
+        return;
+    }
+    clock.clock.tick(time.delta());
+    // only changed, and replicated, once per second
+    let remaining_secs = clock.remaining_secs();
+    if state.remaining_secs != remaining_secs {
+        state.remaining_secs = remaining_secs;
+    }
+    if clock.clock.finished() {
+        info!("Full time, {} - {}", state.red, state.blue);
+        state.over = true;
+    }
+}
+
+fn kickoff(
+    mut kickoffs: EventReader<Kickoff>,
+    level: Res<Level>,
+    mut balls: Query<
+        (
+            &KickoffPosition,
+            &mut Position,
+            &mut LinearVelocity,
+            &mut AngularVelocity,
+        ),
+        Without<Team>,
+    >,
+    mut players: Query<
+        (
+            &PlayerId,
+            &Team,
+            &mut Position,
+            &mut LinearVelocity,
+            &mut AngularVelocity,
+        ),
+        Without<KickoffPosition>,
+    >,
+) {
+    if kickoffs.read().count() == 0 {
+        return;
+    }
+    for (kickoff, mut position, mut velocity, mut angular_velocity) in balls.iter_mut() {
+        position.0 = kickoff.0;
+        velocity.0 = Vec2::ZERO;
+        angular_velocity.0 = 0.0;
+    }
+    let mut occupied: Vec<_> = balls
+        .iter()
+        .map(|(kickoff, ..)| (kickoff.0, BALL_SIZE))
+        .collect();
+    let mut players: Vec<_> = players.iter_mut().collect();
+    players.sort_by_key(|(id, ..)| id.0.to_bits());
+    for team in Team::ALL {
+        let spawn_points = level.team_spawn_points(team);
+        if spawn_points.is_empty() {
+            continue;
+        }
+        let team_players: Vec<_> = players
+            .iter_mut()
+            .filter(|(_, player_team, ..)| **player_team == team)
+            .collect();
+        let positions = kickoff_positions(
+            &spawn_points,
+            level.bounds,
+            &mut occupied,
+            team_players.len(),
+        );
+        for ((_, _, position, velocity, angular_velocity), point) in
+            team_players.into_iter().zip(positions)
+        {
+            position.0 = point;
+            velocity.0 = Vec2::ZERO;
+            angular_velocity.0 = 0.0;
+        }
+    }
+}
+
+/// Where `players` players of a team go at kickoff, each on the first spawn point clear of the
+/// `occupied` bodies and of the players placed before it, which are added to `occupied`
+pub(crate) fn kickoff_positions(
+    spawn_points: &[Vec2],
+    bounds: Rect,
+    occupied: &mut Vec<(Vec2, f32)>,
+    players: usize,
+) -> Vec<Vec2> {
+    // players that don't fit on the spawn points go on rings around them
+    let spacing = 2.0 * PLAYER_RADIUS + BALL_SIZE;
+    let mut candidates = spawn_points.to_vec();
+    for ring in 1..=KICKOFF_RINGS {
+        for point in spawn_points {
+            candidates.extend((0..6).map(|index| {
+                let direction = Vec2::from_angle(index as f32 * std::f32::consts::TAU / 6.0);
+                *point + direction * spacing * ring as f32
+            }));
+        }
+    }
+    candidates.retain(|point| bounds.contains(*point));
+    (0..players)
+        .map(|_| {
+            let point = spawn_point(&candidates, occupied);
+            occupied.push((point, PLAYER_RADIUS));
+            point
+        })
+        .collect()
+}
+
+/// Shows the score and the match clock on the clients
+pub struct ScoreboardPlugin;
+
+#[derive(Component)]
+struct Scoreboard;
+
+impl Plugin for ScoreboardPlugin {
+    fn build(&self, app: &mut App) {
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(
+                Update,
+                update_scoreboard.run_if(resource_exists_and_changed::<MatchState>),
+            );
+        }
+    }
+}
+
+fn update_scoreboard(
+    mut commands: Commands,
+    state: Res<MatchState>,
+    mut scoreboards: Query<&mut Text, With<Scoreboard>>,
+) {
+    let clock = if state.over {
+        "Full time".to_string()
+    } else {
+        format!(
+            "{}:{:02}",
+            state.remaining_secs / 60,
+            state.remaining_secs % 60
+        )
+    };
+    let text = format!("Red {} - {} Blue   {clock}", state.red, state.blue);
+    if let Ok(mut scoreboard) = scoreboards.get_single_mut() {
+        scoreboard.sections[0].value = text;
+        return;
+    }
+    commands.spawn((
+        TextBundle::from_section(
+            text,
+            TextStyle {
+                font_size: 30.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(0.0),
+            left: Val::Percent(40.0),
+            ..default()
+        }),
+        Scoreboard,
+    ));
+}
@@ -0,0 +1,154 @@
+//! Camera of the spectator clients, which connect without spawning a player
+use avian2d::prelude::*;
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use lightyear::prelude::client::Interpolated;
+use lightyear::prelude::ClientId;
+
+use crate::protocol::*;
+
+const PAN_SPEED: f32 = 600.0;
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 4.0;
+
+/// Follow a player with Tab or the number keys, in the order of their ids, move the free camera with WASD or the arrows,
+/// zoom with the mouse wheel
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Spectator>();
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, spawn_status)
+                .add_systems(Update, (controls, follow_player, update_status).chain());
+        }
+    }
+}
+
+/// Present on the clients that spectate instead of playing
+#[derive(Resource, Default)]
+pub struct Spectator {
+    /// Player the camera is centered on, the camera is free if `None`
+    pub following: Option<ClientId>,
+}
+
+#[derive(Component)]
+struct SpectatorStatus;
+
+fn spawn_status(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 20.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+        SpectatorStatus,
+    ));
+}
+
+fn controls(
+    time: Res<Time>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut wheel: EventReader<MouseWheel>,
+    mut spectator: ResMut<Spectator>,
+    players: Query<&PlayerId, With<Interpolated>>,
+    mut camera: Query<(&mut Transform, &mut OrthographicProjection), With<Camera2d>>,
+) {
+    let Ok((mut transform, mut projection)) = camera.get_single_mut() else {
+        return;
+    };
+    for event in wheel.read() {
+        projection.scale = (projection.scale * 0.9f32.powf(event.y)).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    let mut ids: Vec<_> = players.iter().map(|id| id.0).collect();
+    ids.sort_by_key(|id| id.to_bits());
+    if keys.just_pressed(KeyCode::Tab) {
+        let next = spectator
+            .following
+            .and_then(|current| ids.iter().position(|id| *id == current))
+            .map_or(0, |index| index + 1);
+        spectator.following = ids.get(next % ids.len().max(1)).copied();
+    }
+    for (key, number) in [
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ]
+    .into_iter()
+    .zip(1..)
+    {
+        if keys.just_pressed(key) {
+            spectator.following = ids.get(number - 1).copied();
+        }
+    }
+    if keys.just_pressed(KeyCode::Escape) {
+        spectator.following = None;
+    }
+
+    let direction = Vec2::new(
+        axis(&keys, [KeyCode::KeyD, KeyCode::ArrowRight], [KeyCode::KeyA, KeyCode::ArrowLeft]),
+        axis(&keys, [KeyCode::KeyW, KeyCode::ArrowUp], [KeyCode::KeyS, KeyCode::ArrowDown]),
+    );
+    if direction != Vec2::ZERO {
+        spectator.following = None;
+        let offset = direction.normalize() * PAN_SPEED * projection.scale * time.delta_seconds();
+        transform.translation += offset.extend(0.0);
+    }
+}
+
+fn axis(keys: &ButtonInput<KeyCode>, positive: [KeyCode; 2], negative: [KeyCode; 2]) -> f32 {
+    let positive = keys.any_pressed(positive) as i32;
+    let negative = keys.any_pressed(negative) as i32;
+    (positive - negative) as f32
+}
+
+fn follow_player(
+    mut spectator: ResMut<Spectator>,
+    players: Query<(&PlayerId, &Position), With<Interpolated>>,
+    mut camera: Query<&mut Transform, With<Camera2d>>,
+) {
+    let Some(following) = spectator.following else {
+        return;
+    };
+    let Some((_, position)) = players.iter().find(|(id, _)| id.0 == following) else {
+        // the player left
+        spectator.following = None;
+        return;
+    };
+    for mut transform in camera.iter_mut() {
+        transform.translation = position.0.extend(transform.translation.z);
+    }
+}
+
+fn update_status(
+    spectator: Res<Spectator>,
+    mut status: Query<&mut Text, With<SpectatorStatus>>,
+) {
+    if !spectator.is_changed() {
+        return;
+    }
+    let message = match spectator.following {
+        Some(client_id) => format!("Following player {client_id}, Esc for the free camera"),
+        None => "Free camera, Tab or 1-9 to follow a player".to_string(),
+    };
+    for mut text in status.iter_mut() {
+        text.sections[0].value.clone_from(&message);
+    }
+}
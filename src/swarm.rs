@@ -0,0 +1,100 @@
+//! Runs a server and many headless clients in one process, connected with in-memory channels
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use lightyear::prelude::client::ClientTransport;
+use lightyear::prelude::server::ServerTransport;
+
+use crate::app::{client_app, server_app, Apps};
+use crate::client::MockInputSettings;
+use crate::settings::build_client_netcode_config;
+use crate::MySettings;
+
+/// Server end of a channel transport: the address of the client, and the channels from and to it
+pub(crate) type ServerChannel = (SocketAddr, Receiver<Vec<u8>>, Sender<Vec<u8>>);
+
+/// Address of the `index`th channel client, the server tells the clients apart by their address
+fn channel_address(index: usize) -> SocketAddr {
+    let ports = u16::MAX as usize;
+    // the addresses go on through 127.0.0.0/8 once the ports of 127.0.0.1 are used up
+    let ip = u32::from(Ipv4Addr::LOCALHOST) + (index / ports) as u32;
+    SocketAddr::new(Ipv4Addr::from(ip).into(), (index % ports) as u16 + 1)
+}
+
+/// Builds the `index`th client with the game plugins, connected to the server through in-memory
+/// channels
+pub(crate) fn channel_client(
+    index: usize,
+    client_id: u64,
+    settings: &MySettings,
+) -> (Apps, ServerChannel) {
+    let (to_client_send, to_client_recv) = crossbeam_channel::unbounded();
+    let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+    let client_addr = channel_address(index);
+    let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+    let net_config = build_client_netcode_config(
+        client_id,
+        server_addr,
+        &settings.common.client,
+        &settings.common.shared,
+        ClientTransport::LocalChannel {
+            recv: to_client_recv,
+            send: to_server_send,
+        },
+    );
+    let (app, config) = client_app(settings.common.clone(), net_config);
+    let mut client = Apps::Client { app, config };
+    crate::add_plugins(&mut client, settings);
+    (client, (client_addr, to_server_recv, to_client_send))
+}
+
+/// Builds a server with `clients` headless clients that move with mock input, the clients take
+/// their link conditioner in turn from `swarm_conditioners`
+pub(crate) fn local_swarm(settings: &MySettings, clients: u64) -> Apps {
+    let mut settings = settings.clone();
+    // no socket or token service is needed in the same process
+    settings.common.server.transport.clear();
+    settings.common.server.token_port = None;
+    settings.common.client.token_port = None;
+    settings.common.client.headless = true;
+    settings.common.client.spectator = false;
+
+    let mut channels = vec![];
+    let mut client_apps = vec![];
+    for (client_id, index) in (1..=clients).zip(0..) {
+        let mut client_settings = settings.clone();
+        if !settings.swarm_conditioners.is_empty() {
+            let conditioner = &settings.swarm_conditioners[index % settings.swarm_conditioners.len()];
+            client_settings.common.client.conditioner = Some(conditioner.clone());
+        }
+        let (client, channel) = channel_client(index, client_id, &client_settings);
+        let Apps::Client { mut app, .. } = client else {
+            unreachable!("channel_client builds a client");
+        };
+        app.insert_resource(MockInputSettings { enabled: true });
+        app.finish();
+        app.cleanup();
+        channels.push(channel);
+        client_apps.push(app);
+    }
+
+    let (mut app, config) = server_app(
+        settings.common.clone(),
+        vec![ServerTransport::Channels { channels }],
+    );
+    app.insert_non_send_resource(SwarmClients(client_apps))
+        .add_systems(Last, update_clients);
+    let mut server = Apps::Server { app, config };
+    crate::add_plugins(&mut server, &settings);
+    server
+}
+
+/// Clients of the local swarm, updated by the server app
+pub(crate) struct SwarmClients(pub(crate) Vec<App>);
+
+pub(crate) fn update_clients(mut clients: NonSendMut<SwarmClients>) {
+    for client in clients.0.iter_mut() {
+        client.update();
+    }
+}
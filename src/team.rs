@@ -0,0 +1,234 @@
+//! Teams of players, assigned by the server and shown to the clients in a roster
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::utils::HashMap;
+use lightyear::prelude::client::{ClientConnection, Interpolated, NetClient, Predicted};
+use lightyear::prelude::*;
+
+use crate::protocol::*;
+
+/// Server side of the teams
+pub struct TeamPlugin {
+    pub(crate) friendly_collisions: bool,
+}
+
+/// Team of every client that played, so that players who reconnect stay in theirs
+#[derive(Resource, Default)]
+pub(crate) struct Teams {
+    members: HashMap<ClientId, Team>,
+    /// Whether players of the same team collide
+    friendly_collisions: bool,
+}
+
+impl Teams {
+    pub(crate) fn new(friendly_collisions: bool) -> Self {
+        Self {
+            members: default(),
+            friendly_collisions,
+        }
+    }
+
+    /// The team the client played in, or the one with the fewest `players`
+    pub(crate) fn assign(&mut self, client_id: ClientId, players: &[ClientId]) -> Team {
+        if let Some(team) = self.members.get(&client_id) {
+            return *team;
+        }
+        let team = if self.count(Team::Blue, players) < self.count(Team::Red, players) {
+            Team::Blue
+        } else {
+            Team::Red
+        };
+        self.members.insert(client_id, team);
+        team
+    }
+
+    /// Moves the client to `team`, unless it would then have more players than the other
+    pub(crate) fn change(&mut self, client_id: ClientId, team: Team, players: &[ClientId]) -> bool {
+        let others: Vec<_> = players
+            .iter()
+            .copied()
+            .filter(|id| *id != client_id)
+            .collect();
+        if self.count(team, &others) > self.count(team.other(), &others) {
+            return false;
+        }
+        self.members.insert(client_id, team);
+        true
+    }
+
+    fn count(&self, team: Team, players: &[ClientId]) -> usize {
+        players
+            .iter()
+            .filter(|id| self.members.get(*id) == Some(&team))
+            .count()
+    }
+
+    /// Components of a player in `team`, its color and collision layers follow the team
+    pub(crate) fn components(&self, team: Team) -> (Team, ColorComponent, CollisionLayers) {
+        (
+            team,
+            ColorComponent(team.color()),
+            team_layers(team, self.friendly_collisions),
+        )
+    }
+}
+
+/// Players are on the layer of their team, and don't collide with it without friendly collisions
+pub(crate) fn team_layers(team: Team, friendly_collisions: bool) -> CollisionLayers {
+    let layer = |team| match team {
+        Team::Red => GameLayer::Red,
+        Team::Blue => GameLayer::Blue,
+    };
+    // the balls and the level are on the default layer
+    let filters = if friendly_collisions {
+        LayerMask::from([GameLayer::Default, GameLayer::Red, GameLayer::Blue])
+    } else {
+        LayerMask::from([GameLayer::Default, layer(team.other())])
+    };
+    CollisionLayers::new(layer(team), filters)
+}
+
+impl Plugin for TeamPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Teams::new(self.friendly_collisions))
+            .add_systems(PreUpdate, change_teams.after(MainSet::EmitEvents));
+    }
+}
+
+fn change_teams(
+    mut teams: ResMut<Teams>,
+    mut events: EventReader<server::MessageEvent<RequestTeam>>,
+    mut players: Query<(
+        &PlayerId,
+        &mut Team,
+        &mut ColorComponent,
+        &mut CollisionLayers,
+    )>,
+) {
+    for event in events.read() {
+        let client_id = *event.context();
+        let team = event.message().team;
+        let ids: Vec<_> = players.iter().map(|(id, ..)| id.0).collect();
+        if !teams.change(client_id, team, &ids) {
+            warn!("Client {client_id} can't join the {team} team, it has more players");
+            continue;
+        }
+        info!("Client {client_id} joined the {team} team");
+        if let Some((_, mut player_team, mut color, mut layers)) =
+            players.iter_mut().find(|(id, ..)| id.0 == client_id)
+        {
+            (*player_team, *color, *layers) = teams.components(team);
+        }
+    }
+}
+
+/// Client side of the teams: team colors, the roster, and C to change team
+pub struct RosterPlugin;
+
+#[derive(Component)]
+struct Roster;
+
+impl Plugin for RosterPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                apply_team_colors
+                    .after(crate::client::handle_predicted_spawn)
+                    .after(crate::client::handle_interpolated_spawn),
+                request_team_change,
+            ),
+        );
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Update, update_roster);
+        }
+    }
+}
+
+/// The team color replaces the player's, with the saturation that marks predicted and
+/// interpolated entities
+#[allow(clippy::type_complexity)]
+fn apply_team_colors(
+    mut players: Query<
+        (&Team, &mut ColorComponent, Has<Predicted>),
+        (Changed<Team>, Or<(With<Predicted>, With<Interpolated>)>),
+    >,
+) {
+    for (team, mut color, predicted) in players.iter_mut() {
+        let hsva = Hsva {
+            saturation: if predicted { 0.4 } else { 0.1 },
+            ..Hsva::from(team.color())
+        };
+        color.0 = Color::from(hsva);
+    }
+}
+
+/// Asks the server to move the player to the other team with C
+fn request_team_change(
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    connection: Res<ClientConnection>,
+    mut manager: ResMut<client::ConnectionManager>,
+    players: Query<(&PlayerId, &Team), Without<client::Confirmed>>,
+) {
+    if !keys.is_some_and(|keys| keys.just_pressed(KeyCode::KeyC)) {
+        return;
+    }
+    let client_id = connection.id();
+    let Some((_, team)) = players.iter().find(|(id, _)| id.0 == client_id) else {
+        return;
+    };
+    let request = RequestTeam { team: team.other() };
+    if let Err(e) = manager.send_message::<LobbyChannel, _>(&request) {
+        error!("Could not request the team change: {e}");
+    }
+}
+
+fn update_roster(
+    mut commands: Commands,
+    players: Query<(&PlayerId, &Team), Without<client::Confirmed>>,
+    mut rosters: Query<&mut Text, With<Roster>>,
+) {
+    let mut members: Vec<_> = players.iter().map(|(id, team)| (*team, id.0)).collect();
+    members.sort_by_key(|(team, id)| (*team == Team::Blue, id.to_bits()));
+    let text = Team::ALL
+        .iter()
+        .filter(|team| members.iter().any(|(member, _)| member == *team))
+        .map(|team| {
+            let ids: Vec<_> = members
+                .iter()
+                .filter(|(member, _)| member == team)
+                .map(|(_, id)| id.to_string())
+                .collect();
+            format!("{team}: {}", ids.join(", "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    match rosters.get_single_mut() {
+        Ok(mut roster) => {
+            if roster.sections[0].value != text {
+                roster.sections[0].value = text;
+            }
+        }
+        Err(_) if !text.is_empty() => {
+            commands.spawn((
+                TextBundle::from_section(
+                    text,
+                    TextStyle {
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                )
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(40.0),
+                    right: Val::Px(10.0),
+                    ..default()
+                }),
+                Roster,
+            ));
+        }
+        Err(_) => {}
+    }
+}
@@ -0,0 +1,145 @@
+use avian2d::prelude::*;
+use bevy::ecs::system::RunSystemOnce;
+use bevy::prelude::*;
+use leafwing_input_manager::axislike::DualAxisData;
+use leafwing_input_manager::prelude::*;
+
+use crate::protocol::*;
+use crate::server::movement;
+use crate::shared::*;
+
+fn spawn_player(world: &mut World, pressed: &[PlayerActions]) -> Entity {
+    let mut action = ActionState::<PlayerActions>::default();
+    for pressed in pressed {
+        action.press(pressed);
+    }
+    world
+        .spawn((
+            Position(Vec2::ZERO),
+            Rotation::default(),
+            LinearVelocity(Vec2::ZERO),
+            AngularVelocity(0.0),
+            Cooldowns::default(),
+            action,
+        ))
+        .id()
+}
+
+fn spawn_ball(world: &mut World, position: Vec2) -> Entity {
+    world
+        .spawn((BallMarker, Position(position), LinearVelocity(Vec2::ZERO)))
+        .id()
+}
+
+#[test]
+fn dashes_wait_for_their_cooldown() {
+    let mut world = World::new();
+    let player = spawn_player(&mut world, &[PlayerActions::Dash]);
+
+    world.run_system_once(movement);
+    assert_eq!(
+        world.get::<LinearVelocity>(player).unwrap().0,
+        Vec2::X * DASH_SPEED
+    );
+    assert_eq!(world.get::<Cooldowns>(player).unwrap().dash, DASH_COOLDOWN);
+
+    for _ in 1..DASH_COOLDOWN {
+        world.run_system_once(movement);
+    }
+    assert_eq!(
+        world.get::<LinearVelocity>(player).unwrap().0,
+        Vec2::X * DASH_SPEED
+    );
+
+    world.run_system_once(movement);
+    assert_eq!(
+        world.get::<LinearVelocity>(player).unwrap().0,
+        Vec2::X * DASH_SPEED * 2.0
+    );
+}
+
+#[test]
+fn dashes_go_where_the_player_faces() {
+    let mut world = World::new();
+    let walking = spawn_player(&mut world, &[PlayerActions::Move]);
+    let dashing = spawn_player(&mut world, &[PlayerActions::Move, PlayerActions::Dash]);
+    for player in [walking, dashing] {
+        *world.get_mut::<Rotation>(player).unwrap() = Rotation::degrees(90.0);
+        world
+            .get_mut::<ActionState<PlayerActions>>(player)
+            .unwrap()
+            .action_data_mut_or_default(&PlayerActions::Move)
+            .axis_pair = Some(DualAxisData::new(1.0, 0.0));
+    }
+
+    world.run_system_once(movement);
+    // moving right while facing up, the dash only adds speed upward
+    let walked = world.get::<LinearVelocity>(walking).unwrap().0;
+    let dashed = world.get::<LinearVelocity>(dashing).unwrap().0;
+    assert!(walked.x > 0.0);
+    assert!((dashed - walked).abs_diff_eq(Vec2::Y * DASH_SPEED, 1e-3));
+}
+
+#[test]
+fn brakes_damp_the_player_for_a_while() {
+    let mut world = World::new();
+    let player = spawn_player(&mut world, &[PlayerActions::Brake]);
+    world.get_mut::<LinearVelocity>(player).unwrap().0 = Vec2::X * 100.0;
+
+    for _ in 0..BRAKE_TICKS {
+        world.run_system_once(movement);
+    }
+    let braked = world.get::<LinearVelocity>(player).unwrap().x;
+    assert!(braked < 10.0, "{braked}");
+
+    world.run_system_once(movement);
+    assert_eq!(world.get::<LinearVelocity>(player).unwrap().x, braked);
+}
+
+#[test]
+fn kicks_push_the_balls_in_range_away() {
+    let mut world = World::new();
+    spawn_player(&mut world, &[PlayerActions::Kick]);
+    let near = spawn_ball(&mut world, Vec2::new(0.0, KICK_RANGE - 1.0));
+    let far = spawn_ball(&mut world, Vec2::new(KICK_RANGE + 1.0, 0.0));
+
+    world.run_system_once(movement);
+    assert_eq!(
+        world.get::<LinearVelocity>(near).unwrap().0,
+        Vec2::Y * KICK_SPEED
+    );
+    assert_eq!(world.get::<LinearVelocity>(far).unwrap().0, Vec2::ZERO);
+
+    // held, it only kicks again after the cooldown
+    world.run_system_once(movement);
+    assert_eq!(
+        world.get::<LinearVelocity>(near).unwrap().0,
+        Vec2::Y * KICK_SPEED
+    );
+}
+
+#[test]
+fn aiming_turns_with_a_torque_limit() {
+    let mut world = World::new();
+    let player = spawn_player(&mut world, &[PlayerActions::Aim]);
+    world
+        .get_mut::<ActionState<PlayerActions>>(player)
+        .unwrap()
+        .action_data_mut_or_default(&PlayerActions::Aim)
+        .axis_pair = Some(DualAxisData::new(0.0, 1.0));
+
+    world.run_system_once(movement);
+    // counterclockwise, toward the aim
+    assert_eq!(
+        world.get::<AngularVelocity>(player).unwrap().0,
+        AIM_MAX_ACCELERATION
+    );
+
+    for _ in 0..20 {
+        world.run_system_once(movement);
+    }
+    assert_eq!(
+        world.get::<AngularVelocity>(player).unwrap().0,
+        AIM_MAX_SPEED
+    );
+}
@@ -0,0 +1,111 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bevy::utils::{Duration, Instant};
+use lightyear::prelude::*;
+
+use crate::auth::{
+    request_token, ClientRegistry, TokenError, TokenService, ASSIGN_CLIENT_ID, TOKEN_EXPIRE,
+};
+use crate::tests::stepper::{free_port, test_settings, Stepper};
+use crate::MySettings;
+
+/// Settings with a token service on a free port, which the clients use to connect
+fn token_settings() -> MySettings {
+    let port = free_port();
+    let mut settings = test_settings();
+    settings.common.server.token_port = Some(port);
+    settings.common.client.token_port = Some(port);
+    settings
+}
+
+fn connected_clients(stepper: &mut Stepper) -> usize {
+    stepper
+        .server_world()
+        .resource::<server::ConnectionManager>()
+        .connected_clients()
+        .count()
+}
+
+#[test]
+fn clients_connect_with_issued_tokens() {
+    let mut stepper = Stepper::build(2, token_settings());
+    stepper.finish();
+    assert!(stepper.run_until(1000, |stepper| connected_clients(stepper) == 2));
+}
+
+#[test]
+fn connected_client_ids_are_refused() {
+    let mut stepper = Stepper::build(1, token_settings());
+    stepper.finish();
+    assert!(stepper.run_until(1000, |stepper| connected_clients(stepper) == 1));
+    stepper.tick();
+
+    let addr = stepper.server_world().resource::<TokenService>().addr;
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port());
+    assert!(matches!(request_token(addr, 1, None), Err(TokenError::Refused(1))));
+    assert!(request_token(addr, 2, None).is_ok());
+}
+
+#[test]
+fn unused_client_ids_are_assigned() {
+    let mut stepper = Stepper::build(1, token_settings());
+    stepper.finish();
+    assert!(stepper.run_until(1000, |stepper| connected_clients(stepper) == 1));
+    stepper.tick();
+
+    let addr = stepper.server_world().resource::<TokenService>().addr;
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port());
+    let first = request_token(addr, ASSIGN_CLIENT_ID, None).unwrap().client_id;
+    let second = request_token(addr, ASSIGN_CLIENT_ID, None).unwrap().client_id;
+    assert_ne!(first, second);
+    for id in [first, second] {
+        assert!(id != ASSIGN_CLIENT_ID && id != 1, "{id}");
+    }
+}
+
+#[test]
+fn issued_client_ids_need_their_secret() {
+    let mut stepper = Stepper::build(1, token_settings());
+    stepper.finish();
+    assert!(stepper.run_until(1000, |stepper| connected_clients(stepper) == 1));
+
+    let addr = stepper.server_world().resource::<TokenService>().addr;
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port());
+    let issued = request_token(addr, ASSIGN_CLIENT_ID, None).unwrap();
+    let id = issued.client_id;
+    assert!(matches!(
+        request_token(addr, id, None),
+        Err(TokenError::Refused(refused)) if refused == id
+    ));
+    let mut wrong = issued.secret;
+    wrong[0] ^= 1;
+    assert!(request_token(addr, id, Some(wrong)).is_err());
+    let reissued = request_token(addr, id, Some(issued.secret)).unwrap();
+    assert_eq!(reissued.client_id, id);
+}
+
+#[test]
+fn unused_secrets_expire_with_their_token() {
+    let registry = ClientRegistry::default();
+    let now = Instant::now();
+    let (id, secret) = registry.issue(5, [0; 32], now).unwrap();
+    assert_eq!(id, 5);
+    assert!(matches!(
+        registry.issue(5, [0; 32], now),
+        Err(TokenError::WrongSecret(5))
+    ));
+    // the client never connected, the id is free again once its token expired
+    let later = now + TOKEN_EXPIRE + Duration::from_secs(1);
+    let (_, reissued) = registry.issue(5, [0; 32], later).unwrap();
+    assert_ne!(reissued, secret);
+}
+
+#[test]
+fn clients_without_tokens_are_rejected() {
+    let settings = token_settings();
+    let mut client_settings = settings.clone();
+    client_settings.common.client.token_port = None;
+    let mut stepper = Stepper::build_with_clients(settings, vec![client_settings]);
+    stepper.finish();
+    assert!(!stepper.run_until(300, |stepper| connected_clients(stepper) == 1));
+}
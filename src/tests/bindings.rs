@@ -0,0 +1,75 @@
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::bindings::{Bindings, Device};
+use crate::protocol::PlayerActions;
+use crate::settings::read_settings;
+
+#[test]
+fn devices_are_parsed() {
+    assert_eq!("any".parse::<Device>().unwrap(), Device::Any);
+    assert_eq!("keyboard:1".parse::<Device>().unwrap(), Device::Keyboard(1));
+    assert_eq!("gamepad:0".parse::<Device>().unwrap(), Device::Gamepad(0));
+    assert!("gamepad".parse::<Device>().is_err());
+    assert!("keyboard:first".parse::<Device>().is_err());
+    assert!("mouse:0".parse::<Device>().is_err());
+}
+
+#[test]
+fn the_deadzone_is_ignored_and_the_rest_rescaled() {
+    let bindings = Bindings {
+        deadzone: 0.2,
+        ..default()
+    };
+    assert_eq!(bindings.process(Vec2::new(0.1, 0.1)), Vec2::ZERO);
+    assert!((bindings.process(Vec2::new(0.6, 0.0)) - Vec2::new(0.5, 0.0)).length() < 1e-5);
+    assert!((bindings.process(Vec2::new(0.0, -1.0)) - Vec2::new(0.0, -1.0)).length() < 1e-5);
+    // both keys of a diagonal are not faster than one
+    assert!((bindings.process(Vec2::ONE).length() - 1.0).abs() < 1e-5);
+}
+
+#[test]
+fn embedded_bindings_have_an_alternate_layout() {
+    let bindings = Bindings::default();
+    assert_eq!(bindings.keyboard.len(), 2);
+    assert_eq!(bindings.keyboard[1].up, KeyCode::ArrowUp);
+    assert_eq!(bindings.device, Device::Any);
+}
+
+#[test]
+fn layouts_without_actions_get_the_default_keys() {
+    let bindings: Bindings = read_settings(
+        "Bindings(
+            keyboard: [KeyLayout(up: KeyI, down: KeyK, left: KeyJ, right: KeyL)],
+            gamepad: false,
+            deadzone: 0.15,
+            device: Any,
+        )",
+    )
+    .unwrap();
+    let default = &Bindings::default().keyboard[0];
+    assert_eq!(bindings.keyboard[0].up, KeyCode::KeyI);
+    assert_eq!(bindings.keyboard[0].dash, default.dash);
+    assert_eq!(bindings.keyboard[0].brake, default.brake);
+    assert_eq!(bindings.keyboard[0].kick, default.kick);
+}
+
+#[test]
+fn each_device_gets_its_own_input_map() {
+    let mut bindings = Bindings::default();
+    let any = bindings.input_map();
+    assert_eq!(any.get(&PlayerActions::Move).map(Vec::len), Some(3));
+    assert_eq!(any.get(&PlayerActions::Aim).map(Vec::len), Some(1));
+    assert_eq!(any.gamepad(), None);
+
+    bindings.device = Device::Keyboard(1);
+    let keyboard = bindings.input_map();
+    assert_eq!(keyboard.get(&PlayerActions::Move).map(Vec::len), Some(1));
+    // aimed with the mouse
+    assert_eq!(keyboard.get(&PlayerActions::Aim), None);
+
+    bindings.device = Device::Gamepad(1);
+    let gamepad = bindings.input_map();
+    assert_eq!(gamepad.get(&PlayerActions::Move).map(Vec::len), Some(1));
+    assert_eq!(gamepad.gamepad(), Some(Gamepad::new(1)));
+}
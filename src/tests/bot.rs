@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use leafwing_input_manager::axislike::DualAxisData;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::client::{Confirmed, Predicted};
+use lightyear::prelude::ClientId;
+
+use crate::bot::{Behaviour, BotBehaviour, BotError, BotPlugin};
+use crate::protocol::*;
+use crate::recording::Recording;
+use crate::tests::stepper::{test_settings, Stepper};
+
+fn bot_stepper(behaviour: Behaviour) -> Stepper {
+    let mut stepper = Stepper::build(1, test_settings());
+    stepper.clients[0].add_plugins(BotPlugin { behaviour, seed: 1 });
+    stepper.init();
+    stepper
+}
+
+fn player_position(world: &mut World) -> Option<Vec2> {
+    world
+        .query_filtered::<&Position, (With<PlayerId>, With<Predicted>)>()
+        .iter(world)
+        .next()
+        .map(|position| position.0)
+}
+
+fn distance_to_closest_ball(world: &mut World) -> f32 {
+    let player = player_position(world).unwrap();
+    world
+        .query_filtered::<&Position, (With<BallMarker>, Without<Confirmed>)>()
+        .iter(world)
+        .map(|ball| ball.distance(player))
+        .fold(f32::INFINITY, f32::min)
+}
+
+#[test]
+fn chasing_bots_reach_a_ball() {
+    let mut stepper = bot_stepper(Behaviour::ChaseBall);
+    let spawned = stepper.run_until(100, |stepper| {
+        player_position(stepper.client_world(0)).is_some()
+    });
+    assert!(spawned);
+    let start = distance_to_closest_ball(stepper.client_world(0));
+
+    stepper.tick_n(60);
+
+    let end = distance_to_closest_ball(stepper.client_world(0));
+    assert!(end < start / 2.0, "{start} -> {end}");
+}
+
+#[test]
+fn replaying_bots_play_the_recorded_inputs() {
+    // a recorded player that moves right
+    let mut action = ActionState::<PlayerActions>::default();
+    action.press(&PlayerActions::Move);
+    action
+        .action_data_mut_or_default(&PlayerActions::Move)
+        .axis_pair = Some(DualAxisData::new(1.0, 0.0));
+    let recorded = ClientId::Netcode(7);
+    let recording = Recording {
+        inputs: [(recorded, BTreeMap::from([(0, action)]))].into(),
+        ticks: 100,
+        ..default()
+    };
+    let mut stepper = bot_stepper(Behaviour::Replay {
+        recording: Arc::new(recording),
+        client_id: recorded,
+    });
+    let spawned = stepper.run_until(100, |stepper| {
+        player_position(stepper.client_world(0)).is_some()
+    });
+    assert!(spawned);
+    let start = player_position(stepper.client_world(0)).unwrap();
+
+    stepper.tick_n(30);
+
+    let moved = player_position(stepper.client_world(0)).unwrap() - start;
+    assert!(moved.x > 20.0, "the player moved by {moved}");
+    assert!(moved.y.abs() < moved.x / 4.0, "the player moved by {moved}");
+}
+
+#[test]
+fn replaying_needs_an_input_file() {
+    let result = Behaviour::load(BotBehaviour::Replay, None);
+    assert!(matches!(result, Err(BotError::MissingInputFile)));
+}
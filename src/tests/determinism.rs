@@ -0,0 +1,57 @@
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::server;
+
+use crate::determinism::ChecksumHistory;
+use crate::protocol::*;
+use crate::tests::stepper::{test_settings, Stepper};
+
+#[test]
+fn predicted_world_matches_server_checksums() {
+    let mut settings = test_settings();
+    settings.deterministic = true;
+    let mut stepper = Stepper::new(2, settings);
+    // the server spawns the players once the clients joined
+    let spawned = stepper.run_until(100, |stepper| {
+        let world = stepper.client_world(0);
+        world
+            .query_filtered::<(), (With<PlayerId>, With<InputMap<PlayerActions>>)>()
+            .iter(world)
+            .count()
+            == 1
+    });
+    assert!(spawned);
+    stepper.tick_n(10);
+
+    stepper
+        .client_world(0)
+        .resource_mut::<ButtonInput<KeyCode>>()
+        .press(KeyCode::KeyD);
+    for _ in 0..200 {
+        stepper.tick();
+        let reports = stepper
+            .server_world()
+            .resource::<Events<server::MessageEvent<DesyncReport>>>();
+        assert!(reports.is_empty(), "a client reported a desync");
+    }
+    for i in 0..2 {
+        let matched = stepper.client_world(i).resource::<ChecksumHistory>().matched;
+        assert!(matched > 100, "client {i} only matched {matched} checksums");
+    }
+}
+
+#[test]
+fn partially_predicted_worlds_are_reported_as_uncheckable() {
+    let mut settings = test_settings();
+    settings.deterministic = true;
+    settings.predict_all = false;
+    let mut stepper = Stepper::new(1, settings);
+    assert!(stepper.run_until(400, |stepper| {
+        stepper
+            .client_world(0)
+            .resource::<ChecksumHistory>()
+            .uncheckable
+    }));
+    let history = stepper.client_world(0).resource::<ChecksumHistory>();
+    assert_eq!(history.matched, 0);
+}
@@ -0,0 +1,31 @@
+use std::fs;
+
+use crate::settings::{MetricsExport, MetricsFormat};
+use crate::tests::stepper::{test_settings, Stepper};
+
+#[test]
+fn metrics_are_exported_as_json_lines() {
+    let path = std::env::temp_dir().join(format!("net-phys-metrics-{}.jsonl", std::process::id()));
+    let mut settings = test_settings();
+    settings.metrics_export = Some(MetricsExport {
+        path: path.clone(),
+        format: MetricsFormat::JsonLines,
+    });
+    let mut stepper = Stepper::new(1, settings);
+    stepper.tick_n(100);
+
+    let content = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let samples: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(samples.len() >= 2, "{content}");
+    let last = samples.last().unwrap();
+    assert!(last["tick"].as_u64().unwrap() > 0);
+    assert!(last["rtt_ms"].is_number());
+    for channel in ["replication", "snapshots", "checksums", "lobby"] {
+        assert!(last[format!("estimated_kb_{channel}_per_s")].is_number());
+    }
+    assert_eq!(last["input_delay_ticks"], 2);
+}
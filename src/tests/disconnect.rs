@@ -0,0 +1,105 @@
+use avian2d::prelude::*;
+use bevy::ecs::query::QueryFilter;
+use bevy::ecs::system::RunSystemOnce;
+use bevy::prelude::*;
+use lightyear::prelude::client::{ClientCommands, Predicted};
+use lightyear::prelude::*;
+
+use crate::protocol::*;
+use crate::tests::stepper::{test_settings, Stepper};
+
+fn count<F: QueryFilter>(world: &mut World) -> usize {
+    world.query_filtered::<(), F>().iter(world).count()
+}
+
+fn server_player(world: &mut World, client_id: u64) -> Option<Entity> {
+    world
+        .query::<(Entity, &PlayerId)>()
+        .iter(world)
+        .find(|(_, id)| id.0 == ClientId::Netcode(client_id))
+        .map(|(entity, _)| entity)
+}
+
+#[test]
+fn disconnected_players_are_despawned() {
+    let mut stepper = Stepper::new(2, test_settings());
+    assert!(stepper.run_until(100, |stepper| {
+        count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(1)) == 2
+    }));
+
+    stepper
+        .client_world(0)
+        .run_system_once(|mut commands: Commands| commands.disconnect_client());
+    let mut left = vec![];
+    let despawned = stepper.run_until(100, |stepper| {
+        let world = stepper.client_world(1);
+        let events = world.resource::<Events<client::MessageEvent<PlayerLeft>>>();
+        left.extend(events.get_reader().read(events).map(|e| e.message().client_id));
+        server_player(stepper.server_world(), 1).is_none()
+            && count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(1)) == 1
+    });
+    assert!(despawned);
+    assert!(left.contains(&ClientId::Netcode(1)));
+}
+
+#[test]
+fn reconnecting_players_get_their_body_back() {
+    let mut stepper = Stepper::new(2, test_settings());
+    assert!(stepper.run_until(100, |stepper| {
+        server_player(stepper.server_world(), 1).is_some()
+    }));
+    let player = server_player(stepper.server_world(), 1).unwrap();
+    let moved = Vec2::new(-200.0, 100.0);
+    stepper.server_world().entity_mut(player).insert(Position(moved));
+    stepper.tick_n(5);
+
+    stepper
+        .client_world(0)
+        .run_system_once(|mut commands: Commands| commands.disconnect_client());
+    assert!(stepper.run_until(100, |stepper| {
+        server_player(stepper.server_world(), 1).is_none()
+    }));
+
+    stepper
+        .client_world(0)
+        .run_system_once(|mut commands: Commands| commands.connect_client());
+    assert!(stepper.run_until(200, |stepper| {
+        server_player(stepper.server_world(), 1).is_some()
+    }));
+    let player = server_player(stepper.server_world(), 1).unwrap();
+    let position = stepper.server_world().get::<Position>(player).unwrap().0;
+    assert!(position.distance(moved) < 1.0, "{position}");
+}
+
+#[test]
+fn reconnecting_players_avoid_the_bodies_on_their_position() {
+    let mut stepper = Stepper::new(2, test_settings());
+    assert!(stepper.run_until(100, |stepper| {
+        server_player(stepper.server_world(), 1).is_some()
+            && server_player(stepper.server_world(), 2).is_some()
+    }));
+    let player = server_player(stepper.server_world(), 1).unwrap();
+    let left_at = stepper.server_world().get::<Position>(player).unwrap().0;
+    stepper
+        .client_world(0)
+        .run_system_once(|mut commands: Commands| commands.disconnect_client());
+    assert!(stepper.run_until(100, |stepper| {
+        server_player(stepper.server_world(), 1).is_none()
+    }));
+
+    // another player stands where the player left
+    let other = server_player(stepper.server_world(), 2).unwrap();
+    stepper
+        .server_world()
+        .entity_mut(other)
+        .insert((Position(left_at), LinearVelocity::ZERO));
+    stepper
+        .client_world(0)
+        .run_system_once(|mut commands: Commands| commands.connect_client());
+    assert!(stepper.run_until(200, |stepper| {
+        server_player(stepper.server_world(), 1).is_some()
+    }));
+    let player = server_player(stepper.server_world(), 1).unwrap();
+    let position = stepper.server_world().get::<Position>(player).unwrap().0;
+    assert!(position.distance(left_at) > PLAYER_SIZE, "{position}");
+}
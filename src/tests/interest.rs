@@ -0,0 +1,75 @@
+use avian2d::prelude::*;
+use bevy::ecs::query::QueryFilter;
+use bevy::prelude::*;
+use lightyear::prelude::client::Predicted;
+use lightyear::prelude::ClientId;
+
+use crate::interest::in_range;
+use crate::protocol::*;
+use crate::settings::Interest;
+use crate::tests::stepper::{test_settings, Stepper};
+
+fn count<F: QueryFilter>(world: &mut World) -> usize {
+    world.query_filtered::<(), F>().iter(world).count()
+}
+
+fn move_body(world: &mut World, entity: Entity, position: Vec2) {
+    world.get_mut::<Position>(entity).unwrap().0 = position;
+}
+
+#[test]
+fn entities_on_the_edge_stay_relevant() {
+    assert!(in_range(90.0, 100.0, 20.0, false));
+    assert!(!in_range(110.0, 100.0, 20.0, false));
+    assert!(in_range(110.0, 100.0, 20.0, true));
+    assert!(!in_range(130.0, 100.0, 20.0, true));
+}
+
+#[test]
+fn clients_only_receive_nearby_entities() {
+    let mut settings = test_settings();
+    settings.common.server.interest = Some(Interest {
+        player_radius: 150.0,
+        ball_radius: 150.0,
+        hysteresis: 30.0,
+    });
+    let mut stepper = Stepper::new(2, settings);
+    // both players spawn next to each other, far from the balls
+    assert!(stepper.run_until(100, |stepper| {
+        count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(0)) == 2
+    }));
+    assert!(count::<With<BallMarker>>(stepper.server_world()) > 0);
+    assert_eq!(count::<With<BallMarker>>(stepper.client_world(0)), 0);
+
+    let server = stepper.server_world();
+    let other = server
+        .query::<(Entity, &PlayerId)>()
+        .iter(server)
+        .find(|(_, id)| id.0 == ClientId::Netcode(2))
+        .map(|(entity, _)| entity)
+        .unwrap();
+    let center = server
+        .query::<(&PlayerId, &Position)>()
+        .iter(server)
+        .find(|(id, _)| id.0 == ClientId::Netcode(1))
+        .map(|(_, position)| position.0)
+        .unwrap();
+    move_body(server, other, center + Vec2::new(300.0, 0.0));
+    assert!(stepper.run_until(100, |stepper| {
+        count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(0)) == 1
+    }));
+
+    let server = stepper.server_world();
+    move_body(server, other, center + Vec2::new(100.0, 0.0));
+    let ball = server
+        .query_filtered::<Entity, With<BallMarker>>()
+        .iter(server)
+        .next()
+        .unwrap();
+    move_body(server, ball, center + Vec2::new(0.0, 60.0));
+    assert!(stepper.run_until(100, |stepper| {
+        let client = stepper.client_world(0);
+        count::<(With<PlayerId>, With<Predicted>)>(client) == 2
+            && count::<(With<BallMarker>, With<Predicted>)>(client) == 1
+    }));
+}
@@ -0,0 +1,97 @@
+use std::fs;
+
+use bevy::prelude::*;
+
+use crate::level::{BallPattern, Level, LevelError, LevelGeometry};
+use crate::tests::stepper::{test_settings, Stepper};
+
+#[test]
+fn builtin_levels_are_valid() {
+    let arena = Level::load("arena").unwrap();
+    assert_eq!(arena, Level::default());
+    assert_eq!(arena.ball_positions().len(), 160);
+    assert_eq!(arena.spawn_points.len(), 16);
+
+    let pillars = Level::load("pillars").unwrap();
+    assert_eq!(pillars.obstacles.len(), 6);
+}
+
+#[test]
+fn ball_patterns_are_centered() {
+    let grid = Level {
+        balls: vec![BallPattern::Grid {
+            center: Vec2::new(0.0, 140.0),
+            columns: 16,
+            rows: 10,
+            spacing: 40.0,
+        }],
+        ..default()
+    };
+    let positions = grid.ball_positions();
+    assert_eq!(positions[0], Vec2::new(-300.0, -40.0));
+    assert_eq!(positions[159], Vec2::new(300.0, 320.0));
+
+    let ring = Level {
+        balls: vec![BallPattern::Ring {
+            center: Vec2::ZERO,
+            radius: 100.0,
+            count: 8,
+        }],
+        ..default()
+    };
+    let positions = ring.ball_positions();
+    assert_eq!(positions.len(), 8);
+    assert!(positions.iter().all(|p| (p.length() - 100.0).abs() < 1e-3));
+}
+
+#[test]
+fn level_files_can_be_json() {
+    let path = std::env::temp_dir().join(format!("net-phys-level-{}.json", std::process::id()));
+    let mut level = Level::default();
+    level.name = "from json".to_string();
+    fs::write(&path, serde_json::to_string(&level).unwrap()).unwrap();
+    let loaded = Level::load(&path.to_string_lossy());
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap(), level);
+}
+
+#[test]
+fn invalid_levels_are_refused() {
+    let path = std::env::temp_dir().join(format!("net-phys-invalid-{}.json", std::process::id()));
+    let mut level = Level::default();
+    level.spawn_points.push(Vec2::new(1000.0, 0.0));
+    fs::write(&path, serde_json::to_string(&level).unwrap()).unwrap();
+    let loaded = Level::load(&path.to_string_lossy());
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(loaded, Err(LevelError::SpawnOutOfBounds(_))));
+
+    assert!(matches!(
+        Level::load("no-such-level"),
+        Err(LevelError::Io { .. })
+    ));
+}
+
+#[test]
+fn clients_load_the_level_of_the_server() {
+    let mut settings = test_settings();
+    settings.common.server.level = "pillars".to_string();
+    let mut stepper = Stepper::new(1, settings);
+    assert!(stepper.run_until(100, |stepper| {
+        stepper
+            .client_world(0)
+            .get_resource::<Level>()
+            .is_some_and(|level| level.name == "pillars")
+    }));
+    stepper.tick_n(1);
+
+    let geometry = |world: &mut World| {
+        world
+            .query_filtered::<(), With<LevelGeometry>>()
+            .iter(world)
+            .count()
+    };
+    let server = geometry(stepper.server_world());
+    // 4 walls of 3 segments and 6 obstacles
+    assert_eq!(server, 18);
+    assert_eq!(geometry(stepper.client_world(0)), server);
+}
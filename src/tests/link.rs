@@ -0,0 +1,198 @@
+use std::net::Ipv4Addr;
+use std::thread;
+
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use lightyear::connection::server::NetServer;
+use lightyear::prelude::*;
+
+use crate::app::{client_app, server_app, Apps};
+use crate::client::LinkProfile;
+use crate::link::{parse_link_command, Link, LinkCommandError, LinkConditions, SetLinkProfile};
+use crate::settings::{
+    get_client_net_config, ClientTransports, ConditionerPreset, ServerTransports,
+};
+use crate::tests::stepper::{free_port, test_settings, Stepper};
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+#[test]
+fn links_deliver_packets_after_their_latency() {
+    let mut link = Link::new(
+        LinkConditions {
+            latency: ms(50),
+            ..default()
+        },
+        1,
+    );
+    link.send(ms(0), vec![1]);
+    link.send(ms(10), vec![2]);
+
+    assert_eq!(link.receive(ms(49)).count(), 0);
+    assert_eq!(link.receive(ms(50)).collect::<Vec<_>>(), vec![vec![1]]);
+    assert_eq!(link.receive(ms(100)).collect::<Vec<_>>(), vec![vec![2]]);
+}
+
+#[test]
+fn links_drop_and_duplicate_packets() {
+    let mut lossy = Link::new(
+        LinkConditions {
+            loss: 1.0,
+            ..default()
+        },
+        1,
+    );
+    lossy.send(ms(0), vec![1]);
+    assert_eq!(lossy.receive(ms(10)).count(), 0);
+
+    let mut duplicating = Link::new(
+        LinkConditions {
+            duplicate: 1.0,
+            ..default()
+        },
+        1,
+    );
+    duplicating.send(ms(0), vec![1]);
+    assert_eq!(duplicating.receive(ms(10)).count(), 2);
+}
+
+#[test]
+fn bandwidth_caps_spread_the_packets_out() {
+    // 10 bytes per millisecond
+    let mut link = Link::new(
+        LinkConditions {
+            bandwidth_kbps: Some(80),
+            ..default()
+        },
+        1,
+    );
+    for _ in 0..10 {
+        link.send(ms(0), vec![0; 100]);
+    }
+    assert_eq!(link.receive(ms(50)).count(), 5);
+    assert_eq!(link.receive(ms(100)).count(), 5);
+}
+
+#[test]
+fn link_commands_are_parsed() {
+    assert_eq!(
+        parse_link_command("link 3 4g"),
+        Ok(Some(SetLinkProfile {
+            target: Some(ClientId::Netcode(3)),
+            preset: Some(ConditionerPreset::FourG),
+        }))
+    );
+    assert_eq!(
+        parse_link_command(" link all off "),
+        Ok(Some(SetLinkProfile {
+            target: None,
+            preset: None,
+        }))
+    );
+    assert_eq!(parse_link_command(""), Ok(None));
+    assert_eq!(
+        parse_link_command("link 3 dialup"),
+        Err(LinkCommandError::Profile("dialup".to_string()))
+    );
+}
+
+#[test]
+fn presets_cycle_back_to_no_conditions() {
+    let mut preset = None;
+    let mut seen = vec![];
+    loop {
+        preset = ConditionerPreset::next(preset);
+        let Some(current) = preset else {
+            break;
+        };
+        seen.push(current);
+    }
+    assert_eq!(seen.len(), 4);
+}
+
+#[test]
+fn link_profiles_apply_to_a_single_client() {
+    let mut settings = test_settings();
+    settings.common.server.link_relay = true;
+    let mut stepper = Stepper::new(2, settings);
+
+    stepper.server_world().send_event(SetLinkProfile {
+        target: Some(ClientId::Netcode(1)),
+        preset: Some(ConditionerPreset::Intercontinental),
+    });
+    // the relay keeps its own clock, so the apps are updated in real time
+    for _ in 0..300 {
+        stepper.tick();
+        thread::sleep(stepper.tick_duration);
+    }
+
+    let rtt = |world: &mut World| {
+        world
+            .resource::<client::ConnectionManager>()
+            .ping_manager
+            .rtt()
+    };
+    let slow = rtt(stepper.client_world(0));
+    let fast = rtt(stepper.client_world(1));
+    assert!(
+        slow > ms(120),
+        "rtt of the intercontinental client: {slow:?}"
+    );
+    assert!(fast < ms(60), "rtt of the other client: {fast:?}");
+    assert_eq!(
+        stepper.client_world(0).resource::<LinkProfile>().0,
+        Some(ConditionerPreset::Intercontinental)
+    );
+    assert_eq!(stepper.client_world(1).resource::<LinkProfile>().0, None);
+}
+
+#[test]
+fn udp_clients_connect_through_the_relay() {
+    let port = free_port();
+    let mut settings = test_settings();
+    settings.common.server.transport = vec![ServerTransports::Udp { local_port: port }];
+    settings.common.server.link_relay = true;
+    settings.common.client.transport = ClientTransports::Udp;
+    settings.common.client.server_port = port;
+
+    let (app, config) = server_app(settings.common.clone(), vec![]);
+    let mut server = Apps::Server { app, config };
+    crate::add_plugins(&mut server, &settings);
+    let net_config = get_client_net_config(&settings.common, 1, Ipv4Addr::LOCALHOST.into());
+    let (app, config) = client_app(settings.common.clone(), net_config);
+    let mut client = Apps::Client { app, config };
+    crate::add_plugins(&mut client, &settings);
+
+    for apps in [&mut server, &mut client] {
+        apps.app_mut().finish();
+        apps.app_mut().cleanup();
+    }
+
+    // the relay keeps its own clock, so the apps are updated in real time
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let connected = loop {
+        server.app_mut().update();
+        client.app_mut().update();
+        let world = client.app_mut().world();
+        if world.resource::<client::ConnectionManager>().is_synced() {
+            break true;
+        }
+        if Instant::now() > deadline {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    assert!(connected, "the client did not connect through the relay");
+
+    // only the relay listens on the public port
+    let connections = server
+        .app_mut()
+        .world()
+        .resource::<server::ServerConnections>();
+    for listener in &connections.servers {
+        let addr = listener.io().unwrap().local_addr();
+        assert!(addr.ip().is_loopback() && addr.port() != port, "{addr}");
+    }
+}
@@ -0,0 +1,21 @@
+mod actions;
+mod auth;
+mod bindings;
+mod bot;
+mod determinism;
+mod diagnostics;
+mod disconnect;
+mod interest;
+mod level;
+mod link;
+mod reload;
+mod replay;
+mod replication;
+mod settings;
+mod snapshot;
+mod soccer;
+mod spectator;
+mod swarm;
+mod team;
+mod transport;
+pub(crate) mod stepper;
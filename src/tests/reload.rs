@@ -0,0 +1,62 @@
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime};
+
+use bevy::asset::ron;
+use bevy::prelude::*;
+use lightyear::prelude::client::{ClientConfig, NetworkingState};
+
+use crate::reload::{restart_required, SettingsReloadPlugin};
+use crate::tests::stepper::{test_settings, Stepper};
+
+#[test]
+fn input_delay_is_changed_without_reconnecting() {
+    let path = std::env::temp_dir().join(format!("net-phys-reload-{}.ron", std::process::id()));
+    let mut settings = test_settings();
+    fs::write(&path, ron::to_string(&settings).unwrap()).unwrap();
+    let mut stepper = Stepper::build(1, settings.clone());
+    stepper.clients[0]
+        .app_mut()
+        .add_plugins(SettingsReloadPlugin {
+            path: path.clone(),
+            overrides: default(),
+            settings: settings.clone(),
+        });
+    stepper.init();
+
+    settings.input_delay_ticks = 5;
+    settings.correction_ticks_factor = 3.0;
+    fs::write(&path, ron::to_string(&settings).unwrap()).unwrap();
+    // the file may be written within the resolution of the modification time
+    File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now() + Duration::from_secs(2)))
+        .unwrap();
+    let mut states = vec![];
+    let applied = stepper.run_until(100, |stepper| {
+        let client = stepper.client_world(0);
+        states.push(*client.resource::<State<NetworkingState>>().get());
+        client
+            .resource::<ClientConfig>()
+            .prediction
+            .minimum_input_delay_ticks
+            == 5
+    });
+    fs::remove_file(&path).unwrap();
+
+    assert!(applied);
+    let config = stepper.client_world(0).resource::<ClientConfig>();
+    assert_eq!(config.prediction.correction_ticks_factor, 3.0);
+    assert!(states
+        .iter()
+        .all(|state| *state == NetworkingState::Connected));
+}
+
+#[test]
+fn replication_rates_need_a_restart() {
+    let old = test_settings();
+    let mut new = old.clone();
+    assert!(restart_required(&old, &new).is_empty());
+    new.replication.predicted_balls_ms += 50;
+    assert_eq!(restart_required(&old, &new), vec!["replication"]);
+}
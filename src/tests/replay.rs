@@ -0,0 +1,251 @@
+use std::fs;
+
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::Duration;
+
+use crate::app::add_headless_plugins;
+use crate::protocol::{BallMarker, Cooldowns, MatchState, PlayerId, Team};
+use crate::recording::{BodyId, Recording, RecordingPlugin};
+use crate::replay::{Divergences, ReplayPlugin, ReplayState, SeekTarget};
+use crate::settings::GameMode;
+use crate::shared::FIXED_TIMESTEP_HZ;
+use crate::tests::stepper::{test_settings, Stepper};
+use crate::MySettings;
+
+fn record_session(name: &str) -> Recording {
+    record_session_with(name, test_settings())
+}
+
+fn record_session_with(name: &str, mut settings: MySettings) -> Recording {
+    let path = std::env::temp_dir().join(format!("net-phys-{name}-{}.ron", std::process::id()));
+    settings.deterministic = true;
+    let mut stepper = Stepper::build(2, settings);
+    stepper.server.add_plugins(RecordingPlugin {
+        path: path.clone(),
+        deterministic: true,
+    });
+    stepper.init();
+
+    // push the balls around with the first player
+    stepper
+        .client_world(0)
+        .resource_mut::<ButtonInput<KeyCode>>()
+        .press(KeyCode::KeyW);
+    stepper.tick_n(150);
+    stepper
+        .client_world(0)
+        .resource_mut::<ButtonInput<KeyCode>>()
+        .release(KeyCode::KeyW);
+    stepper.tick_n(100);
+
+    let recording = Recording::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    recording
+}
+
+fn replay_app(recording: Recording) -> App {
+    let mut app = App::new();
+    add_headless_plugins(&mut app);
+    app.add_plugins(ReplayPlugin { recording })
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1.0 / FIXED_TIMESTEP_HZ,
+        )));
+    app.finish();
+    app.cleanup();
+    app
+}
+
+#[test]
+fn recording_holds_the_session() {
+    let recording = record_session("recording");
+
+    assert_eq!(recording.balls.len(), 160);
+    assert_eq!(recording.level.name, "arena");
+    assert!(recording.deterministic);
+    let spawned = recording.spawns.values().flatten().count();
+    assert_eq!(spawned, 2);
+    assert_eq!(recording.inputs.len(), 2);
+    assert!(recording.snapshots.len() >= 3);
+}
+
+#[test]
+fn replay_matches_the_recorded_snapshots() {
+    let recording = record_session("replay");
+    let snapshots = recording.snapshots.len();
+    let ticks = recording.ticks;
+    let mut app = replay_app(recording);
+
+    for _ in 0..ticks * 2 {
+        if app.world().resource::<ReplayState>().paused {
+            break;
+        }
+        app.update();
+    }
+
+    assert!(app.world().resource::<ReplayState>().next_tick >= ticks);
+    let divergences = app.world().resource::<Divergences>();
+    assert_eq!(divergences.compared, snapshots);
+    assert!(divergences.ticks.is_empty(), "{:?}", divergences.ticks);
+}
+
+#[test]
+fn replay_loads_the_recorded_kickoffs() {
+    let path = std::env::temp_dir().join(format!("net-phys-kickoff-{}.ron", std::process::id()));
+    let mut settings = test_settings();
+    settings.deterministic = true;
+    settings.common.server.level = "pillars".to_string();
+    settings.common.server.mode = GameMode::Soccer { match_secs: 300 };
+    let mut stepper = Stepper::build(2, settings);
+    stepper.server.add_plugins(RecordingPlugin {
+        path: path.clone(),
+        deterministic: true,
+    });
+    stepper.init();
+    assert!(stepper.run_until(100, |stepper| {
+        let server = stepper.server_world();
+        server.query::<&Team>().iter(server).count() == 2
+    }));
+
+    // push a ball into the goal of the blue team
+    let server = stepper.server_world();
+    let ball = server
+        .query_filtered::<Entity, With<BallMarker>>()
+        .iter(server)
+        .next()
+        .unwrap();
+    server.get_mut::<Position>(ball).unwrap().0 = Vec2::new(475.0, 0.0);
+    assert!(stepper.run_until(10, |stepper| {
+        stepper.server_world().resource::<MatchState>().red == 1
+    }));
+    stepper.tick_n(200);
+    let recording = Recording::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(recording.kickoffs.len(), 1);
+    let kickoff = *recording.kickoffs.keys().next().unwrap();
+    let ticks = recording.ticks;
+    let mut app = replay_app(recording);
+    for _ in 0..ticks * 2 {
+        if app.world().resource::<ReplayState>().paused {
+            break;
+        }
+        app.update();
+    }
+
+    // only the teleport of the ball into the goal is not replayed
+    let divergences = app.world().resource::<Divergences>();
+    assert!(divergences.compared >= 3);
+    assert!(
+        divergences.ticks.range(kickoff..).next().is_none(),
+        "{:?}",
+        divergences.ticks
+    );
+}
+
+#[test]
+fn replay_restores_the_teams() {
+    let mut settings = test_settings();
+    settings.common.server.teams = true;
+    settings.common.server.friendly_collisions = false;
+    let recording = record_session_with("teams", settings);
+    let recorded: Vec<_> = recording
+        .spawns
+        .values()
+        .flatten()
+        .map(|(_, state)| state.team.unwrap())
+        .collect();
+    assert_eq!(recorded.len(), 2);
+    assert_ne!(recorded[0].0, recorded[1].0);
+
+    let (&tick, _) = recording.snapshots.iter().nth(2).unwrap();
+    let mut app = replay_app(recording);
+    app.world_mut().resource_mut::<ReplayState>().paused = true;
+    app.world_mut().insert_resource(SeekTarget(tick));
+    app.update();
+
+    let world = app.world_mut();
+    let mut restored: Vec<_> = world
+        .query_filtered::<(&Team, &CollisionLayers), With<PlayerId>>()
+        .iter(world)
+        .map(|(team, layers)| (*team, *layers))
+        .collect();
+    restored.sort_by_key(|(team, _)| *team == Team::Blue);
+    let mut recorded = recorded;
+    recorded.sort_by_key(|(team, _)| *team == Team::Blue);
+    assert_eq!(restored, recorded);
+}
+
+#[test]
+fn replay_flags_the_ticks_that_diverge() {
+    let mut recording = record_session("divergence");
+    // the replayed players never move
+    recording.inputs.clear();
+    let ticks = recording.ticks;
+    let mut app = replay_app(recording);
+
+    for _ in 0..ticks * 2 {
+        if app.world().resource::<ReplayState>().paused {
+            break;
+        }
+        app.update();
+    }
+
+    let divergences = app.world().resource::<Divergences>();
+    assert!(!divergences.ticks.is_empty());
+    assert!(divergences.ticks.values().all(|error| *error > 0.0));
+}
+
+#[test]
+fn seeking_restores_the_closest_snapshot() {
+    let recording = record_session("seek");
+    let (&tick, bodies) = recording.snapshots.iter().nth(2).unwrap();
+    let bodies = bodies.clone();
+    let mut app = replay_app(recording);
+    app.world_mut().resource_mut::<ReplayState>().paused = true;
+    app.world_mut().insert_resource(SeekTarget(tick + 1));
+    app.update();
+
+    assert_eq!(app.world().resource::<ReplayState>().next_tick, tick + 2);
+    let world = app.world_mut();
+    let players = world
+        .query_filtered::<(), With<crate::protocol::PlayerId>>()
+        .iter(world)
+        .count();
+    let recorded_players = bodies
+        .iter()
+        .filter(|(id, _)| matches!(id, BodyId::Player(_)))
+        .count();
+    assert_eq!(players, recorded_players);
+}
+
+#[test]
+fn seeking_restores_the_recorded_cooldowns() {
+    let mut recording = record_session("cooldowns");
+    let (&tick, bodies) = recording.snapshots.iter_mut().nth(2).unwrap();
+    let cooldowns = Cooldowns {
+        dash: 30,
+        brake: 0,
+        kick: 10,
+    };
+    for (id, state) in bodies.iter_mut() {
+        if matches!(id, BodyId::Player(_)) {
+            assert!(state.cooldowns.is_some());
+            state.cooldowns = Some(cooldowns);
+        }
+    }
+    let mut app = replay_app(recording);
+    app.world_mut().resource_mut::<ReplayState>().paused = true;
+    app.world_mut().insert_resource(SeekTarget(tick));
+    app.update();
+
+    let world = app.world_mut();
+    let restored: Vec<_> = world
+        .query_filtered::<&Cooldowns, With<PlayerId>>()
+        .iter(world)
+        .copied()
+        .collect();
+    assert_eq!(restored.len(), 2);
+    assert!(restored.iter().all(|restored| *restored == cooldowns));
+}
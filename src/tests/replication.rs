@@ -0,0 +1,226 @@
+use std::fs;
+
+use avian2d::prelude::*;
+use bevy::asset::ron;
+use bevy::ecs::query::QueryFilter;
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::client::{Confirmed, Predicted};
+use lightyear::prelude::*;
+
+use crate::level::Level;
+use crate::protocol::*;
+use crate::server::ball_priority;
+use crate::tests::stepper::{test_settings, Stepper};
+
+fn count<F: QueryFilter>(world: &mut World) -> usize {
+    world.query_filtered::<(), F>().iter(world).count()
+}
+
+fn player_position<F: QueryFilter>(world: &mut World, client_id: u64) -> Option<Vec2> {
+    world
+        .query_filtered::<(&PlayerId, &Position), F>()
+        .iter(world)
+        .find(|(id, _)| id.0 == ClientId::Netcode(client_id))
+        .map(|(_, position)| position.0)
+}
+
+#[test]
+fn players_are_replicated_to_every_client() {
+    let mut stepper = Stepper::new(2, test_settings());
+
+    let replicated = stepper.run_until(100, |stepper| {
+        count::<With<PlayerId>>(stepper.server_world()) == 2
+            && (0..2).all(|i| {
+                count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(i)) == 2
+            })
+    });
+    assert!(replicated);
+
+    let server = stepper.server_world();
+    let mut ids: Vec<_> = server
+        .query::<&PlayerId>()
+        .iter(server)
+        .map(|id| id.0.to_bits())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+#[test]
+fn balls_are_replicated_to_every_client() {
+    let mut stepper = Stepper::new(2, test_settings());
+    stepper.tick_n(20);
+
+    let server = stepper.server_world();
+    let mut server_positions: Vec<_> = server
+        .query_filtered::<&Position, With<BallMarker>>()
+        .iter(server)
+        .map(|position| position.0.round().to_array())
+        .collect();
+    server_positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(server_positions.len(), 160);
+
+    for i in 0..2 {
+        let client = stepper.client_world(i);
+        let mut client_positions: Vec<_> = client
+            .query_filtered::<&Position, (With<BallMarker>, With<Confirmed>)>()
+            .iter(client)
+            .map(|position| position.0.round().to_array())
+            .collect();
+        client_positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(client_positions, server_positions);
+    }
+}
+
+#[test]
+fn client_inputs_move_the_server_player() {
+    let mut stepper = Stepper::new(2, test_settings());
+    assert!(stepper.run_until(100, |stepper| {
+        count::<With<PlayerId>>(stepper.server_world()) == 2
+    }));
+    let start_1 = player_position::<()>(stepper.server_world(), 1).unwrap();
+    let start_2 = player_position::<()>(stepper.server_world(), 2).unwrap();
+
+    stepper
+        .client_world(0)
+        .resource_mut::<ButtonInput<KeyCode>>()
+        .press(KeyCode::KeyD);
+    stepper.tick_n(30);
+
+    let end_1 = player_position::<()>(stepper.server_world(), 1).unwrap();
+    let end_2 = player_position::<()>(stepper.server_world(), 2).unwrap();
+    assert!(end_1.x > start_1.x + 10.0, "{start_1} -> {end_1}");
+    assert_eq!(end_2, start_2);
+
+    // the client predicted its own movement
+    let predicted_1 = player_position::<With<Predicted>>(stepper.client_world(0), 1).unwrap();
+    assert!(predicted_1.x > start_1.x + 10.0, "{start_1} -> {predicted_1}");
+}
+
+#[test]
+fn inputs_are_forwarded_to_other_clients() {
+    let mut stepper = Stepper::new(2, test_settings());
+    assert!(stepper.run_until(100, |stepper| {
+        count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(1)) == 2
+    }));
+
+    stepper
+        .client_world(0)
+        .resource_mut::<ButtonInput<KeyCode>>()
+        .press(KeyCode::KeyD);
+
+    // client 2 predicts the remote player with the inputs relayed by the server
+    let forwarded = stepper.run_until(30, |stepper| {
+        let world = stepper.client_world(1);
+        world
+            .query_filtered::<(&PlayerId, &ActionState<PlayerActions>), With<Predicted>>()
+            .iter(world)
+            .any(|(id, action)| {
+                id.0 == ClientId::Netcode(1) && action.pressed(&PlayerActions::Move)
+            })
+    });
+    assert!(forwarded);
+}
+
+#[test]
+fn players_spawn_at_the_first_free_spawn_point() {
+    let path = std::env::temp_dir().join(format!("net-phys-spawns-{}.ron", std::process::id()));
+    // the first point overlaps a ball, the second and third are the same
+    let level = Level {
+        spawn_points: vec![
+            Vec2::new(20.0, -40.0),
+            Vec2::new(-100.0, -250.0),
+            Vec2::new(-100.0, -250.0),
+            Vec2::new(100.0, -250.0),
+        ],
+        ..default()
+    };
+    fs::write(&path, ron::to_string(&level).unwrap()).unwrap();
+    let mut settings = test_settings();
+    settings.common.server.level = path.to_string_lossy().into_owned();
+    let mut stepper = Stepper::new(2, settings);
+    fs::remove_file(&path).unwrap();
+    assert!(stepper.run_until(100, |stepper| {
+        count::<With<PlayerId>>(stepper.server_world()) == 2
+    }));
+
+    let server = stepper.server_world();
+    let mut positions: Vec<_> = server
+        .query::<(&PlayerId, &Position, &ColorComponent)>()
+        .iter(server)
+        .map(|(id, position, color)| {
+            assert_eq!(color.0, crate::shared::color_from_id(id.0));
+            position.0.to_array()
+        })
+        .collect();
+    positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(positions, vec![[-100.0, -250.0], [100.0, -250.0]]);
+}
+
+#[test]
+fn player_colors_have_distinct_hues() {
+    let mut hues: Vec<f32> = (0..=16)
+        .map(|id| Hsla::from(crate::shared::color_from_id(ClientId::Netcode(id))).hue)
+        .collect();
+    hues.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let closest = hues
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .fold(f32::MAX, f32::min);
+    assert!(closest > 10.0, "{hues:?}");
+}
+
+#[test]
+fn replication_rates_are_set_per_entity_kind() {
+    let mut rates = test_settings().replication;
+    rates.predicted_players_ms = 0;
+    rates.interpolated_players_ms = 100;
+    rates.predicted_balls_ms = 50;
+    rates.interpolated_balls_ms = 200;
+    assert_eq!(rates.players(false), Duration::from_millis(100));
+    assert_eq!(rates.balls(true), Duration::from_millis(50));
+    // the replication systems run as often as the fastest kind
+    assert_eq!(rates.send_interval(), Duration::ZERO);
+    rates.predicted_players_ms = 30;
+    assert_eq!(rates.send_interval(), Duration::from_millis(30));
+    // the owner of a player predicts it while the others interpolate it
+    assert!(rates.player_rates_match(true));
+    assert!(!rates.player_rates_match(false));
+    rates.interpolated_players_ms = 30;
+    assert!(rates.player_rates_match(false));
+    // the predicted balls are sent with the players, unless each ball has a group of its own
+    assert!(!rates.ball_rates_match(true));
+    assert!(rates.ball_rates_match(false));
+    rates.predicted_balls_ms = 30;
+    assert!(rates.ball_rates_match(true));
+    rates.predicted_balls_ms = 50;
+    rates.bandwidth_cap_kbps = Some(2000);
+    assert!(rates.ball_rates_match(true));
+}
+
+#[test]
+fn close_balls_have_the_highest_priority() {
+    assert!(ball_priority(0.0) > ball_priority(200.0));
+    assert!(ball_priority(200.0) > ball_priority(2000.0));
+    assert!(ball_priority(f32::INFINITY) > 0.0);
+
+    let mut settings = test_settings();
+    settings.replication.bandwidth_cap_kbps = Some(2000);
+    let mut stepper = Stepper::new(1, settings);
+    assert!(stepper.run_until(100, |stepper| {
+        count::<With<PlayerId>>(stepper.server_world()) == 1
+    }));
+    stepper.tick_n(2);
+    let player = player_position::<()>(stepper.server_world(), 1).unwrap();
+    let server = stepper.server_world();
+    let mut balls: Vec<_> = server
+        .query_filtered::<(&Position, &ReplicationGroup), With<BallMarker>>()
+        .iter(server)
+        .map(|(position, group)| (position.distance(player), group.priority()))
+        .collect();
+    balls.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (closest, farthest) = (balls[0], balls[balls.len() - 1]);
+    assert!(closest.1 > farthest.1, "{closest:?} {farthest:?}");
+}
@@ -0,0 +1,75 @@
+use crate::settings::{read_settings, GameMode};
+use crate::MySettings;
+
+/// Settings file written before most of the fields were added
+const FIRST_SETTINGS: &str = r#"MySettings(
+  input_delay_ticks: 2,
+  correction_ticks_factor: 1.5,
+  predict_all: true,
+  show_confirmed: false,
+  common: Settings(
+    client: ClientSettings(
+            client_id: 0,
+            client_port: 0,
+            server_addr: "127.0.0.1",
+            server_port: 38000,
+            transport: Udp,
+            conditioner: None,
+        ),
+        server: ServerSettings(
+            headless: true,
+            conditioner: None,
+            transport: [Udp(local_port: 38000)],
+        ),
+        shared: SharedSettings(
+            protocol_id: 0,
+            private_key: (0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0),
+            compression: None,
+        )
+    )
+)"#;
+
+#[test]
+fn older_settings_files_still_load() {
+    let settings: MySettings = read_settings(FIRST_SETTINGS).unwrap();
+    let embedded: MySettings = read_settings(include_str!("../../assets/settings.ron")).unwrap();
+    let (server, client) = (&settings.common.server, &settings.common.client);
+    assert_eq!(client.client_id, Some(0));
+    assert_eq!(client.token_port, None);
+    assert_eq!(server.max_clients, embedded.common.server.max_clients);
+    assert_eq!(server.level, embedded.common.server.level);
+    assert_eq!(server.mode, GameMode::Sandbox);
+    assert_eq!(
+        server.reconnect_grace_ms,
+        embedded.common.server.reconnect_grace_ms
+    );
+    assert_eq!(server.certificate, embedded.common.server.certificate);
+    assert_eq!(settings.replication, embedded.replication);
+    assert!(settings.show_diagnostics && server.friendly_collisions);
+}
+
+#[test]
+fn settings_files_are_layered_over_the_embedded_ones() {
+    let settings: MySettings = read_settings(
+        "MySettings(
+            input_delay_ticks: 6,
+            common: Settings(
+                server: ServerSettings(max_clients: 4),
+            ),
+        )",
+    )
+    .unwrap();
+    let embedded: MySettings = read_settings(include_str!("../../assets/settings.ron")).unwrap();
+    assert_eq!(settings.input_delay_ticks, 6);
+    assert_eq!(settings.common.server.max_clients, 4);
+    assert_eq!(settings.predict_all, embedded.predict_all);
+    assert_eq!(
+        settings.common.server.transport,
+        embedded.common.server.transport
+    );
+    assert_eq!(settings.common.client, embedded.common.client);
+    assert_eq!(
+        settings.common.shared.private_key,
+        embedded.common.shared.private_key
+    );
+}
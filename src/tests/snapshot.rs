@@ -0,0 +1,273 @@
+use avian2d::prelude::*;
+use bevy::diagnostic::DiagnosticsStore;
+use bevy::prelude::*;
+use lightyear::prelude::client::Confirmed;
+use lightyear::transport::io::IoDiagnosticsPlugin;
+
+use crate::level::{BallPattern, Level};
+use crate::protocol::*;
+use crate::settings::BallSnapshots;
+use crate::snapshot::{ClientSnapshots, QuantizedBody, SnapshotBody, SnapshotHistory};
+use crate::tests::stepper::{test_settings, Stepper};
+
+fn body(position: Vec2, velocity: Vec2) -> QuantizedBody {
+    QuantizedBody::new(
+        &Position(position),
+        &Rotation::radians(position.x),
+        &LinearVelocity(velocity),
+        &AngularVelocity(velocity.y / 10.0),
+    )
+}
+
+fn bodies(states: &[QuantizedBody]) -> Vec<SnapshotBody> {
+    states
+        .iter()
+        .enumerate()
+        .map(|(id, state)| SnapshotBody {
+            id: id as u32,
+            state: *state,
+            distance: state.position().length(),
+        })
+        .collect()
+}
+
+#[test]
+fn quantized_states_are_stable() {
+    let state = body(Vec2::new(12.345, -678.9), Vec2::new(-3.21, 45.6));
+    let requantized = QuantizedBody::new(
+        &state.position(),
+        &state.rotation(),
+        &state.linear_velocity(),
+        &state.angular_velocity(),
+    );
+    assert_eq!(requantized, state);
+    assert!(state.position().distance(Vec2::new(12.345, -678.9)) < 0.02);
+    // a full turn wraps around
+    let turn = QuantizedBody::new(
+        &Position::default(),
+        &Rotation::radians(-1e-6),
+        &LinearVelocity::default(),
+        &AngularVelocity::default(),
+    );
+    assert_eq!(turn.rotation, 0);
+}
+
+#[test]
+fn snapshots_are_delta_encoded_against_acknowledged_states() {
+    let mut server = ClientSnapshots::default();
+    let mut client = SnapshotHistory::default();
+    let mut states: Vec<_> = (0..100)
+        .map(|i| body(Vec2::new(i as f32 * 40.0, 300.0), Vec2::new(50.0, -20.0)))
+        .collect();
+
+    let (sequence, full) = server.build(&bodies(&states), usize::MAX).unwrap();
+    let decoded = client.decode(sequence, &full).unwrap().bodies;
+    assert_eq!(
+        decoded.iter().map(|(_, state)| *state).collect::<Vec<_>>(),
+        states
+    );
+    server.ack(sequence);
+
+    // a few balls moved a little
+    for state in states.iter_mut().take(10) {
+        *state = body(
+            state.position().0 + Vec2::new(0.8, -0.3),
+            Vec2::new(48.0, -20.0),
+        );
+    }
+    let (sequence, delta) = server.build(&bodies(&states), usize::MAX).unwrap();
+    assert!(
+        delta.len() * 10 < full.len(),
+        "{} / {}",
+        delta.len(),
+        full.len()
+    );
+    let decoded = client.decode(sequence, &delta).unwrap();
+    assert_eq!(decoded.skipped, 0);
+    assert_eq!(decoded.bodies.len(), 10);
+    for (id, state) in decoded.bodies {
+        assert_eq!(state, states[id as usize]);
+    }
+}
+
+#[test]
+fn balls_without_a_baseline_are_skipped() {
+    let mut server = ClientSnapshots::default();
+    let mut client = SnapshotHistory::default();
+    let mut states = vec![body(Vec2::ZERO, Vec2::new(10.0, 0.0)); 5];
+    let (sequence, _) = server.build(&bodies(&states), usize::MAX).unwrap();
+    // the client never received the first snapshot
+    server.ack(sequence);
+    states[0] = body(Vec2::new(1.0, 0.0), Vec2::new(10.0, 0.0));
+    let (sequence, data) = server.build(&bodies(&states), usize::MAX).unwrap();
+    let decoded = client.decode(sequence, &data).unwrap();
+    assert_eq!(decoded.skipped, 1);
+    assert!(decoded.bodies.is_empty());
+}
+
+#[test]
+fn balls_at_rest_are_not_resent() {
+    let mut server = ClientSnapshots::default();
+    let states = vec![body(Vec2::ZERO, Vec2::ZERO); 10];
+    let (sequence, _) = server.build(&bodies(&states), usize::MAX).unwrap();
+    // resent until the client acknowledges them
+    assert!(server.build(&bodies(&states), usize::MAX).is_some());
+    server.ack(sequence);
+    assert!(server.build(&bodies(&states), usize::MAX).is_none());
+}
+
+#[test]
+fn the_budget_goes_to_the_most_urgent_balls_first() {
+    let mut server = ClientSnapshots::default();
+    let mut client = SnapshotHistory::default();
+    let states = vec![
+        body(Vec2::new(1000.0, 0.0), Vec2::ZERO),
+        body(Vec2::new(1000.0, 0.0), Vec2::new(400.0, 0.0)),
+        body(Vec2::new(10.0, 0.0), Vec2::ZERO),
+    ];
+    // room for a single ball: the fast one first, even far away, then the close one
+    let (sequence, data) = server.build(&bodies(&states), 14).unwrap();
+    let ids: Vec<_> = client
+        .decode(sequence, &data)
+        .unwrap()
+        .bodies
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    assert_eq!(ids, vec![1]);
+    let (sequence, data) = server.build(&bodies(&states), 14).unwrap();
+    let ids: Vec<_> = client
+        .decode(sequence, &data)
+        .unwrap()
+        .bodies
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    assert_eq!(ids, vec![2]);
+    // the ball that waited becomes urgent enough
+    let sent = (0..20).any(|_| {
+        let (sequence, data) = server.build(&bodies(&states), 14).unwrap();
+        client
+            .decode(sequence, &data)
+            .unwrap()
+            .bodies
+            .iter()
+            .any(|(id, _)| *id == 0)
+    });
+    assert!(sent);
+}
+
+#[test]
+fn snapshots_update_the_confirmed_balls() {
+    let mut settings = test_settings();
+    settings.common.server.ball_snapshots = Some(BallSnapshots {
+        bandwidth_kbps: 10_000,
+    });
+    let mut stepper = Stepper::new(1, settings);
+    assert!(stepper.run_until(100, |stepper| {
+        let client = stepper.client_world(0);
+        client
+            .query_filtered::<(), (With<BallId>, With<Confirmed>)>()
+            .iter(client)
+            .count()
+            > 0
+    }));
+
+    let server = stepper.server_world();
+    // the lowest ball has room to move down
+    let (ball, id) = server
+        .query::<(Entity, &BallId, &Position)>()
+        .iter(server)
+        .min_by(|(_, _, a), (_, _, b)| a.y.total_cmp(&b.y))
+        .map(|(entity, id, _)| (entity, *id))
+        .unwrap();
+    server.get_mut::<LinearVelocity>(ball).unwrap().0 = Vec2::new(0.0, -100.0);
+    let confirmed_position = |stepper: &mut Stepper| {
+        let client = stepper.client_world(0);
+        client
+            .query_filtered::<(&BallId, &Position), With<Confirmed>>()
+            .iter(client)
+            .find(|(ball_id, _)| **ball_id == id)
+            .map(|(_, position)| position.0)
+    };
+    let start = confirmed_position(&mut stepper).unwrap();
+    assert!(stepper.run_until(30, |stepper| {
+        confirmed_position(stepper).is_some_and(|position| position.y < start.y - 5.0)
+    }));
+    // the client receives the state of the server, which stays on the quantization grid
+    let server_position = stepper.server_world().get::<Position>(ball).unwrap().0;
+    assert_eq!(server_position, (server_position * 64.0).round() / 64.0);
+    let client_position = confirmed_position(&mut stepper).unwrap();
+    assert_eq!(client_position, (client_position * 64.0).round() / 64.0);
+}
+
+/// Bytes received per tick by a client when a fifth of 5000 balls move, measured by its
+/// [`IoDiagnosticsPlugin`]
+fn received_bytes_per_tick(ball_snapshots: Option<BallSnapshots>) -> f64 {
+    const TICKS: usize = 300;
+    const BALLS: usize = 5000;
+    let mut settings = test_settings();
+    settings.common.server.ball_snapshots = ball_snapshots;
+    let mut stepper = Stepper::build(1, settings);
+    // an open field, so that the balls keep moving
+    let mut level = Level::default();
+    level.walls.clear();
+    level.obstacles.clear();
+    level.balls = vec![BallPattern::Grid {
+        center: Vec2::ZERO,
+        columns: 100,
+        rows: 50,
+        spacing: 40.0,
+    }];
+    stepper.server_world().insert_resource(level);
+    let client = stepper.clients[0].app_mut();
+    if !client.is_plugin_added::<IoDiagnosticsPlugin>() {
+        client.add_plugins(IoDiagnosticsPlugin);
+    }
+    stepper.init();
+    assert!(stepper.run_until(1000, |stepper| {
+        let client = stepper.client_world(0);
+        client
+            .query_filtered::<(), (With<BallMarker>, With<Confirmed>)>()
+            .iter(client)
+            .count()
+            == BALLS
+    }));
+
+    let server = stepper.server_world();
+    let mut balls = server.query_filtered::<&mut LinearVelocity, With<BallMarker>>();
+    for (index, mut velocity) in balls.iter_mut(server).enumerate() {
+        if index % 5 == 0 {
+            velocity.0 = Vec2::from_angle(index as f32) * 200.0;
+        }
+    }
+    let mut bytes = 0.0;
+    for _ in 0..TICKS {
+        stepper.tick();
+        // measured in KB/s
+        let kb_per_s = stepper
+            .client_world(0)
+            .resource::<DiagnosticsStore>()
+            .get(&IoDiagnosticsPlugin::BYTES_IN)
+            .and_then(|diagnostic| diagnostic.value())
+            .unwrap_or_default();
+        bytes += kb_per_s * 1000.0 * stepper.tick_duration.as_secs_f64();
+    }
+    bytes / TICKS as f64
+}
+
+/// Compares the bytes per tick a client receives with the full state of the balls replicated by
+/// lightyear and with the ball snapshots
+/// ```sh
+/// cargo test --release snapshot_bandwidth -- --ignored --nocapture
+/// ```
+#[test]
+#[ignore = "benchmark"]
+fn snapshot_bandwidth() {
+    let full_state = received_bytes_per_tick(None);
+    println!("full state: {full_state:.0} bytes per tick");
+    for bandwidth_kbps in [10_000, 256] {
+        let snapshots = received_bytes_per_tick(Some(BallSnapshots { bandwidth_kbps }));
+        println!("snapshots of {bandwidth_kbps} kbps: {snapshots:.0} bytes per tick");
+    }
+}
@@ -0,0 +1,79 @@
+use avian2d::prelude::*;
+use bevy::prelude::*;
+
+use crate::level::Level;
+use crate::protocol::*;
+use crate::server::PLAYER_RADIUS;
+use crate::settings::GameMode;
+use crate::soccer::kickoff_positions;
+use crate::tests::stepper::{test_settings, Stepper};
+
+#[test]
+fn teams_spawn_on_their_half() {
+    let level = Level::load("pillars").unwrap();
+    assert!(level.has_goals());
+    assert!(!Level::default().has_goals());
+    let red = level.team_spawn_points(Team::Red);
+    let blue = level.team_spawn_points(Team::Blue);
+    assert!(!red.is_empty() && red.iter().all(|point| point.x < 0.0));
+    assert!(!blue.is_empty() && blue.iter().all(|point| point.x > 0.0));
+}
+
+#[test]
+fn kickoff_does_not_stack_the_players() {
+    let level = Level::load("pillars").unwrap();
+    let spawn_points = level.team_spawn_points(Team::Red);
+    let players = spawn_points.len() * 3;
+    let ball = (spawn_points[0], BALL_SIZE);
+    let mut occupied = vec![ball];
+    let positions = kickoff_positions(&spawn_points, level.bounds, &mut occupied, players);
+
+    assert_eq!(positions.len(), players);
+    assert_eq!(occupied.len(), players + 1);
+    for (index, position) in positions.iter().enumerate() {
+        assert!(position.distance(ball.0) >= BALL_SIZE + PLAYER_RADIUS);
+        for other in &positions[index + 1..] {
+            assert!(position.distance(*other) >= 2.0 * PLAYER_RADIUS);
+        }
+    }
+}
+
+#[test]
+fn goals_are_scored_by_the_server_and_replicated() {
+    let mut settings = test_settings();
+    settings.common.server.level = "pillars".to_string();
+    settings.common.server.mode = GameMode::Soccer { match_secs: 300 };
+    let mut stepper = Stepper::new(2, settings);
+    assert!(stepper.run_until(100, |stepper| {
+        let server = stepper.server_world();
+        server.query::<&Team>().iter(server).count() == 2
+    }));
+    let server = stepper.server_world();
+    let mut teams: Vec<_> = server.query::<&Team>().iter(server).copied().collect();
+    teams.sort_by_key(|team| *team == Team::Blue);
+    assert_eq!(teams, vec![Team::Red, Team::Blue]);
+
+    // push a ball into the goal of the blue team
+    let (ball, start) = server
+        .query_filtered::<(Entity, &Position), With<BallMarker>>()
+        .iter(server)
+        .map(|(entity, position)| (entity, position.0))
+        .next()
+        .unwrap();
+    server.get_mut::<Position>(ball).unwrap().0 = Vec2::new(475.0, 0.0);
+    assert!(stepper.run_until(10, |stepper| {
+        stepper.server_world().resource::<MatchState>().red == 1
+    }));
+    // kickoff
+    assert_eq!(
+        stepper.server_world().get::<Position>(ball).unwrap().0,
+        start
+    );
+
+    assert!(stepper.run_until(100, |stepper| {
+        stepper
+            .client_world(0)
+            .get_resource::<MatchState>()
+            .is_some_and(|state| state.red == 1 && state.blue == 0)
+    }));
+}
@@ -0,0 +1,84 @@
+use bevy::ecs::query::QueryFilter;
+use bevy::prelude::*;
+use lightyear::prelude::client::{ConnectionManager, Interpolated, NetworkingState, Predicted};
+
+use crate::protocol::*;
+use crate::tests::stepper::{test_settings, Stepper};
+
+fn count<F: QueryFilter>(world: &mut World) -> usize {
+    world.query_filtered::<(), F>().iter(world).count()
+}
+
+fn is_connected(world: &World) -> bool {
+    *world.resource::<State<NetworkingState>>().get() == NetworkingState::Connected
+}
+
+#[test]
+fn spectators_interpolate_every_body() {
+    let settings = test_settings();
+    let mut spectator = settings.clone();
+    spectator.common.client.spectator = true;
+    let mut stepper = Stepper::build_with_clients(settings.clone(), vec![settings, spectator]);
+    stepper.init();
+
+    let replicated = stepper.run_until(100, |stepper| {
+        count::<(With<BallMarker>, With<Interpolated>)>(stepper.client_world(1)) == 160
+            && count::<(With<PlayerId>, With<Interpolated>)>(stepper.client_world(1)) == 1
+    });
+    assert!(replicated);
+    stepper.tick_n(20);
+
+    // the spectator did not spawn a player and predicts nothing
+    assert_eq!(count::<With<PlayerId>>(stepper.server_world()), 1);
+    assert_eq!(count::<With<Predicted>>(stepper.client_world(1)), 0);
+    // the player still predicts every body
+    assert_eq!(count::<(With<BallMarker>, With<Predicted>)>(stepper.client_world(0)), 160);
+    assert_eq!(count::<(With<BallMarker>, With<Interpolated>)>(stepper.client_world(0)), 0);
+}
+
+#[test]
+fn spectators_do_not_count_toward_max_clients() {
+    let mut settings = test_settings();
+    settings.common.server.max_clients = 1;
+    let mut spectator = settings.clone();
+    spectator.common.client.spectator = true;
+    let mut stepper = Stepper::build_with_clients(
+        settings.clone(),
+        vec![spectator, settings.clone(), settings],
+    );
+    stepper.finish();
+
+    // one of the two players is refused
+    let settled = stepper.run_until(200, |stepper| {
+        let connected: Vec<_> = stepper
+            .clients
+            .iter_mut()
+            .map(|client| is_connected(client.app_mut().world()))
+            .collect();
+        connected[0]
+            && connected[1] != connected[2]
+            && count::<With<PlayerId>>(stepper.server_world()) == 1
+            && count::<(With<PlayerId>, With<Interpolated>)>(stepper.client_world(0)) == 1
+    });
+    assert!(settled);
+    stepper.tick_n(20);
+    assert_eq!(count::<With<PlayerId>>(stepper.server_world()), 1);
+    assert!(is_connected(stepper.client_world(0)));
+}
+
+#[test]
+fn joining_twice_does_not_spawn_another_player() {
+    let mut stepper = Stepper::new(1, test_settings());
+    assert!(stepper.run_until(100, |stepper| {
+        count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(0)) == 1
+    }));
+
+    stepper
+        .client_world(0)
+        .resource_mut::<ConnectionManager>()
+        .send_message::<LobbyChannel, _>(&JoinGame { spectator: false })
+        .unwrap();
+    stepper.tick_n(20);
+    assert_eq!(count::<With<PlayerId>>(stepper.server_world()), 1);
+    assert_eq!(count::<(With<PlayerId>, With<Predicted>)>(stepper.client_world(0)), 1);
+}
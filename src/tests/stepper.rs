@@ -0,0 +1,159 @@
+//! Runs a server and several clients in the same process, connected with crossbeam channels
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::{Duration, Instant};
+use lightyear::prelude::server::ServerTransport;
+use lightyear::prelude::*;
+
+use crate::app::{server_app, Apps};
+use crate::auth::TokenClientPlugin;
+use crate::settings::read_settings;
+use crate::swarm::channel_client;
+use crate::MySettings;
+
+/// Settings used by the tests: the embedded defaults without windows, sockets or conditioners
+pub(crate) fn test_settings() -> MySettings {
+    let mut settings: MySettings = read_settings(include_str!("../../assets/settings.ron"))
+        .expect("the embedded settings are valid");
+    settings.common.server.headless = true;
+    settings.common.server.transport.clear();
+    settings.common.server.conditioner = None;
+    settings.common.client.headless = true;
+    settings.common.client.conditioner = None;
+    // the tests run in parallel, and can't all bind the token service port
+    settings.common.server.token_port = None;
+    settings.common.client.token_port = None;
+    settings
+}
+
+/// A local port that was free when this was called
+pub(crate) fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("a free port")
+        .port()
+}
+
+pub(crate) struct Stepper {
+    pub(crate) server: Apps,
+    pub(crate) clients: Vec<Apps>,
+    current_time: Instant,
+    pub(crate) tick_duration: Duration,
+}
+
+impl Stepper {
+    /// Connect `num_clients` clients, with ids starting at 1, and wait until they are synced
+    pub(crate) fn new(num_clients: u64, settings: MySettings) -> Self {
+        let mut stepper = Self::build(num_clients, settings);
+        stepper.init();
+        stepper
+    }
+
+    /// Build the apps without running them, so that more plugins can be added before [`Self::init`]
+    pub(crate) fn build(num_clients: u64, settings: MySettings) -> Self {
+        let client_settings = vec![settings.clone(); num_clients as usize];
+        Self::build_with_clients(settings, client_settings)
+    }
+
+    /// Build the apps with different settings for each client, the ids still start at 1
+    pub(crate) fn build_with_clients(
+        settings: MySettings,
+        client_settings: Vec<MySettings>,
+    ) -> Self {
+        let mut server_channels = vec![];
+        let mut clients = vec![];
+        for (index, client_settings) in client_settings.into_iter().enumerate() {
+            let client_id = index as u64 + 1;
+            let (mut client, channel) = channel_client(index, client_id, &client_settings);
+            if let Some(port) = client_settings.common.client.token_port {
+                client.add_plugins(TokenClientPlugin {
+                    service_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                    client_id,
+                });
+            }
+            server_channels.push(channel);
+            clients.push(client);
+        }
+
+        let (app, config) = server_app(
+            settings.common.clone(),
+            vec![ServerTransport::Channels {
+                channels: server_channels,
+            }],
+        );
+        let mut server = Apps::Server { app, config };
+        crate::add_plugins(&mut server, &settings);
+
+        Self {
+            server,
+            clients,
+            current_time: Instant::now(),
+            tick_duration: Duration::from_secs_f64(1.0 / crate::shared::FIXED_TIMESTEP_HZ),
+        }
+    }
+
+    pub(crate) fn init(&mut self) {
+        self.finish();
+        // the server starts and the clients connect in their Startup systems
+        let synced = self.run_until(200, |stepper| {
+            stepper.clients.iter_mut().all(|client| {
+                client
+                    .app_mut()
+                    .world()
+                    .resource::<client::ConnectionManager>()
+                    .is_synced()
+            })
+        });
+        assert!(synced, "the clients did not sync with the server");
+    }
+
+    /// Finish building the apps, without waiting for the clients to connect
+    pub(crate) fn finish(&mut self) {
+        for apps in std::iter::once(&mut self.server).chain(self.clients.iter_mut()) {
+            let app = apps.app_mut();
+            app.finish();
+            app.cleanup();
+        }
+    }
+
+    pub(crate) fn server_world(&mut self) -> &mut World {
+        self.server.app_mut().world_mut()
+    }
+
+    pub(crate) fn client_world(&mut self, index: usize) -> &mut World {
+        self.clients[index].app_mut().world_mut()
+    }
+
+    /// Advance the time by one fixed timestep and update every app
+    pub(crate) fn tick(&mut self) {
+        self.current_time += self.tick_duration;
+        for apps in std::iter::once(&mut self.server).chain(self.clients.iter_mut()) {
+            let app = apps.app_mut();
+            app.insert_resource(TimeUpdateStrategy::ManualInstant(self.current_time));
+            app.update();
+        }
+    }
+
+    pub(crate) fn tick_n(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Tick until `condition` holds, returns false if it still doesn't after `max_ticks`
+    pub(crate) fn run_until(
+        &mut self,
+        max_ticks: usize,
+        mut condition: impl FnMut(&mut Self) -> bool,
+    ) -> bool {
+        for _ in 0..max_ticks {
+            if condition(self) {
+                return true;
+            }
+            self.tick();
+        }
+        condition(self)
+    }
+}
@@ -0,0 +1,60 @@
+use std::thread;
+
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use lightyear::prelude::client::{ClientConfig, NetConfig};
+
+use crate::protocol::*;
+use crate::swarm::{local_swarm, SwarmClients};
+use crate::tests::stepper::test_settings;
+
+#[test]
+fn swarm_clients_join_and_move() {
+    let settings = test_settings();
+    let mut swarm = local_swarm(&settings, 3);
+    let server = swarm.app_mut();
+    server.finish();
+    server.cleanup();
+
+    // the apps keep their own clocks, so they are updated in real time
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut start = None;
+    let moved = loop {
+        server.update();
+        let world = server.world_mut();
+        let positions: Vec<_> = world
+            .query_filtered::<&Position, With<PlayerId>>()
+            .iter(world)
+            .map(|position| position.0)
+            .collect();
+        if positions.len() == 3 {
+            let start = start.get_or_insert(positions.clone());
+            if start.iter().zip(&positions).all(|(a, b)| a.distance(*b) > 10.0) {
+                break true;
+            }
+        }
+        if Instant::now() > deadline {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    assert!(moved, "the swarm players did not all join and move");
+
+    let latencies: Vec<_> = server
+        .world()
+        .non_send_resource::<SwarmClients>()
+        .0
+        .iter()
+        .map(|client| match &client.world().resource::<ClientConfig>().net {
+            NetConfig::Netcode { io, .. } => io.conditioner.as_ref().map(|c| c.incoming_latency),
+            _ => None,
+        })
+        .collect();
+    let expected: Vec<_> = settings
+        .swarm_conditioners
+        .iter()
+        .map(|c| Some(Duration::from_millis(c.latency_ms as u64)))
+        .collect();
+    assert_eq!(latencies, expected);
+}
@@ -0,0 +1,98 @@
+use avian2d::prelude::*;
+use bevy::prelude::*;
+use lightyear::prelude::client::{ConnectionManager, Predicted};
+use lightyear::prelude::ClientId;
+
+use crate::protocol::*;
+use crate::team::{team_layers, Teams};
+use crate::tests::stepper::{test_settings, Stepper};
+
+#[test]
+fn teams_are_balanced_and_kept() {
+    let ids: Vec<_> = (0..3).map(ClientId::Netcode).collect();
+    let mut teams = Teams::default();
+    assert_eq!(teams.assign(ids[0], &ids[..1]), Team::Red);
+    assert_eq!(teams.assign(ids[1], &ids[..2]), Team::Blue);
+    assert_eq!(teams.assign(ids[2], &ids[..3]), Team::Red);
+    // reconnecting keeps the team, even if it is now the larger one
+    assert_eq!(teams.assign(ids[0], &ids[..3]), Team::Red);
+}
+
+#[test]
+fn team_changes_keep_the_teams_balanced() {
+    let ids: Vec<_> = (0..3).map(ClientId::Netcode).collect();
+    let mut teams = Teams::default();
+    for (index, id) in ids.iter().enumerate() {
+        teams.assign(*id, &ids[..=index]);
+    }
+    // two red players and one blue
+    assert!(!teams.change(ids[1], Team::Red, &ids));
+    assert!(teams.change(ids[2], Team::Blue, &ids));
+    assert_eq!(teams.assign(ids[2], &ids), Team::Blue);
+}
+
+#[test]
+fn friendly_collisions_are_optional() {
+    let red = team_layers(Team::Red, false);
+    let blue = team_layers(Team::Blue, false);
+    assert!(!red.interacts_with(red));
+    assert!(red.interacts_with(blue));
+    assert!(blue.interacts_with(red));
+    let red = team_layers(Team::Red, true);
+    assert!(red.interacts_with(red));
+}
+
+#[test]
+fn players_collide_with_the_default_layer() {
+    let level = CollisionLayers::default();
+    for team in Team::ALL {
+        for friendly_collisions in [false, true] {
+            let player = team_layers(team, friendly_collisions);
+            assert!(player.interacts_with(level));
+            assert!(level.interacts_with(player));
+        }
+    }
+}
+
+#[test]
+fn team_change_is_replicated() {
+    let mut settings = test_settings();
+    settings.common.server.teams = true;
+    let mut stepper = Stepper::new(1, settings);
+    assert!(stepper.run_until(100, |stepper| {
+        let client = stepper.client_world(0);
+        client
+            .query_filtered::<&Team, With<Predicted>>()
+            .iter(client)
+            .any(|team| *team == Team::Red)
+    }));
+
+    stepper
+        .client_world(0)
+        .resource_mut::<ConnectionManager>()
+        .send_message::<LobbyChannel, _>(&RequestTeam { team: Team::Blue })
+        .unwrap();
+    assert!(stepper.run_until(100, |stepper| {
+        let client = stepper.client_world(0);
+        client
+            .query_filtered::<&Team, With<Predicted>>()
+            .iter(client)
+            .any(|team| *team == Team::Blue)
+    }));
+    let server = stepper.server_world();
+    let (team, layers) = server
+        .query_filtered::<(&Team, &CollisionLayers), With<PlayerId>>()
+        .single(server);
+    assert_eq!(*team, Team::Blue);
+    assert_eq!(*layers, team_layers(Team::Blue, true));
+
+    // the predicted player takes the team color
+    let client = stepper.client_world(0);
+    let color = client
+        .query_filtered::<&ColorComponent, (With<PlayerId>, With<Predicted>)>()
+        .iter(client)
+        .map(|color| Hsva::from(color.0).hue)
+        .next()
+        .unwrap();
+    assert!((color - Hsva::from(Team::Blue.color()).hue).abs() < 1.0);
+}
@@ -0,0 +1,70 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::thread;
+
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use lightyear::prelude::*;
+
+use crate::app::{client_app, server_app, Apps};
+use crate::auth::ServerAuth;
+use crate::settings::{get_client_net_config, get_server_net_configs, Certificate};
+use crate::settings::{ClientTransports, ServerTransports};
+use crate::tests::stepper::{free_port, test_settings};
+
+#[test]
+fn server_listens_on_every_transport() {
+    let mut settings = test_settings().common;
+    settings.server.transport = vec![
+        ServerTransports::Udp { local_port: 0 },
+        ServerTransports::WebTransport { local_port: 0 },
+        ServerTransports::WebSocket { local_port: 0 },
+    ];
+    let auth = ServerAuth::new(&settings);
+    assert_eq!(get_server_net_configs(&settings, &auth).len(), 3);
+
+    // the WebTransport server is skipped without its certificate
+    settings.server.certificate = Certificate::FromFile {
+        cert: PathBuf::from("missing/cert.pem"),
+        key: PathBuf::from("missing/key.pem"),
+    };
+    assert_eq!(get_server_net_configs(&settings, &auth).len(), 2);
+}
+
+#[test]
+fn clients_connect_over_websocket() {
+    let port = free_port();
+    let mut settings = test_settings();
+    settings.common.server.transport = vec![ServerTransports::WebSocket { local_port: port }];
+    settings.common.client.transport = ClientTransports::WebSocket;
+    settings.common.client.server_port = port;
+
+    let (app, config) = server_app(settings.common.clone(), vec![]);
+    let mut server = Apps::Server { app, config };
+    crate::add_plugins(&mut server, &settings);
+    let net_config = get_client_net_config(&settings.common, 1, Ipv4Addr::LOCALHOST.into());
+    let (app, config) = client_app(settings.common.clone(), net_config);
+    let mut client = Apps::Client { app, config };
+    crate::add_plugins(&mut client, &settings);
+
+    for apps in [&mut server, &mut client] {
+        apps.app_mut().finish();
+        apps.app_mut().cleanup();
+    }
+
+    // the sockets run on their own tasks, so the apps are updated in real time
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let connected = loop {
+        server.app_mut().update();
+        client.app_mut().update();
+        let world = client.app_mut().world();
+        if world.resource::<client::ConnectionManager>().is_synced() {
+            break true;
+        }
+        if Instant::now() > deadline {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    assert!(connected, "the client did not connect over WebSocket");
+}